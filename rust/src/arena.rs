@@ -0,0 +1,208 @@
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::Marker3D;
+
+use crate::match_settings::{MatchSettings, GameMode};
+use crate::kill_volume::KnockoutReason;
+use crate::arena_generator::GeneratedArena;
+use crate::custom_arena::{CustomArena, ArenaFile, find_arena_text};
+
+// Arenas put their parts in these groups, so nothing has to know
+// how a particular arena is laid out
+pub const SPAWN_POINTS_GROUP: &str = "spawn_points";
+// Anything on the arena that's dangerous to stand next to
+pub const HAZARDS_GROUP: &str = "hazards";
+// An arena sets this AABB metadata on its root. Anyone leaving it
+// is out, whether they dropped out the bottom or were blasted off.
+pub const BOUNDS_META: &str = "bounds";
+// Arenas without bounds only have a floor to fall through, this low
+pub const DEFAULT_KILL_HEIGHT: f32 = -10.0;
+
+// Everything the lobby needs to know about an arena without loading it
+pub struct ArenaInfo {
+    pub name: &'static str,
+    pub scene: &'static str,
+    // How many players it's meant for
+    pub min_players: i64,
+    pub max_players: i64,
+    // A line about it for the lobby
+    pub preview: &'static str,
+    pub modes: &'static [GameMode],
+    // Built from the match's seed rather than laid out by hand
+    pub generated: bool,
+}
+
+// Custom arenas are built into this, with the same lights as the rest
+const CUSTOM_ARENA_SCENE: &str = "res://arenas/custom_arena.tscn";
+
+// Indexed by `MatchSettings::arena`, so only ever add to the end
+pub const ARENAS: [ArenaInfo; 3] = [
+    ArenaInfo {
+        name: "Arena 1",
+        scene: "res://arenas/arena_1.tscn",
+        min_players: 2,
+        max_players: 8,
+        preview: "Green hills and ledges, with mines hidden all over.",
+        modes: &GameMode::ALL,
+        generated: false,
+    },
+    ArenaInfo {
+        name: "Arena 2",
+        scene: "res://arenas/arena_2.tscn",
+        min_players: 2,
+        max_players: 2,
+        preview: "A bare floor and nowhere to hide. Made for duels.",
+        modes: &GameMode::ALL,
+        generated: false,
+    },
+    ArenaInfo {
+        name: "Generated",
+        scene: "res://arenas/generated_arena.tscn",
+        min_players: 2,
+        max_players: 16,
+        preview: "A floating island grown from the host's seed. Different every time.",
+        modes: &GameMode::ALL,
+        generated: true,
+    },
+];
+
+impl ArenaInfo {
+    // Out of range indices get the first arena
+    pub fn get(index: i64) -> &'static ArenaInfo {
+        ARENAS.get(index as usize).unwrap_or(&ARENAS[0])
+    }
+
+    pub fn supports(&self, mode: GameMode) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    pub fn fits(&self, players: i64) -> bool {
+        (self.min_players..=self.max_players).contains(&players)
+    }
+
+    pub fn label(&self) -> String {
+        if self.min_players == self.max_players {
+            format!("{} ({} players)", self.name, self.min_players)
+        } else {
+            format!("{} ({}-{} players)", self.name, self.min_players, self.max_players)
+        }
+    }
+}
+
+// An arena loaded into the game
+#[derive(Clone)]
+pub struct Arena {
+    root: Gd<Node3D>,
+}
+
+impl Arena {
+    pub fn instantiate(settings: &MatchSettings) -> Self {
+        if settings.is_custom_arena() {
+            match Self::instantiate_custom(settings) {
+                Ok(arena) => return arena,
+                // Better to play somewhere than nowhere
+                Err(error) => godot_error!("Couldn't load {}: {error}", settings.custom_arena),
+            }
+        }
+        let scene = load::<PackedScene>(ArenaInfo::get(settings.arena).scene);
+        let root = scene.instantiate_as::<Node3D>();
+        // Generated arenas build themselves once they're in the tree
+        if let Ok(mut generated) = root.clone().try_cast::<GeneratedArena>() {
+            generated.bind_mut().set_generation(settings.arena_seed, settings.generator);
+        }
+        Self { root }
+    }
+
+    fn instantiate_custom(settings: &MatchSettings) -> Result<Self, String> {
+        let json = find_arena_text(&settings.custom_arena, settings.custom_arena_hash)
+            .ok_or("no copy matching the host's")?;
+        Ok(Self::from_file(ArenaFile::parse(&json)?))
+    }
+
+    pub fn from_file(file: ArenaFile) -> Self {
+        let mut root = load::<PackedScene>(CUSTOM_ARENA_SCENE).instantiate_as::<CustomArena>();
+        root.bind_mut().set_file(file);
+        Self { root: root.upcast() }
+    }
+
+    pub fn root(&self) -> Gd<Node3D> {
+        self.root.clone()
+    }
+
+    pub fn spawn_points(&self) -> Vec<Vector3> {
+        self.positions_in_group(SPAWN_POINTS_GROUP)
+    }
+
+    pub fn hazards(&self) -> Vec<Vector3> {
+        self.positions_in_group(HAZARDS_GROUP)
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.root
+            .get_meta_ex(BOUNDS_META)
+            .default(&default_bounds().to_variant())
+            .done()
+            .try_to::<Aabb>()
+            .unwrap_or_else(|_| default_bounds())
+    }
+
+    // Only counts what's part of this arena, and only once it's in the tree
+    fn positions_in_group(&self, group: &str) -> Vec<Vector3> {
+        let Some(mut tree) = self.root.get_tree() else {
+            return Vec::new();
+        };
+        tree.get_nodes_in_group(group)
+            .iter_shared()
+            .filter(|node| self.root.is_ancestor_of(node))
+            .filter_map(|node| node.try_cast::<Node3D>().ok())
+            .map(|node| node.get_global_position())
+            .collect()
+    }
+}
+
+// For arenas built in code rather than laid out in the editor.
+// Named in order, so they line up across the network.
+pub fn spawn_points_node(positions: &[Vector3]) -> Gd<Node> {
+    let mut spawn_points = Node::new_alloc();
+    spawn_points.set_name("SpawnPoints");
+    for (index, position) in positions.iter().enumerate() {
+        let mut point = Marker3D::new_alloc();
+        point.set_name(&format!("Point{}", index + 1));
+        point.set_position(*position);
+        point.add_to_group(SPAWN_POINTS_GROUP);
+        spawn_points.add_child(&point);
+    }
+    spawn_points
+}
+
+pub fn mines_node(mine_scene: &Gd<PackedScene>, positions: &[Vector3]) -> Gd<Node> {
+    let mut mines = Node::new_alloc();
+    mines.set_name("mines");
+    for (index, position) in positions.iter().enumerate() {
+        let mut mine = mine_scene.instantiate_as::<Node3D>();
+        mine.set_name(&format!("Mine{}", index + 1));
+        mine.set_position(*position);
+        mines.add_child(&mine);
+    }
+    mines
+}
+
+pub fn default_bounds() -> Aabb {
+    let reach = 1000.0;
+    Aabb::new(
+        Vector3::new(-reach, DEFAULT_KILL_HEIGHT, -reach),
+        Vector3::new(reach * 2.0, reach * 2.0, reach * 2.0),
+    )
+}
+
+// Why someone at `position` is out of `bounds`, if they are
+pub fn out_of_bounds_reason(bounds: Aabb, position: Vector3) -> Option<KnockoutReason> {
+    if position.y < bounds.position.y {
+        Some(KnockoutReason::Fell)
+    } else if bounds.contains_point(position) {
+        None
+    } else {
+        Some(KnockoutReason::BlastedOut)
+    }
+}
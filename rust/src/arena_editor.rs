@@ -0,0 +1,642 @@
+use godot::prelude::*;
+use godot::global::MouseButton;
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Node3D, INode3D, GridMap, MeshInstance3D, Mesh, MeshLibrary, CanvasLayer,
+    Button, Label, LineEdit, OptionButton, SpinBox,
+    InputEvent, InputEventMouseButton, InputEventMouseMotion, Input,
+    OfflineMultiplayerPeer,
+};
+use godot::classes::input::MouseMode;
+use godot::classes::object::ConnectFlags;
+
+use crate::arena::Arena;
+use crate::custom_arena::{
+    ArenaFile, Hazard, CUSTOM_ARENAS_DIR, MESH_LIBRARY,
+    arena_path, list_custom_arenas,
+};
+use crate::free_camera::FreeCamera;
+use crate::game::Game;
+use crate::match_settings::MatchSettings;
+use crate::profile::PlayerProfile;
+use crate::roster::{RosterEntry, player_id};
+use crate::input_bindings::ANY_DEVICE;
+
+// Spawn points hover this far over the ground, like the hand made ones
+const SPAWN_HEIGHT: f32 = 1.1;
+// Room past the cells before anyone counts as out, unless set otherwise
+const DEFAULT_BOUNDS_MARGIN: f64 = 20.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MarkerKind {
+    SpawnPoint,
+    Mine,
+    Exploder,
+}
+
+impl MarkerKind {
+    // How far over the top of the cell it's placed on
+    fn height(self) -> f32 {
+        match self {
+            MarkerKind::SpawnPoint => 1.0 + SPAWN_HEIGHT,
+            MarkerKind::Mine | MarkerKind::Exploder => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum EditorTool {
+    #[default]
+    Cell,
+    Erase,
+    Marker(MarkerKind),
+}
+
+impl EditorTool {
+    const ALL: [EditorTool; 5] = [
+        EditorTool::Cell,
+        EditorTool::Erase,
+        EditorTool::Marker(MarkerKind::SpawnPoint),
+        EditorTool::Marker(MarkerKind::Mine),
+        EditorTool::Marker(MarkerKind::Exploder),
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            EditorTool::Cell => "Place Cell",
+            EditorTool::Erase => "Erase",
+            EditorTool::Marker(MarkerKind::SpawnPoint) => "Spawn Point",
+            EditorTool::Marker(MarkerKind::Mine) => "Mine",
+            EditorTool::Marker(MarkerKind::Exploder) => "Exploder",
+        }
+    }
+
+    fn from_index(index: i64) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+}
+
+// One undoable change
+#[derive(Clone, Debug)]
+enum Edit {
+    // Items are INVALID_CELL_ITEM where there's no cell
+    Cell { cell: Vector3i, before: i32, after: i32 },
+    AddMarker { kind: MarkerKind, position: Vector3 },
+    RemoveMarker { kind: MarkerKind, index: usize, position: Vector3 },
+    Bounds { before: Option<Aabb>, after: Option<Aabb> },
+}
+
+// Builds custom arenas in the game itself, without the Godot editor.
+// Saves them in the format `custom_arena` loads.
+#[derive(GodotClass)]
+#[class(init, base=Node3D)]
+pub struct ArenaEditor {
+    #[export]
+    grid: OnEditor<Gd<GridMap>>,
+    #[export]
+    markers: OnEditor<Gd<Node3D>>,
+    #[export]
+    cursor: OnEditor<Gd<MeshInstance3D>>,
+    #[export]
+    bounds_box: OnEditor<Gd<MeshInstance3D>>,
+    #[export]
+    camera: OnEditor<Gd<FreeCamera>>,
+    #[export]
+    ui: OnEditor<Gd<CanvasLayer>>,
+    #[export]
+    arena_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    name_edit: OnEditor<Gd<LineEdit>>,
+    #[export]
+    preview_edit: OnEditor<Gd<LineEdit>>,
+    #[export]
+    tool_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    item_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    layer_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    margin_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    fit_bounds_button: OnEditor<Gd<Button>>,
+    #[export]
+    undo_button: OnEditor<Gd<Button>>,
+    #[export]
+    redo_button: OnEditor<Gd<Button>>,
+    #[export]
+    test_play_button: OnEditor<Gd<Button>>,
+    #[export]
+    save_button: OnEditor<Gd<Button>>,
+    #[export]
+    back_button: OnEditor<Gd<Button>>,
+    #[export]
+    status_label: OnEditor<Gd<Label>>,
+    #[export]
+    spawn_point_mesh: OnEditor<Gd<Mesh>>,
+    #[export]
+    mine_mesh: OnEditor<Gd<Mesh>>,
+    #[export]
+    exploder_mesh: OnEditor<Gd<Mesh>>,
+    spawn_points: Vec<Vector3>,
+    mines: Vec<Vector3>,
+    exploders: Vec<Vector3>,
+    // Kept as they were in the file, there's no tool for them here
+    hazards: Vec<Hazard>,
+    // None until set, then it's fitted to the cells on saving
+    bounds: Option<Aabb>,
+    undo_stack: Vec<Edit>,
+    redo_stack: Vec<Edit>,
+    // The cell under the mouse, on the current layer
+    hovered: Option<Vector3i>,
+    // The file it was opened from or last saved to
+    arena_id: Option<String>,
+    // Whether a test play is going on, with the editor hidden
+    testing: bool,
+    base: Base<Node3D>,
+}
+
+#[godot_api]
+impl INode3D for ArenaEditor {
+    fn ready(&mut self) {
+        let gd_ref = self.to_gd();
+        self.camera.make_current();
+
+        self.tool_option.clear();
+        for tool in EditorTool::ALL {
+            self.tool_option.add_item(tool.label());
+        }
+        let library = load::<MeshLibrary>(MESH_LIBRARY);
+        self.item_option.clear();
+        for item in library.get_item_list().as_slice() {
+            self.item_option.add_item_ex(&library.get_item_name(*item)).id(*item).done();
+        }
+        self.margin_spin.set_value_no_signal(DEFAULT_BOUNDS_MARGIN);
+        self.refresh_arena_list();
+
+        self.arena_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, index: i64| {
+                // The first entry starts a new arena
+                let id = match index {
+                    0 => None,
+                    _ => Some(this.arena_option.get_item_text(index as i32).to_string()),
+                };
+                this.open(id);
+            });
+        self.fit_bounds_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                let after = this.fitted_bounds();
+                let before = this.bounds;
+                this.perform(Edit::Bounds { before, after });
+            });
+        self.undo_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::undo);
+        self.redo_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::redo);
+        self.save_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::save);
+        self.test_play_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::start_test_play);
+        self.back_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| this.signals().closed().emit());
+
+        self.open(None);
+    }
+
+    fn process(&mut self, _delta: f64) {
+        if self.testing {
+            return;
+        }
+        self.hovered = self.cell_under_mouse();
+        let cursor_position = self.hovered
+            .map(|cell| Vector3::new(cell.x as f32, cell.y as f32, cell.z as f32) + Vector3::ONE * 0.5);
+        self.cursor.set_visible(cursor_position.is_some());
+        if let Some(position) = cursor_position {
+            self.cursor.set_position(position);
+        }
+    }
+
+    fn unhandled_input(&mut self, event: Gd<InputEvent>) {
+        if self.testing {
+            return;
+        }
+        if event.is_action_pressed("ui_redo") {
+            self.redo();
+        } else if event.is_action_pressed("ui_undo") {
+            self.undo();
+        } else if let Ok(button) = event.clone().try_cast::<InputEventMouseButton>()
+                && button.get_button_index() == MouseButton::LEFT
+                && button.is_pressed() {
+            self.use_tool();
+        } else if let Ok(motion) = event.try_cast::<InputEventMouseMotion>()
+                && motion.get_button_mask().is_set(godot::global::MouseButtonMask::LEFT) {
+            // Cells can be painted and erased by dragging, markers can't
+            if !matches!(self.current_tool(), EditorTool::Marker(_)) {
+                self.hovered = self.cell_under_mouse();
+                self.use_tool();
+            }
+        }
+    }
+}
+
+#[godot_api]
+impl ArenaEditor {
+    #[signal]
+    pub fn closed();
+
+    fn current_tool(&self) -> EditorTool {
+        EditorTool::from_index(self.tool_option.get_selected() as i64)
+    }
+
+    // Where the mouse ray meets the top of the current layer
+    fn cell_under_mouse(&self) -> Option<Vector3i> {
+        let mouse = self.base().get_viewport()?.get_mouse_position();
+        let origin = self.camera.project_ray_origin(mouse);
+        let normal = self.camera.project_ray_normal(mouse);
+        let layer = self.layer_spin.get_value() as i32;
+        let top = layer as f32 + 1.0;
+        if normal.y.abs() < 0.0001 {
+            return None;
+        }
+        let distance = (top - origin.y) / normal.y;
+        if distance <= 0.0 {
+            return None;
+        }
+        let hit = origin + normal * distance;
+        Some(Vector3i::new(hit.x.floor() as i32, layer, hit.z.floor() as i32))
+    }
+
+    fn use_tool(&mut self) {
+        let Some(cell) = self.hovered else {
+            return;
+        };
+        match self.current_tool() {
+            EditorTool::Cell => {
+                let after = self.item_option.get_selected_id();
+                let before = self.grid.get_cell_item(cell);
+                if before != after {
+                    self.perform(Edit::Cell { cell, before, after });
+                }
+            }
+            EditorTool::Erase => {
+                // Whatever stands on the cell goes first, then the cell
+                let marker = [MarkerKind::SpawnPoint, MarkerKind::Mine, MarkerKind::Exploder]
+                    .into_iter()
+                    .find_map(|kind| {
+                        self.marker_on(kind, cell)
+                            .map(|index| (kind, index, self.marker_list(kind)[index]))
+                    });
+                if let Some((kind, index, position)) = marker {
+                    self.perform(Edit::RemoveMarker { kind, index, position });
+                } else {
+                    let before = self.grid.get_cell_item(cell);
+                    if before != GridMap::INVALID_CELL_ITEM {
+                        self.perform(Edit::Cell { cell, before, after: GridMap::INVALID_CELL_ITEM });
+                    }
+                }
+            }
+            EditorTool::Marker(kind) => {
+                if self.marker_on(kind, cell).is_none() {
+                    let position = Vector3::new(
+                        cell.x as f32 + 0.5,
+                        cell.y as f32 + kind.height(),
+                        cell.z as f32 + 0.5,
+                    );
+                    self.perform(Edit::AddMarker { kind, position });
+                }
+            }
+        }
+    }
+
+    fn marker_list(&self, kind: MarkerKind) -> &Vec<Vector3> {
+        match kind {
+            MarkerKind::SpawnPoint => &self.spawn_points,
+            MarkerKind::Mine => &self.mines,
+            MarkerKind::Exploder => &self.exploders,
+        }
+    }
+
+    fn marker_list_mut(&mut self, kind: MarkerKind) -> &mut Vec<Vector3> {
+        match kind {
+            MarkerKind::SpawnPoint => &mut self.spawn_points,
+            MarkerKind::Mine => &mut self.mines,
+            MarkerKind::Exploder => &mut self.exploders,
+        }
+    }
+
+    // Index of a marker standing on top of `cell`
+    fn marker_on(&self, kind: MarkerKind, cell: Vector3i) -> Option<usize> {
+        self.marker_list(kind).iter().position(|position| {
+            let above = position.y - (cell.y as f32 + 1.0);
+            position.x.floor() as i32 == cell.x
+                && position.z.floor() as i32 == cell.z
+                && (0.0..=SPAWN_HEIGHT + 0.5).contains(&above)
+        })
+    }
+
+    fn perform(&mut self, edit: Edit) {
+        self.apply(&edit, true);
+        self.undo_stack.push(edit);
+        self.redo_stack.clear();
+        self.update_buttons();
+    }
+
+    fn undo(&mut self) {
+        if let Some(edit) = self.undo_stack.pop() {
+            self.apply(&edit, false);
+            self.redo_stack.push(edit);
+        }
+        self.update_buttons();
+    }
+
+    fn redo(&mut self) {
+        if let Some(edit) = self.redo_stack.pop() {
+            self.apply(&edit, true);
+            self.undo_stack.push(edit);
+        }
+        self.update_buttons();
+    }
+
+    // Does the edit, or undoes it when not `forward`
+    fn apply(&mut self, edit: &Edit, forward: bool) {
+        match *edit {
+            Edit::Cell { cell, before, after } => {
+                let item = if forward { after } else { before };
+                self.grid.set_cell_item(cell, item);
+            }
+            Edit::AddMarker { kind, position } => {
+                if forward {
+                    self.marker_list_mut(kind).push(position);
+                } else {
+                    self.marker_list_mut(kind).pop();
+                }
+                self.update_markers();
+            }
+            Edit::RemoveMarker { kind, index, position } => {
+                if forward {
+                    self.marker_list_mut(kind).remove(index);
+                } else {
+                    self.marker_list_mut(kind).insert(index, position);
+                }
+                self.update_markers();
+            }
+            Edit::Bounds { before, after } => {
+                self.bounds = if forward { after } else { before };
+                self.update_bounds_box();
+            }
+        }
+    }
+
+    fn update_buttons(&mut self) {
+        self.undo_button.set_disabled(self.undo_stack.is_empty());
+        self.redo_button.set_disabled(self.redo_stack.is_empty());
+    }
+
+    fn update_markers(&mut self) {
+        for mut child in self.markers.get_children().iter_shared() {
+            child.queue_free();
+        }
+        let lists = [
+            (self.spawn_points.clone(), self.spawn_point_mesh.clone()),
+            (self.mines.clone(), self.mine_mesh.clone()),
+            (self.exploders.clone(), self.exploder_mesh.clone()),
+        ];
+        for (positions, mesh) in lists {
+            for position in positions {
+                let mut marker = MeshInstance3D::new_alloc();
+                marker.set_mesh(&mesh);
+                marker.set_position(position);
+                self.markers.add_child(&marker);
+            }
+        }
+    }
+
+    fn update_bounds_box(&mut self) {
+        let bounds = self.bounds;
+        self.bounds_box.set_visible(bounds.is_some());
+        if let Some(bounds) = bounds {
+            self.bounds_box.set_position(bounds.center());
+            self.bounds_box.set_scale(bounds.size);
+        }
+    }
+
+    // Around every cell, with the margin to spare on all sides
+    fn fitted_bounds(&self) -> Option<Aabb> {
+        let cells = self.grid.get_used_cells();
+        let mut cells = cells.iter_shared();
+        let first = cells.next()?;
+        let corner = |cell: Vector3i| Vector3::new(cell.x as f32, cell.y as f32, cell.z as f32);
+        let around = cells.fold(Aabb::new(corner(first), Vector3::ONE), |bounds, cell| {
+            bounds.merge(Aabb::new(corner(cell), Vector3::ONE))
+        });
+        Some(around.grow(self.margin_spin.get_value() as f32))
+    }
+
+    fn refresh_arena_list(&mut self) {
+        let ids = list_custom_arenas();
+        self.arena_option.clear();
+        self.arena_option.add_item("New Arena");
+        for id in &ids {
+            self.arena_option.add_item(id);
+        }
+        let selected = self.arena_id
+            .as_ref()
+            .and_then(|id| ids.iter().position(|other| other == id))
+            .map_or(0, |index| index + 1);
+        self.arena_option.select(selected as i32);
+    }
+
+    // Opens a saved arena, or starts a new one for None
+    fn open(&mut self, id: Option<String>) {
+        let file = match &id {
+            Some(id) => match ArenaFile::load(&arena_path(CUSTOM_ARENAS_DIR, id)) {
+                Ok(file) => Some(file),
+                Err(error) => {
+                    self.status_label.set_text(&error);
+                    return;
+                }
+            },
+            None => None,
+        };
+        self.grid.clear();
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        match file {
+            Some(file) => {
+                for (cell, item) in &file.cells {
+                    self.grid.set_cell_item(*cell, *item);
+                }
+                self.name_edit.set_text(&file.name);
+                self.preview_edit.set_text(&file.preview);
+                self.spawn_points = file.spawn_points;
+                self.mines = file.mines;
+                self.exploders = file.exploders;
+                self.hazards = file.hazards;
+                self.bounds = Some(file.bounds);
+            }
+            None => {
+                // Something to stand on to begin with
+                for x in -4..4 {
+                    for z in -4..4 {
+                        let item = self.item_option.get_selected_id();
+                        self.grid.set_cell_item(Vector3i::new(x, 0, z), item);
+                    }
+                }
+                self.name_edit.set_text("");
+                self.preview_edit.set_text("");
+                self.spawn_points.clear();
+                self.mines.clear();
+                self.exploders.clear();
+                self.hazards.clear();
+                self.bounds = None;
+            }
+        }
+        self.arena_id = id;
+        self.update_markers();
+        self.update_bounds_box();
+        self.update_buttons();
+        self.status_label.set_text("");
+    }
+
+    // The arena as it would be saved. Checked the same way a loaded one
+    // is, so anything wrong with it shows up here first.
+    fn to_file(&self) -> Result<ArenaFile, String> {
+        let name = self.name_edit.get_text().to_string().trim().to_string();
+        if name.is_empty() {
+            return Err("Give the arena a name first.".to_string());
+        }
+        let cells = self.grid
+            .get_used_cells()
+            .iter_shared()
+            .map(|cell| (cell, self.grid.get_cell_item(cell)))
+            .collect();
+        let bounds = self.bounds
+            .or_else(|| self.fitted_bounds())
+            .ok_or("Place some cells first.")?;
+        let file = ArenaFile {
+            name,
+            preview: self.preview_edit.get_text().to_string(),
+            cells,
+            spawn_points: self.spawn_points.clone(),
+            mines: self.mines.clone(),
+            exploders: self.exploders.clone(),
+            hazards: self.hazards.clone(),
+            bounds,
+        };
+        ArenaFile::from_dict(&file.to_dict())
+    }
+
+    fn save(&mut self) {
+        let file = match self.to_file() {
+            Ok(file) => file,
+            Err(error) => {
+                self.status_label.set_text(&error);
+                return;
+            }
+        };
+        let id = self.arena_id.clone().unwrap_or_else(|| free_id(&file.name));
+        let path = arena_path(CUSTOM_ARENAS_DIR, &id);
+        if file.save(&path) {
+            self.status_label.set_text(&format!("Saved to {path}"));
+            self.arena_id = Some(id);
+            self.refresh_arena_list();
+        } else {
+            self.status_label.set_text(&format!("Couldn't write {path}"));
+        }
+    }
+
+    // Plays the arena as it is, saved or not, in a practice match
+    // that doesn't end until it's left from the pause menu
+    fn start_test_play(&mut self) {
+        let file = match self.to_file() {
+            Ok(file) => file,
+            Err(error) => {
+                self.status_label.set_text(&error);
+                return;
+            }
+        };
+        let peer = OfflineMultiplayerPeer::new_gd();
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        multiplayer.set_multiplayer_peer(&peer);
+
+        let profile = PlayerProfile::load();
+        let mut entry = RosterEntry::new(profile.display_name, profile.color);
+        entry.device = ANY_DEVICE;
+        let mut roster = VarDictionary::new();
+        roster.set(player_id(1, 0), entry.to_dict());
+
+        let mut game = load::<PackedScene>("res://game.tscn").instantiate_as::<Game>();
+        game.bind_mut().set_match_settings(MatchSettings {
+            ko_limit: 0,
+            time_limit: 0,
+            ..MatchSettings::default()
+        });
+        game.bind_mut().set_arena(Arena::from_file(file));
+        game.bind_mut().initialize_authority(&roster);
+        game.bind_mut()
+            .get_pause_menu()
+            .unwrap()
+            .signals()
+            .exit_pressed()
+            .builder()
+            .flags(ConnectFlags::DEFERRED)
+            .connect_other_mut(&self.to_gd(), Self::end_test_play);
+
+        self.testing = true;
+        self.base_mut().set_visible(false);
+        self.ui.set_visible(false);
+        self.base_mut()
+            .get_tree()
+            .unwrap()
+            .get_root()
+            .unwrap()
+            .add_child(&game);
+    }
+
+    fn end_test_play(&mut self) {
+        if self.base().has_node("/root/Game") {
+            self.base().get_node_as::<Node>("/root/Game").queue_free();
+        }
+        Input::singleton().set_mouse_mode(MouseMode::VISIBLE);
+        self.testing = false;
+        self.base_mut().set_visible(true);
+        self.ui.set_visible(true);
+        self.camera.make_current();
+    }
+}
+
+// A file name made out of the arena's name
+fn id_from_name(name: &str) -> String {
+    let id: String = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    if id.is_empty() { "arena".to_string() } else { id }
+}
+
+// Like `id_from_name`, with a number on the end if a saved arena
+// already has that id, so a new one never writes over it
+fn free_id(name: &str) -> String {
+    let id = id_from_name(name);
+    let taken = list_custom_arenas();
+    (1..)
+        .map(|n| if n == 1 { id.clone() } else { format!("{id}_{n}") })
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap()
+}
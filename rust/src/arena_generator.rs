@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{GridMap, IGridMap};
+
+use crate::arena::{spawn_points_node, mines_node, BOUNDS_META, DEFAULT_KILL_HEIGHT};
+
+// Everything here but GeneratedArena itself stays clear of the engine,
+// so the same seed builds the same arena anywhere, even headless
+
+// Mesh library item every generated tile is made of
+const CUBE_ITEM: i32 = 1;
+// Spawn points hover this far over the ground, like the hand made ones
+const SPAWN_HEIGHT: f32 = 1.1;
+// A step up higher than this many tiles can't be walked
+const MAX_STEP: i32 = 1;
+// Tiles between value noise lattice points, for heights and for gaps
+const HEIGHT_SPACING: f32 = 6.0;
+const GAP_SPACING: f32 = 4.0;
+// Most of the land has to be reachable from the rest
+const MIN_CONNECTED_SHARE: f32 = 0.6;
+// Nobody spawns this close to somebody else's spawn, in tiles
+const MIN_SPAWN_SPACING: f32 = 4.0;
+// The most crowded spawn has to have at least this share of the
+// room the roomiest one has
+const MIN_SPAWN_FAIRNESS: f32 = 0.5;
+// Nor this close to a mine
+const MINE_CLEARANCE: f32 = 3.0;
+// Tries with the given settings before falling back to a plain island
+const MAX_ATTEMPTS: u64 = 12;
+// Room past the island before anyone counts as blasted out
+const BOUNDS_MARGIN: f32 = 40.0;
+
+pub const MIN_ARENA_SIZE: i64 = 16;
+pub const MAX_ARENA_SIZE: i64 = 80;
+pub const MAX_HEIGHT_VARIATION: i64 = 8;
+pub const MAX_GAPS: f64 = 0.5;
+pub const MAX_MINE_DENSITY: f64 = 0.2;
+pub const MIN_SPAWN_COUNT: i64 = 2;
+pub const MAX_SPAWN_COUNT: i64 = 16;
+
+// What the host picks for a generated arena, on top of the seed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeneratorParams {
+    // Across the island, in tiles
+    pub size: i64,
+    // Share of the island knocked out as holes
+    pub gaps: f64,
+    // Tiles between the lowest and highest ground
+    pub height_variation: i64,
+    // Chance of a mine on any tile clear of the spawns
+    pub mine_density: f64,
+    pub spawn_count: i64,
+}
+
+impl Default for GeneratorParams {
+    fn default() -> Self {
+        Self {
+            size: 40,
+            gaps: 0.15,
+            height_variation: 3,
+            mine_density: 0.03,
+            spawn_count: 8,
+        }
+    }
+}
+
+impl GeneratorParams {
+    pub fn to_dict(self) -> VarDictionary {
+        vdict! {
+            "size": self.size,
+            "gaps": self.gaps,
+            "height_variation": self.height_variation,
+            "mine_density": self.mine_density,
+            "spawn_count": self.spawn_count,
+        }
+    }
+
+    // Missing or mistyped keys fall back to the defaults
+    pub fn from_dict(dict: &VarDictionary) -> Self {
+        let defaults = Self::default();
+        let int = |key: &str, default: i64| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<i64>().ok())
+                .unwrap_or(default)
+        };
+        let float = |key: &str, default: f64| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<f64>().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            size: int("size", defaults.size).clamp(MIN_ARENA_SIZE, MAX_ARENA_SIZE),
+            gaps: float("gaps", defaults.gaps).clamp(0.0, MAX_GAPS),
+            height_variation: int("height_variation", defaults.height_variation)
+                .clamp(0, MAX_HEIGHT_VARIATION),
+            mine_density: float("mine_density", defaults.mine_density)
+                .clamp(0.0, MAX_MINE_DENSITY),
+            spawn_count: int("spawn_count", defaults.spawn_count)
+                .clamp(MIN_SPAWN_COUNT, MAX_SPAWN_COUNT),
+        }
+    }
+}
+
+// Small, and the same on every machine, unlike the engine's
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // In [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// The same every time for the same seed, channel and lattice point, in [0, 1)
+fn lattice(seed: u64, channel: u64, x: i32, z: i32) -> f32 {
+    let key = seed
+        ^ channel.wrapping_mul(0xD6E8_FEB8_6659_FD93)
+        ^ (x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ ((z as u32 as u64) << 32);
+    SplitMix64(key).next_f32()
+}
+
+// Smooth noise in [0, 1) made by blending lattice points `spacing` tiles apart
+fn value_noise(seed: u64, channel: u64, x: i32, z: i32, spacing: f32) -> f32 {
+    let fx = x as f32 / spacing;
+    let fz = z as f32 / spacing;
+    let (x0, z0) = (fx.floor() as i32, fz.floor() as i32);
+    let (tx, tz) = (smoothstep(fx - x0 as f32), smoothstep(fz - z0 as f32));
+    let a = lattice(seed, channel, x0, z0);
+    let b = lattice(seed, channel, x0 + 1, z0);
+    let c = lattice(seed, channel, x0, z0 + 1);
+    let d = lattice(seed, channel, x0 + 1, z0 + 1);
+    let top = a + (b - a) * tx;
+    let bottom = c + (d - c) * tx;
+    top + (bottom - top) * tz
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+// A generated arena, before it's turned into tiles
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    // Each column of ground as (x, z, top tile), in a fixed order
+    pub columns: Vec<(i32, i32, i32)>,
+    pub spawn_points: Vec<Vector3>,
+    pub mines: Vec<Vector3>,
+    pub bounds: Aabb,
+}
+
+impl Layout {
+    // Where on a tile's top someone stands
+    fn surface(x: i32, z: i32, top: i32) -> Vector3 {
+        Vector3::new(x as f32 + 0.5, top as f32 + 1.0, z as f32 + 0.5)
+    }
+}
+
+// Builds a playable arena from `seed`, retrying with seeds derived from
+// it until the land is connected and the spawns are fair
+pub fn generate(seed: i64, params: &GeneratorParams) -> Layout {
+    for attempt in 0..MAX_ATTEMPTS {
+        let mut rng = SplitMix64(seed as u64 ^ attempt.wrapping_mul(0xA24B_AED4_963E_E407));
+        if let Some(layout) = try_generate(rng.next_u64(), params) {
+            return layout;
+        }
+    }
+    // A flat island with no holes is always connected
+    let plain = GeneratorParams { gaps: 0.0, height_variation: 0, ..*params };
+    let fewest = GeneratorParams { spawn_count: MIN_SPAWN_COUNT, ..plain };
+    try_generate(seed as u64, &plain)
+        .or_else(|| try_generate(seed as u64, &fewest))
+        .expect("a flat island always fits two spawns")
+}
+
+fn try_generate(seed: u64, params: &GeneratorParams) -> Option<Layout> {
+    let radius = params.size as f32 / 2.0;
+    let heights = land(seed, params);
+
+    // Only the biggest walkable piece is kept
+    let land = heights.len();
+    let mut component = largest_component(&heights);
+    if land == 0 || (component.len() as f32) < land as f32 * MIN_CONNECTED_SHARE {
+        return None;
+    }
+    component.sort_unstable();
+    let columns: Vec<(i32, i32, i32)> = component
+        .iter()
+        .map(|&(x, z)| (x, z, heights[&(x, z)]))
+        .collect();
+
+    let spawns = pick_spawns(&columns, &heights, params.spawn_count as usize)?;
+    let spawn_points: Vec<Vector3> = spawns
+        .iter()
+        .map(|&(x, z, top)| Layout::surface(x, z, top) + Vector3::UP * SPAWN_HEIGHT)
+        .collect();
+
+    let mut rng = SplitMix64(seed ^ 0x4D49_4E45);
+    let mines = columns
+        .iter()
+        .filter(|&&(x, z, _)| {
+            spawns.iter().all(|&(sx, sz, _)| tile_distance((x, z), (sx, sz)) > MINE_CLEARANCE)
+        })
+        .filter(|_| rng.next_f32() < params.mine_density as f32)
+        .map(|&(x, z, top)| Layout::surface(x, z, top))
+        .collect();
+
+    let top = params.height_variation as f32 + 1.0;
+    let bounds = Aabb::new(
+        Vector3::new(-radius - BOUNDS_MARGIN, DEFAULT_KILL_HEIGHT, -radius - BOUNDS_MARGIN),
+        Vector3::new(
+            (radius + BOUNDS_MARGIN) * 2.0,
+            top + BOUNDS_MARGIN - DEFAULT_KILL_HEIGHT,
+            (radius + BOUNDS_MARGIN) * 2.0,
+        ),
+    );
+    Some(Layout { columns, spawn_points, mines, bounds })
+}
+
+// A wobbly disc with holes knocked out of it, as tile to ground height
+fn land(seed: u64, params: &GeneratorParams) -> HashMap<(i32, i32), i32> {
+    let radius = params.size as f32 / 2.0;
+    let extent = radius.ceil() as i32 + 1;
+    let mut heights = HashMap::new();
+    for x in -extent..=extent {
+        for z in -extent..=extent {
+            let distance = ((x * x + z * z) as f32).sqrt();
+            let edge = radius * (0.8 + 0.2 * value_noise(seed, 0, x, z, GAP_SPACING * 2.0));
+            if distance > edge {
+                continue;
+            }
+            if value_noise(seed, 1, x, z, GAP_SPACING) < params.gaps as f32 {
+                continue;
+            }
+            let height = value_noise(seed, 2, x, z, HEIGHT_SPACING) * params.height_variation as f32;
+            heights.insert((x, z), height.round() as i32);
+        }
+    }
+    limit_steps(&mut heights);
+    heights
+}
+
+const NEIGHBOURS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+// Lowers ground until no step between neighbours is too tall to walk
+fn limit_steps(heights: &mut HashMap<(i32, i32), i32>) {
+    let mut tiles: Vec<(i32, i32)> = heights.keys().copied().collect();
+    tiles.sort_unstable();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(x, z) in &tiles {
+            let lowest_neighbour = NEIGHBOURS
+                .iter()
+                .filter_map(|(dx, dz)| heights.get(&(x + dx, z + dz)).copied())
+                .min();
+            if let Some(lowest) = lowest_neighbour
+                    && heights[&(x, z)] > lowest + MAX_STEP {
+                heights.insert((x, z), lowest + MAX_STEP);
+                changed = true;
+            }
+        }
+    }
+}
+
+fn largest_component(heights: &HashMap<(i32, i32), i32>) -> Vec<(i32, i32)> {
+    let mut tiles: Vec<(i32, i32)> = heights.keys().copied().collect();
+    tiles.sort_unstable();
+    let mut seen = HashSet::new();
+    let mut largest = Vec::new();
+    for start in tiles {
+        if seen.contains(&start) {
+            continue;
+        }
+        let mut component = Vec::new();
+        let mut queue = VecDeque::from([start]);
+        seen.insert(start);
+        while let Some((x, z)) = queue.pop_front() {
+            component.push((x, z));
+            for (dx, dz) in NEIGHBOURS {
+                let next = (x + dx, z + dz);
+                if let Some(next_height) = heights.get(&next)
+                        && (next_height - heights[&(x, z)]).abs() <= MAX_STEP
+                        && seen.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        if component.len() > largest.len() {
+            largest = component;
+        }
+    }
+    largest
+}
+
+// Spread out as far from each other as they'll go, on tiles with solid
+// ground all around. None when they can't be spread out fairly.
+fn pick_spawns(
+    columns: &[(i32, i32, i32)],
+    heights: &HashMap<(i32, i32), i32>,
+    count: usize,
+) -> Option<Vec<(i32, i32, i32)>> {
+    let candidates: Vec<(i32, i32, i32)> = columns
+        .iter()
+        .copied()
+        .filter(|&(x, z, _)| {
+            NEIGHBOURS.iter().all(|(dx, dz)| heights.contains_key(&(x + dx, z + dz)))
+        })
+        .collect();
+    // Start from the edge, furthest from the middle
+    let first = candidates
+        .iter()
+        .copied()
+        .max_by(|a, b| {
+            tile_distance((a.0, a.1), (0, 0)).total_cmp(&tile_distance((b.0, b.1), (0, 0)))
+        })?;
+    let mut spawns = vec![first];
+    while spawns.len() < count {
+        let room = |tile: &(i32, i32, i32)| {
+            spawns
+                .iter()
+                .map(|spawn| tile_distance((tile.0, tile.1), (spawn.0, spawn.1)))
+                .fold(f32::INFINITY, f32::min)
+        };
+        let next = candidates
+            .iter()
+            .copied()
+            .max_by(|a, b| room(a).total_cmp(&room(b)))?;
+        if room(&next) < MIN_SPAWN_SPACING {
+            return None;
+        }
+        spawns.push(next);
+    }
+
+    // How far each spawn is from its closest neighbour
+    let rooms: Vec<f32> = spawns
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            spawns
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, b)| tile_distance((a.0, a.1), (b.0, b.1)))
+                .fold(f32::INFINITY, f32::min)
+        })
+        .collect();
+    let least = rooms.iter().copied().fold(f32::INFINITY, f32::min);
+    let most = rooms.iter().copied().fold(0.0, f32::max);
+    (least >= most * MIN_SPAWN_FAIRNESS).then_some(spawns)
+}
+
+fn tile_distance(a: (i32, i32), b: (i32, i32)) -> f32 {
+    let (dx, dz) = ((a.0 - b.0) as f32, (a.1 - b.1) as f32);
+    (dx * dx + dz * dz).sqrt()
+}
+
+// A GridMap that fills itself in from a seed when it enters the game.
+// Every machine builds the same one, so only the seed goes over the network.
+#[derive(GodotClass)]
+#[class(init, base=GridMap)]
+pub struct GeneratedArena {
+    #[init(val=OnReady::from_loaded("res://explosion/mine.tscn"))]
+    mine_scene: OnReady<Gd<PackedScene>>,
+    seed: i64,
+    params: GeneratorParams,
+    base: Base<GridMap>,
+}
+
+#[godot_api]
+impl IGridMap for GeneratedArena {
+    fn ready(&mut self) {
+        let layout = generate(self.seed, &self.params);
+        self.build(&layout);
+    }
+}
+
+#[godot_api]
+impl GeneratedArena {
+    // Must be called before the arena enters the tree
+    pub fn set_generation(&mut self, seed: i64, params: GeneratorParams) {
+        self.seed = seed;
+        self.params = params;
+    }
+
+    fn build(&mut self, layout: &Layout) {
+        self.base_mut().clear();
+        for &(x, z, top) in &layout.columns {
+            // Thickest in the middle, like a floating island
+            let distance = tile_distance((x, z), (0, 0));
+            let depth = 1 + ((1.0 - distance / self.params.size as f32 * 2.0).max(0.0) * 4.0) as i32;
+            for y in (top - depth)..=top {
+                self.base_mut().set_cell_item(Vector3i::new(x, y, z), CUBE_ITEM);
+            }
+        }
+
+        let spawn_points = spawn_points_node(&layout.spawn_points);
+        self.base_mut().add_child(&spawn_points);
+        let mines = mines_node(&self.mine_scene, &layout.mines);
+        self.base_mut().add_child(&mines);
+
+        self.base_mut().set_meta(BOUNDS_META, &layout.bounds.to_variant());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEEDS: std::ops::Range<i64> = 0..24;
+
+    // How far each spawn is from its closest neighbour, across the ground
+    fn spawn_rooms(layout: &Layout) -> Vec<f32> {
+        let flat: Vec<Vector2> = layout.spawn_points
+            .iter()
+            .map(|point| Vector2::new(point.x, point.z))
+            .collect();
+        flat.iter()
+            .enumerate()
+            .map(|(i, a)| {
+                flat.iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, b)| a.distance_to(*b))
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_layout() {
+        let params = GeneratorParams::default();
+        for seed in SEEDS {
+            assert_eq!(generate(seed, &params), generate(seed, &params));
+        }
+    }
+
+    #[test]
+    fn different_seeds_different_layouts() {
+        let params = GeneratorParams::default();
+        let first = generate(1, &params);
+        assert!(SEEDS.filter(|seed| *seed != 1).all(|seed| generate(seed, &params) != first));
+    }
+
+    #[test]
+    fn largest_component_picks_the_biggest_walkable_piece() {
+        let mut heights = HashMap::new();
+        for x in 0..5 {
+            heights.insert((x, 0), 0);
+        }
+        // Cut off by a gap
+        heights.insert((7, 0), 0);
+        heights.insert((8, 0), 0);
+        // Next door, but too tall to step up
+        heights.insert((2, 1), 3);
+        let mut component = largest_component(&heights);
+        component.sort_unstable();
+        assert_eq!(component, (0..5).map(|x| (x, 0)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn kept_land_is_mostly_connected() {
+        let params = GeneratorParams { gaps: MAX_GAPS, ..GeneratorParams::default() };
+        let mut checked = 0;
+        for seed in SEEDS {
+            let seed = seed as u64;
+            let Some(layout) = try_generate(seed, &params) else {
+                continue;
+            };
+            let land = land(seed, &params);
+            assert_eq!(layout.columns.len(), largest_component(&land).len());
+            assert!(layout.columns.len() as f32 >= land.len() as f32 * MIN_CONNECTED_SHARE);
+            checked += 1;
+        }
+        assert!(checked > 0, "no seed made it past the connectivity check");
+    }
+
+    #[test]
+    fn spawns_are_spaced_and_fair() {
+        let params = GeneratorParams { spawn_count: MAX_SPAWN_COUNT, ..GeneratorParams::default() };
+        for seed in SEEDS {
+            let layout = generate(seed, &params);
+            let rooms = spawn_rooms(&layout);
+            let least = rooms.iter().copied().fold(f32::INFINITY, f32::min);
+            let most = rooms.iter().copied().fold(0.0, f32::max);
+            assert!(least >= MIN_SPAWN_SPACING, "seed {seed}: spawns {least} apart");
+            assert!(least >= most * MIN_SPAWN_FAIRNESS, "seed {seed}: {least} against {most}");
+        }
+    }
+
+    // The fallback in `generate` has to work for anything the host can pick
+    #[test]
+    fn flat_fallback_always_fits() {
+        for size in [MIN_ARENA_SIZE, GeneratorParams::default().size, MAX_ARENA_SIZE] {
+            for seed in SEEDS {
+                let fewest = GeneratorParams {
+                    size,
+                    gaps: 0.0,
+                    height_variation: 0,
+                    spawn_count: MIN_SPAWN_COUNT,
+                    ..GeneratorParams::default()
+                };
+                assert!(try_generate(seed as u64, &fewest).is_some(), "size {size}, seed {seed}");
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use godot::prelude::*;
+use godot::classes::{CharacterBody3D, PhysicsRayQueryParameters3D};
+use godot::global::randf_range;
+use std::f32::consts::{PI, TAU};
+
+use crate::player::PLAYERS_GROUP;
+use crate::rocket::RocketFlight;
+use crate::input_source::{InputCommand, InputSource, PlayerView};
+
+// Bots only look at the arena itself when feeling their way around
+const ARENA_LAYER: u32 = 1;
+// Furthest a bot will pick a fight from
+const SIGHT_RANGE: f32 = 60.0;
+// Stays between these distances from its target, circling it
+const NEAR_DISTANCE: f32 = 8.0;
+const FAR_DISTANCE: f32 = 18.0;
+// How far ahead it checks for floor before walking there
+const FEELER_LENGTH: f32 = 1.5;
+// Anything closer than this counts as reaching a waypoint
+const WAYPOINT_RADIUS: f32 = 1.5;
+// Sideways speed, away from the arena middle, that means it's been blasted
+const KNOCKED_SPEED: f32 = 2.0;
+// Only fires once the crosshair is this close to where it wants it
+const FIRE_ANGLE: f32 = 0.05;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BotDifficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl BotDifficulty {
+    pub const ALL: [BotDifficulty; 3] =
+        [BotDifficulty::Easy, BotDifficulty::Normal, BotDifficulty::Hard];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            BotDifficulty::Easy => "Easy",
+            BotDifficulty::Normal => "Normal",
+            BotDifficulty::Hard => "Hard",
+        }
+    }
+
+    pub fn to_index(self) -> i64 {
+        Self::ALL.iter().position(|d| *d == self).unwrap_or(0) as i64
+    }
+
+    pub fn from_index(index: i64) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+
+    // Seconds between spotting someone and the first shot at them
+    fn reaction_time(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 0.8,
+            BotDifficulty::Normal => 0.4,
+            BotDifficulty::Hard => 0.15,
+        }
+    }
+
+    // Radians of wobble added to every shot
+    fn aim_error(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 0.12,
+            BotDifficulty::Normal => 0.05,
+            BotDifficulty::Hard => 0.015,
+        }
+    }
+
+    // Radians per second
+    fn turn_speed(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 2.0,
+            BotDifficulty::Normal => 3.5,
+            BotDifficulty::Hard => 6.0,
+        }
+    }
+
+    // How much of the target's movement it accounts for
+    fn lead(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 0.3,
+            BotDifficulty::Normal => 0.8,
+            BotDifficulty::Hard => 1.0,
+        }
+    }
+
+    fn rocket_jumps(self) -> bool {
+        self != BotDifficulty::Easy
+    }
+}
+
+// Plays the game from the host, through the same controls as a person
+pub struct Bot {
+    difficulty: BotDifficulty,
+    flight: RocketFlight,
+    rocket_speed: f32,
+    // Spots around the arena to wander between, and roughly its middle
+    waypoints: Vec<Vector3>,
+    center: Vector3,
+    waypoint: Option<Vector3>,
+    target: Option<InstanceId>,
+    reaction: f32,
+    aim_error: Vector3,
+    aim_error_timer: f32,
+    strafe: f32,
+    strafe_timer: f32,
+}
+
+impl Bot {
+    pub fn new(
+        difficulty: BotDifficulty,
+        flight: RocketFlight,
+        rocket_speed: f32,
+        waypoints: Vec<Vector3>,
+    ) -> Self {
+        let center = if waypoints.is_empty() {
+            Vector3::ZERO
+        } else {
+            waypoints.iter().copied().sum::<Vector3>() / waypoints.len() as f32
+        };
+        Self {
+            difficulty,
+            flight,
+            rocket_speed,
+            waypoints,
+            center,
+            waypoint: None,
+            target: None,
+            reaction: 0.0,
+            aim_error: Vector3::ZERO,
+            aim_error_timer: 0.0,
+            strafe: 1.0,
+            strafe_timer: 0.0,
+        }
+    }
+
+    // The closest player in sight, sticking with the current one while it's visible
+    fn pick_target(&mut self, view: &PlayerView, world: &Gd<Node3D>) -> Option<(Vector3, Vector3)> {
+        let mut tree = world.get_tree()?;
+        let mut best: Option<(f32, InstanceId, Vector3, Vector3)> = None;
+        for node in tree.get_nodes_in_group(PLAYERS_GROUP).iter_shared() {
+            if node.instance_id() == view.own_id {
+                continue;
+            }
+            let Ok(player) = node.try_cast::<Node3D>() else {
+                continue;
+            };
+            let position = player.get_global_position();
+            let distance = position.distance_to(view.position);
+            if distance > SIGHT_RANGE || cast(world, view.muzzle, position, &view.exclude).is_some() {
+                continue;
+            }
+            let velocity = player
+                .try_get_node_as::<CharacterBody3D>("PlayerKinematicBody")
+                .map(|body| body.get_velocity())
+                .unwrap_or_default();
+            // Whoever we were already after wins ties
+            let score = if Some(player.instance_id()) == self.target {
+                distance * 0.5
+            } else {
+                distance
+            };
+            if best.is_none_or(|(best_score, ..)| score < best_score) {
+                best = Some((score, player.instance_id(), position, velocity));
+            }
+        }
+        let new_target = best.map(|(_, id, ..)| id);
+        if new_target != self.target {
+            self.target = new_target;
+            self.reaction = self.difficulty.reaction_time();
+        }
+        best.map(|(_, _, position, velocity)| (position, velocity))
+    }
+
+    // Where to aim so a rocket meets the target, using the same flight
+    // model the rocket flies by. Each pass nudges the aim by however
+    // far the last one would have missed.
+    fn lead(&self, view: &PlayerView, position: Vector3, velocity: Vector3) -> Vector3 {
+        let mut aim = position;
+        let mut time = 0.0;
+        for _ in 0..4 {
+            let predicted = position + velocity * time * self.difficulty.lead();
+            let launch = (aim - view.muzzle).normalized_or_zero() * self.rocket_speed
+                + view.velocity;
+            let Some((flight_time, flown)) =
+                self.flight.fly(launch, predicted.distance_to(view.muzzle)) else {
+                break;
+            };
+            time = flight_time;
+            aim += predicted - (view.muzzle + flown);
+        }
+        aim
+    }
+
+    // Turns as far towards a point as it can this frame.
+    // Also says how far off the crosshair still is, in radians.
+    fn look_towards(&self, view: &PlayerView, point: Vector3, delta: f32) -> (Vector2, f32) {
+        let wanted = (point - view.muzzle).normalized_or_zero();
+        if wanted == Vector3::ZERO {
+            return (Vector2::ZERO, 0.0);
+        }
+        let yaw_error = wrap_angle(yaw_of(wanted) - yaw_of(view.aim));
+        let pitch_error = wanted.y.asin() - view.aim.y.asin();
+        let step = self.difficulty.turn_speed() * delta;
+        let look = Vector2::new(
+            -yaw_error.clamp(-step, step),
+            -pitch_error.clamp(-step, step),
+        );
+        (look, view.aim.angle_to(wanted))
+    }
+
+    fn fight_direction(&self, view: &PlayerView, target: Vector3) -> Vector3 {
+        let mut toward = target - view.position;
+        toward.y = 0.0;
+        let distance = toward.length();
+        let toward = toward.normalized_or_zero();
+        let side = Vector3::UP.cross(toward) * self.strafe;
+        let approach = if distance > FAR_DISTANCE {
+            toward
+        } else if distance < NEAR_DISTANCE {
+            -toward
+        } else {
+            Vector3::ZERO
+        };
+        (approach + side).normalized_or_zero()
+    }
+
+    fn wander_direction(&mut self, view: &PlayerView) -> Vector3 {
+        let reached = self.waypoint.is_none_or(|waypoint| {
+            let mut offset = waypoint - view.position;
+            offset.y = 0.0;
+            offset.length() < WAYPOINT_RADIUS
+        });
+        if reached {
+            self.waypoint = if self.waypoints.is_empty() {
+                None
+            } else {
+                let index = randf_range(0.0, self.waypoints.len() as f64) as usize;
+                self.waypoints.get(index).copied()
+            };
+        }
+        let Some(waypoint) = self.waypoint else {
+            return Vector3::ZERO;
+        };
+        let mut offset = waypoint - view.position;
+        offset.y = 0.0;
+        offset.normalized_or_zero()
+    }
+
+    // Turns away from drops, trying wider and wider angles.
+    // Stands still if there's floor nowhere around.
+    fn safe_direction(&self, view: &PlayerView, world: &Gd<Node3D>, direction: Vector3) -> Vector3 {
+        if direction == Vector3::ZERO || !view.on_floor {
+            return direction;
+        }
+        for angle in [0.0, 0.25, -0.25, 0.5, -0.5, 0.75, -0.75, 1.0] {
+            let candidate = direction.rotated(Vector3::UP, angle * PI);
+            if self.floor_at(view, world, view.position + candidate * FEELER_LENGTH) {
+                return candidate;
+            }
+        }
+        Vector3::ZERO
+    }
+
+    fn floor_at(&self, view: &PlayerView, world: &Gd<Node3D>, point: Vector3) -> bool {
+        cast(world, point + Vector3::UP * 0.5, point + Vector3::DOWN * 3.0, &view.exclude)
+            .is_some()
+    }
+
+    fn blocked(&self, view: &PlayerView, world: &Gd<Node3D>, direction: Vector3) -> bool {
+        cast(world, view.position, view.position + direction, &view.exclude).is_some()
+    }
+
+    // Blasted towards an edge: fire into the floor on the outside,
+    // so the explosion throws us back up and in
+    fn recovery_shot(&self, view: &PlayerView, world: &Gd<Node3D>) -> Option<Vector3> {
+        let mut outward = view.position - self.center;
+        outward.y = 0.0;
+        let outward = outward.normalized_or_zero();
+        if outward == Vector3::ZERO || view.velocity.dot(outward) < KNOCKED_SPEED {
+            return None;
+        }
+        if self.floor_at(view, world, view.position + outward * FEELER_LENGTH) {
+            return None;
+        }
+        let foot = view.position + outward * 0.5;
+        cast(world, foot + Vector3::UP * 0.5, foot + Vector3::DOWN * 3.0, &view.exclude)
+    }
+}
+
+impl InputSource for Bot {
+    fn poll(&mut self, view: &PlayerView, world: &Gd<Node3D>, delta: f32) -> InputCommand {
+        self.reaction -= delta;
+        self.aim_error_timer -= delta;
+        if self.aim_error_timer <= 0.0 {
+            self.aim_error_timer = 0.5;
+            let error = self.difficulty.aim_error();
+            self.aim_error = Vector3::new(random(error), random(error), random(error));
+        }
+        self.strafe_timer -= delta;
+        if self.strafe_timer <= 0.0 {
+            self.strafe_timer = randf_range(1.0, 2.5) as f32;
+            self.strafe = -self.strafe;
+        }
+
+        let target = self.pick_target(view, world);
+        let mut input = InputCommand::default();
+
+        // Staying on the map comes before anything else
+        let recovery = if self.difficulty.rocket_jumps() && view.loaded {
+            self.recovery_shot(view, world)
+        } else {
+            None
+        };
+
+        let (aim_point, wants_to_shoot) = if let Some(floor) = recovery {
+            (floor, true)
+        } else if let Some((position, velocity)) = target {
+            let point = self.lead(view, position, velocity);
+            let distance = (point - view.muzzle).length();
+            (point + self.aim_error * distance, self.reaction <= 0.0 && view.loaded)
+        } else {
+            let ahead = self.waypoint.unwrap_or(self.center);
+            (Vector3::new(ahead.x, view.muzzle.y, ahead.z), false)
+        };
+        let (look, off_by) = self.look_towards(view, aim_point, delta);
+        input.look = look;
+        input.shoot = wants_to_shoot && off_by < FIRE_ANGLE;
+
+        let direction = match (recovery, target) {
+            // Let the blast do the work
+            (Some(_), _) => Vector3::ZERO,
+            (None, Some((position, _))) => self.fight_direction(view, position),
+            (None, None) => self.wander_direction(view),
+        };
+        let direction = self.safe_direction(view, world, direction);
+        let local = view.basis.transposed() * direction;
+        input.movement = Vector2::new(local.x, local.z);
+        input.jump = view.on_floor && direction != Vector3::ZERO
+            && self.blocked(view, world, direction);
+        input
+    }
+}
+
+// Where a ray hits the arena, if it does
+pub fn cast(world: &Gd<Node3D>, from: Vector3, to: Vector3, exclude: &Array<Rid>) -> Option<Vector3> {
+    let mut space = world.get_world_3d()?.get_direct_space_state()?;
+    let query = PhysicsRayQueryParameters3D::create_ex(from, to)
+        .collision_mask(ARENA_LAYER)
+        .exclude(exclude)
+        .done()?;
+    space
+        .intersect_ray(&query)
+        .get("position")
+        .and_then(|position| position.try_to::<Vector3>().ok())
+}
+
+// Matches the body's rotation.y, looking down -Z
+fn yaw_of(direction: Vector3) -> f32 {
+    (-direction.x).atan2(-direction.z)
+}
+
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+fn random(spread: f32) -> f32 {
+    randf_range(-spread as f64, spread as f64) as f32
+}
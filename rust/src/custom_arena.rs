@@ -0,0 +1,588 @@
+use godot::prelude::*;
+
+use godot::classes::{
+    GridMap, IGridMap, Json, MeshLibrary, DirAccess, FileAccess,
+    CollisionShape3D, BoxShape3D,
+};
+use godot::classes::file_access::ModeFlags;
+use godot::global::Error;
+
+use crate::arena::{spawn_points_node, mines_node, default_bounds, BOUNDS_META};
+use crate::kill_volume::{KillVolume, KnockoutReason};
+use crate::settings::write_atomic;
+
+// A custom arena is a JSON file in CUSTOM_ARENAS_DIR, named after its id:
+//
+// {
+//     "format": "bazooka-wars-arena",
+//     "version": 1,
+//     "name": "Stepping Stones",
+//     "preview": "Optional line for the lobby",
+//     "cells": [[x, y, z, item], ...],
+//     "spawn_points": [[x, y, z], ...],
+//     "mines": [[x, y, z], ...],
+//     "exploders": [[x, y, z], ...],
+//     "hazards": [{ "position": [x, y, z], "size": [x, y, z], "reason": "hazard" }],
+//     "bounds": { "position": [x, y, z], "size": [x, y, z] }
+// }
+//
+// Mines, exploders, hazards and bounds can be left out. Hazard reasons are "fell",
+// "blasted_out" or "hazard", the default.
+pub const ARENA_FORMAT: &str = "bazooka-wars-arena";
+pub const ARENA_VERSION: i64 = 1;
+// Arenas people make themselves go here
+pub const CUSTOM_ARENAS_DIR: &str = "user://arenas";
+// Arenas sent over by a host, kept apart so they never overwrite one of ours
+pub const RECEIVED_ARENAS_DIR: &str = "user://arenas/received";
+pub const ARENA_EXTENSION: &str = "json";
+// Custom arenas are built out of these, and only these, items
+pub const MESH_LIBRARY: &str = "res://arenas/MeshLibrary.tres";
+// Keeps a file small enough to send to everyone before a match
+const MAX_CELLS: usize = 65536;
+// GridMap cell coordinates are stored in 16 bits
+const MAX_CELL_COORD: i64 = i16::MAX as i64;
+
+// A box that knocks out anyone who touches it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hazard {
+    pub position: Vector3,
+    pub size: Vector3,
+    pub reason: KnockoutReason,
+}
+
+// An arena as it's written down in a file, rather than in a scene
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArenaFile {
+    pub name: String,
+    pub preview: String,
+    // Cell and the mesh library item in it
+    pub cells: Vec<(Vector3i, i32)>,
+    pub spawn_points: Vec<Vector3>,
+    pub mines: Vec<Vector3>,
+    // RepeatExploders, going off on their own every so often
+    pub exploders: Vec<Vector3>,
+    pub hazards: Vec<Hazard>,
+    pub bounds: Aabb,
+}
+
+impl ArenaFile {
+    pub fn to_dict(&self) -> VarDictionary {
+        let cells: VarArray = self.cells
+            .iter()
+            .map(|(cell, item)| varray![cell.x, cell.y, cell.z, *item].to_variant())
+            .collect();
+        let hazards: VarArray = self.hazards
+            .iter()
+            .map(|hazard| vdict! {
+                "position": vector_to_array(hazard.position),
+                "size": vector_to_array(hazard.size),
+                "reason": reason_key(hazard.reason),
+            }.to_variant())
+            .collect();
+        vdict! {
+            "format": ARENA_FORMAT,
+            "version": ARENA_VERSION,
+            "name": self.name.clone(),
+            "preview": self.preview.clone(),
+            "cells": cells,
+            "spawn_points": vectors_to_array(&self.spawn_points),
+            "mines": vectors_to_array(&self.mines),
+            "exploders": vectors_to_array(&self.exploders),
+            "hazards": hazards,
+            "bounds": vdict! {
+                "position": vector_to_array(self.bounds.position),
+                "size": vector_to_array(self.bounds.size),
+            },
+        }
+    }
+
+    // Every problem comes back as a message saying where in the file it is
+    pub fn from_dict(dict: &VarDictionary) -> Result<Self, String> {
+        let format = dict.get("format")
+            .and_then(|v| v.try_to::<GString>().ok())
+            .ok_or("format: missing, expected \"bazooka-wars-arena\"")?;
+        let version = integer(&field(dict, "version")?, "version")?;
+        check_header(&format.to_string(), version)?;
+        let name = text(&field(dict, "name")?, "name")?;
+        if name.trim().is_empty() {
+            return Err("name: can't be empty".to_string());
+        }
+        let preview = match dict.get("preview") {
+            Some(preview) => text(&preview, "preview")?,
+            None => String::new(),
+        };
+
+        let items = mesh_library_items();
+        let cells = array(&field(dict, "cells")?, "cells")?;
+        check_cell_count(cells.len())?;
+        let cells = cells
+            .iter_shared()
+            .enumerate()
+            .map(|(index, cell)| parse_cell(&cell, &format!("cells[{index}]"), &items))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let spawn_points = vectors(&field(dict, "spawn_points")?, "spawn_points")?;
+        let mines = match dict.get("mines") {
+            Some(mines) => vectors(&mines, "mines")?,
+            None => Vec::new(),
+        };
+        let exploders = match dict.get("exploders") {
+            Some(exploders) => vectors(&exploders, "exploders")?,
+            None => Vec::new(),
+        };
+        let hazards = match dict.get("hazards") {
+            Some(hazards) => array(&hazards, "hazards")?
+                .iter_shared()
+                .enumerate()
+                .map(|(index, hazard)| parse_hazard(&hazard, &format!("hazards[{index}]")))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        let bounds = match dict.get("bounds") {
+            Some(bounds) => parse_bounds(&bounds, "bounds")?,
+            None => default_bounds(),
+        };
+        check_spawn_points(&spawn_points, bounds)?;
+
+        Ok(Self { name, preview, cells, spawn_points, mines, exploders, hazards, bounds })
+    }
+
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let mut parser = Json::new_gd();
+        if parser.parse(json) != Error::OK {
+            return Err(format!(
+                "line {}: {}",
+                parser.get_error_line(),
+                parser.get_error_message(),
+            ));
+        }
+        let dict = parser
+            .get_data()
+            .try_to::<VarDictionary>()
+            .map_err(|_| "expected an object at the top".to_string())?;
+        Self::from_dict(&dict)
+    }
+
+    pub fn to_json(&self) -> GString {
+        Json::stringify_ex(&self.to_dict().to_variant()).indent("\t").done()
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = read_text(path).ok_or_else(|| format!("{path}: couldn't be read"))?;
+        Self::parse(&json).map_err(|error| format!("{path}: {error}"))
+    }
+
+    pub fn save(&self, path: &str) -> bool {
+        DirAccess::make_dir_recursive_absolute(CUSTOM_ARENAS_DIR);
+        write_text(path, &self.to_json().to_string())
+    }
+}
+
+// What a match calls a custom arena: its file name, without the extension
+pub fn arena_path(dir: &str, id: &str) -> String {
+    format!("{dir}/{id}.{ARENA_EXTENSION}")
+}
+
+// Ids of every custom arena we made ourselves, sorted
+pub fn list_custom_arenas() -> Vec<String> {
+    // So there's somewhere to put them
+    DirAccess::make_dir_recursive_absolute(CUSTOM_ARENAS_DIR);
+    let mut ids: Vec<String> = DirAccess::get_files_at(CUSTOM_ARENAS_DIR)
+        .as_slice()
+        .iter()
+        .filter_map(|file| {
+            file.to_string()
+                .strip_suffix(&format!(".{ARENA_EXTENSION}"))
+                .map(str::to_string)
+        })
+        .collect();
+    ids.sort();
+    ids
+}
+
+// Only ids that are plain file names can come over the network
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.starts_with('.') && GString::from(id).is_valid_filename()
+}
+
+pub fn read_text(path: &str) -> Option<String> {
+    let file = FileAccess::open(path, ModeFlags::READ)?;
+    Some(file.get_as_text().to_string())
+}
+
+// Through a temp file, so nothing half written is ever left at `path`
+fn write_text(path: &str, text: &str) -> bool {
+    let err = write_atomic(&GString::from(path), |temp_path| {
+        let Some(mut file) = FileAccess::open(temp_path, ModeFlags::WRITE) else {
+            return FileAccess::get_open_error();
+        };
+        if file.store_string(text) { Error::OK } else { Error::ERR_FILE_CANT_WRITE }
+    });
+    err == Error::OK
+}
+
+// Our own copy of the arena, or else one a host sent us, as long as it's
+// exactly the same file the host has
+pub fn find_arena_text(id: &str, hash: i64) -> Option<String> {
+    if !is_valid_id(id) {
+        return None;
+    }
+    [CUSTOM_ARENAS_DIR, RECEIVED_ARENAS_DIR]
+        .iter()
+        .filter_map(|dir| read_text(&arena_path(dir, id)))
+        .find(|json| content_hash(json) == hash)
+}
+
+// Zero when we don't have it
+pub fn own_arena_hash(id: &str) -> i64 {
+    if !is_valid_id(id) {
+        return 0;
+    }
+    read_text(&arena_path(CUSTOM_ARENAS_DIR, id)).map_or(0, |json| content_hash(&json))
+}
+
+// Keeps an arena a host sent, after making sure it's a real arena
+pub fn store_received_arena(id: &str, json: &str) -> Result<ArenaFile, String> {
+    if !is_valid_id(id) {
+        return Err(format!("\"{id}\" isn't a valid arena name"));
+    }
+    let arena = ArenaFile::parse(json)?;
+    DirAccess::make_dir_recursive_absolute(RECEIVED_ARENAS_DIR);
+    let path = arena_path(RECEIVED_ARENAS_DIR, id);
+    if !write_text(&path, json) {
+        return Err(format!("{path}: couldn't be written"));
+    }
+    Ok(arena)
+}
+
+// FNV-1a, so every machine agrees on it without the engine
+pub fn content_hash(json: &str) -> i64 {
+    json.bytes()
+        .fold(0xCBF2_9CE4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+        }) as i64
+}
+
+fn mesh_library_items() -> Vec<i32> {
+    load::<MeshLibrary>(MESH_LIBRARY).get_item_list().as_slice().to_vec()
+}
+
+fn reason_key(reason: KnockoutReason) -> &'static str {
+    match reason {
+        KnockoutReason::Fell => "fell",
+        KnockoutReason::BlastedOut => "blasted_out",
+        KnockoutReason::Hazard => "hazard",
+    }
+}
+
+fn vector_to_array(vector: Vector3) -> VarArray {
+    varray![vector.x, vector.y, vector.z]
+}
+
+fn vectors_to_array(vectors: &[Vector3]) -> VarArray {
+    vectors.iter().map(|vector| vector_to_array(*vector).to_variant()).collect()
+}
+
+fn field(dict: &VarDictionary, key: &str) -> Result<Variant, String> {
+    dict.get(key).ok_or_else(|| format!("{key}: missing"))
+}
+
+fn array(value: &Variant, at: &str) -> Result<VarArray, String> {
+    value.try_to::<VarArray>().map_err(|_| format!("{at}: expected an array"))
+}
+
+fn text(value: &Variant, at: &str) -> Result<String, String> {
+    value
+        .try_to::<GString>()
+        .map(|text| text.to_string())
+        .map_err(|_| format!("{at}: expected a string"))
+}
+
+// JSON only has floats, but written by hand they may come in either way
+fn number(value: &Variant, at: &str) -> Result<f64, String> {
+    value
+        .try_to::<f64>()
+        .or_else(|_| value.try_to::<i64>().map(|number| number as f64))
+        .ok()
+        .filter(|number| number.is_finite())
+        .ok_or_else(|| format!("{at}: expected a number"))
+}
+
+fn integer(value: &Variant, at: &str) -> Result<i64, String> {
+    let number = number(value, at)?;
+    if number.fract() != 0.0 {
+        return Err(format!("{at}: expected a whole number, got {number}"));
+    }
+    Ok(number as i64)
+}
+
+fn numbers<const N: usize>(value: &Variant, at: &str) -> Result<[f64; N], String> {
+    let values = array(value, at)?;
+    if values.len() != N {
+        return Err(format!("{at}: expected {N} numbers, got {}", values.len()));
+    }
+    let mut numbers = [0.0; N];
+    for (index, value) in values.iter_shared().enumerate() {
+        numbers[index] = number(&value, &format!("{at}[{index}]"))?;
+    }
+    Ok(numbers)
+}
+
+fn vector(value: &Variant, at: &str) -> Result<Vector3, String> {
+    let [x, y, z] = numbers::<3>(value, at)?;
+    Ok(Vector3::new(x as f32, y as f32, z as f32))
+}
+
+fn vectors(value: &Variant, at: &str) -> Result<Vec<Vector3>, String> {
+    array(value, at)?
+        .iter_shared()
+        .enumerate()
+        .map(|(index, point)| vector(&point, &format!("{at}[{index}]")))
+        .collect()
+}
+
+// The checks from `from_dict` that don't need the engine, so tests can run them
+fn check_header(format: &str, version: i64) -> Result<(), String> {
+    if format != ARENA_FORMAT {
+        return Err(format!("format: expected \"{ARENA_FORMAT}\", got \"{format}\""));
+    }
+    if version < 1 {
+        return Err(format!("version: expected 1 or more, got {version}"));
+    }
+    if version > ARENA_VERSION {
+        return Err(format!(
+            "version: {version} is newer than this game understands ({ARENA_VERSION})"
+        ));
+    }
+    Ok(())
+}
+
+fn check_cell_count(count: usize) -> Result<(), String> {
+    if count == 0 {
+        return Err("cells: needs at least one cell to stand on".to_string());
+    }
+    if count > MAX_CELLS {
+        return Err(format!("cells: {count} is more than the {MAX_CELLS} allowed"));
+    }
+    Ok(())
+}
+
+fn check_spawn_points(spawn_points: &[Vector3], bounds: Aabb) -> Result<(), String> {
+    if spawn_points.is_empty() {
+        return Err("spawn_points: needs at least one".to_string());
+    }
+    if let Some(index) = spawn_points.iter().position(|point| !bounds.contains_point(*point)) {
+        return Err(format!("spawn_points[{index}]: outside the bounds"));
+    }
+    Ok(())
+}
+
+// [x, y, z, item]
+fn parse_cell(value: &Variant, at: &str, items: &[i32]) -> Result<(Vector3i, i32), String> {
+    let values = array(value, at)?;
+    if values.len() != 4 {
+        return Err(format!("{at}: expected [x, y, z, item], got {} numbers", values.len()));
+    }
+    let mut coords = [0; 4];
+    for (index, value) in values.iter_shared().enumerate() {
+        coords[index] = integer(&value, &format!("{at}[{index}]"))?;
+    }
+    check_cell(coords, at, items)
+}
+
+fn check_cell(coords: [i64; 4], at: &str, items: &[i32]) -> Result<(Vector3i, i32), String> {
+    let [x, y, z, item] = coords;
+    if [x, y, z].iter().any(|coord| coord.abs() > MAX_CELL_COORD) {
+        return Err(format!("{at}: cells only go out to {MAX_CELL_COORD} either way"));
+    }
+    let known = i32::try_from(item).ok().filter(|item| items.contains(item));
+    let Some(item) = known else {
+        return Err(format!("{at}: item {item} isn't in {MESH_LIBRARY} (has {items:?})"));
+    };
+    Ok((Vector3i::new(x as i32, y as i32, z as i32), item))
+}
+
+fn parse_size(value: &Variant, at: &str) -> Result<Vector3, String> {
+    let size = vector(value, at)?;
+    if size.x <= 0.0 || size.y <= 0.0 || size.z <= 0.0 {
+        return Err(format!("{at}: every side has to be bigger than zero"));
+    }
+    Ok(size)
+}
+
+fn parse_hazard(value: &Variant, at: &str) -> Result<Hazard, String> {
+    let dict = value
+        .try_to::<VarDictionary>()
+        .map_err(|_| format!("{at}: expected an object"))?;
+    let position = vector(&field(&dict, "position").map_err(|e| format!("{at}.{e}"))?,
+        &format!("{at}.position"))?;
+    let size = parse_size(&field(&dict, "size").map_err(|e| format!("{at}.{e}"))?,
+        &format!("{at}.size"))?;
+    let reason = match dict.get("reason") {
+        Some(reason) => {
+            let key = text(&reason, &format!("{at}.reason"))?;
+            [KnockoutReason::Fell, KnockoutReason::BlastedOut, KnockoutReason::Hazard]
+                .into_iter()
+                .find(|reason| reason_key(*reason) == key)
+                .ok_or_else(|| format!(
+                    "{at}.reason: expected \"fell\", \"blasted_out\" or \"hazard\", got \"{key}\""
+                ))?
+        }
+        None => KnockoutReason::Hazard,
+    };
+    Ok(Hazard { position, size, reason })
+}
+
+fn parse_bounds(value: &Variant, at: &str) -> Result<Aabb, String> {
+    let dict = value
+        .try_to::<VarDictionary>()
+        .map_err(|_| format!("{at}: expected an object"))?;
+    let position = vector(&field(&dict, "position").map_err(|e| format!("{at}.{e}"))?,
+        &format!("{at}.position"))?;
+    let size = parse_size(&field(&dict, "size").map_err(|e| format!("{at}.{e}"))?,
+        &format!("{at}.size"))?;
+    Ok(Aabb::new(position, size))
+}
+
+// A GridMap that builds itself out of an arena file when it enters the game
+#[derive(GodotClass)]
+#[class(init, base=GridMap)]
+pub struct CustomArena {
+    #[init(val=OnReady::from_loaded("res://explosion/mine.tscn"))]
+    mine_scene: OnReady<Gd<PackedScene>>,
+    #[init(val=OnReady::from_loaded("res://explosion/repeat_exploder.tscn"))]
+    exploder_scene: OnReady<Gd<PackedScene>>,
+    file: Option<ArenaFile>,
+    base: Base<GridMap>,
+}
+
+#[godot_api]
+impl IGridMap for CustomArena {
+    fn ready(&mut self) {
+        if let Some(file) = self.file.take() {
+            self.build(&file);
+        }
+    }
+}
+
+#[godot_api]
+impl CustomArena {
+    // Must be called before the arena enters the tree
+    pub fn set_file(&mut self, file: ArenaFile) {
+        self.file = Some(file);
+    }
+
+    fn build(&mut self, file: &ArenaFile) {
+        self.base_mut().clear();
+        for (cell, item) in &file.cells {
+            self.base_mut().set_cell_item(*cell, *item);
+        }
+
+        let spawn_points = spawn_points_node(&file.spawn_points);
+        self.base_mut().add_child(&spawn_points);
+        let mines = mines_node(&self.mine_scene, &file.mines);
+        self.base_mut().add_child(&mines);
+
+        let mut exploders = Node::new_alloc();
+        exploders.set_name("exploders");
+        for (index, position) in file.exploders.iter().enumerate() {
+            let mut exploder = self.exploder_scene.instantiate_as::<Node3D>();
+            exploder.set_name(&format!("Exploder{}", index + 1));
+            exploder.set_position(*position);
+            exploders.add_child(&exploder);
+        }
+        self.base_mut().add_child(&exploders);
+
+        let mut hazards = Node::new_alloc();
+        hazards.set_name("hazards");
+        for (index, hazard) in file.hazards.iter().enumerate() {
+            let mut shape = BoxShape3D::new_gd();
+            shape.set_size(hazard.size);
+            let mut collider = CollisionShape3D::new_alloc();
+            collider.set_shape(&shape);
+            let mut volume = KillVolume::new_alloc();
+            volume.set_name(&format!("Hazard{}", index + 1));
+            volume.set_position(hazard.position);
+            volume.set("reason", &hazard.reason.to_variant());
+            volume.add_child(&collider);
+            hazards.add_child(&volume);
+        }
+        self.base_mut().add_child(&hazards);
+
+        self.base_mut().set_meta(BOUNDS_META, &file.bounds.to_variant());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITEMS: [i32; 3] = [0, 1, 4];
+
+    fn bounds() -> Aabb {
+        Aabb::new(Vector3::new(-10.0, -10.0, -10.0), Vector3::new(20.0, 20.0, 20.0))
+    }
+
+    #[test]
+    fn content_hash_is_fnv_1a() {
+        assert_eq!(content_hash(""), 0xCBF2_9CE4_8422_2325_u64 as i64);
+        assert_eq!(content_hash("a"), 0xAF63_DC4C_8601_EC8C_u64 as i64);
+        assert_eq!(content_hash("foobar"), 0x8594_4171_F739_67E8_u64 as i64);
+    }
+
+    #[test]
+    fn content_hash_sees_every_byte() {
+        let json = r#"{"format": "bazooka-wars-arena", "version": 1}"#;
+        assert_eq!(content_hash(json), content_hash(json));
+        assert_ne!(content_hash(json), content_hash(&json.replace('1', "2")));
+        assert_ne!(content_hash(json), content_hash(&format!("{json} ")));
+    }
+
+    #[test]
+    fn header_checks_format_and_version() {
+        assert!(check_header(ARENA_FORMAT, 1).is_ok());
+        assert!(check_header("bazooka-wars-replay", 1).unwrap_err().starts_with("format:"));
+        assert!(check_header("", 1).unwrap_err().starts_with("format:"));
+        assert!(check_header(ARENA_FORMAT, 0).unwrap_err().starts_with("version:"));
+        assert!(check_header(ARENA_FORMAT, -3).unwrap_err().starts_with("version:"));
+        let newer = check_header(ARENA_FORMAT, ARENA_VERSION + 1).unwrap_err();
+        assert!(newer.contains("newer"), "{newer}");
+    }
+
+    #[test]
+    fn cell_count_needs_some_but_not_too_many() {
+        assert!(check_cell_count(0).unwrap_err().starts_with("cells:"));
+        assert!(check_cell_count(1).is_ok());
+        assert!(check_cell_count(MAX_CELLS).is_ok());
+        assert!(check_cell_count(MAX_CELLS + 1).unwrap_err().starts_with("cells:"));
+    }
+
+    #[test]
+    fn cells_need_a_known_item() {
+        assert_eq!(check_cell([1, 2, 3, 4], "cells[0]", &ITEMS), Ok((Vector3i::new(1, 2, 3), 4)));
+        let unknown = check_cell([0, 0, 0, 2], "cells[5]", &ITEMS).unwrap_err();
+        assert!(unknown.starts_with("cells[5]: item 2"), "{unknown}");
+        // Would wrap to item 0 if it were cast straight to an i32
+        let huge = check_cell([0, 0, 0, 1 << 32], "cells[0]", &ITEMS).unwrap_err();
+        assert!(huge.contains("isn't in"), "{huge}");
+    }
+
+    #[test]
+    fn cells_stay_in_gridmap_range() {
+        let edge = MAX_CELL_COORD;
+        assert!(check_cell([edge, -edge, 0, 0], "cells[0]", &ITEMS).is_ok());
+        assert!(check_cell([edge + 1, 0, 0, 0], "cells[0]", &ITEMS).is_err());
+        assert!(check_cell([0, 0, -edge - 1, 0], "cells[0]", &ITEMS).is_err());
+    }
+
+    #[test]
+    fn spawn_points_must_exist_inside_the_bounds() {
+        assert!(check_spawn_points(&[], bounds()).unwrap_err().starts_with("spawn_points:"));
+        assert!(check_spawn_points(&[Vector3::ZERO, Vector3::ONE], bounds()).is_ok());
+        let outside = [Vector3::ZERO, Vector3::new(0.0, 50.0, 0.0)];
+        assert_eq!(
+            check_spawn_points(&outside, bounds()),
+            Err("spawn_points[1]: outside the bounds".to_string()),
+        );
+    }
+}
@@ -1,19 +1,34 @@
+use std::collections::HashMap;
+
 use godot::prelude::*;
+use godot::global::randf_range;
 
 #[allow(unused_imports)]
-use godot::classes::{Area3D, IArea3D, Node3D, INode3D,
-    GpuParticles3D, Timer, ITimer
+use godot::classes::{Area3D, IArea3D, Node3D, INode3D, Node, INode, MeshInstance3D,
+    GpuParticles3D, Timer, ITimer, AudioStreamPlayer3D, CollisionShape3D, SphereShape3D, CylinderShape3D,
 };
 
 #[allow(unused_imports)]
 use crate::player::{Player, PlayerKinematicBody, PlayerDynamicBody};
+use crate::roster::peer_of;
+use crate::arena::HAZARDS_GROUP;
+use crate::terrain::{DestructibleTerrain, TERRAIN_GROUP};
+
+// Group every Explosion joins until it's done
+pub const EXPLOSIONS_GROUP: &str = "explosions";
 
 #[derive(GodotClass)]
 #[class(base=Area3D, init)]
 pub struct Explosion {
     #[init(node="ExplosionParticles")]
     explosion_particles: OnReady<Gd<GpuParticles3D>>,
+    #[init(node="CollisionSphere")]
+    collision_sphere: OnReady<Gd<CollisionShape3D>>,
     physics_time: f32,
+    // The player whose rocket or mine this was, zero for nobody's
+    owner_id: i64,
+    // Replayed, see `make_puppet`
+    puppet: bool,
     base: Base<Area3D>,
 }
 
@@ -29,6 +44,23 @@ impl IArea3D for Explosion {
             .signals()
             .finished()
             .connect_other(&self.to_gd(), Self::on_done_exploding);
+        if self.puppet {
+            return;
+        }
+        self.base_mut().add_to_group(EXPLOSIONS_GROUP);
+
+        // Wears the arena down, when the match has that turned on
+        let center = self.base().get_global_position();
+        let radius = self.radius();
+        let terrain = self.base()
+            .get_tree()
+            .map(|mut tree| tree.get_nodes_in_group(TERRAIN_GROUP))
+            .unwrap_or_default();
+        for terrain in terrain.iter_shared() {
+            if let Ok(mut terrain) = terrain.try_cast::<DestructibleTerrain>() {
+                terrain.bind_mut().blast(center, radius);
+            }
+        }
     }
 
     fn physics_process(&mut self, delta: f32) {
@@ -49,53 +81,282 @@ impl Explosion {
     pub fn get_time(&self) -> f32 {
         self.physics_time
     }
+
+    pub fn set_owner_id(&mut self, owner_id: i64) {
+        self.owner_id = owner_id;
+    }
+
+    pub fn get_owner_id(&self) -> i64 {
+        self.owner_id
+    }
+
+    // How far the blast reaches
+    pub fn radius(&self) -> f32 {
+        let scale = self.base().get_global_transform().basis.get_scale();
+        self.collision_sphere
+            .get_shape()
+            .and_then(|shape| shape.try_cast::<SphereShape3D>().ok())
+            .map_or(0.0, |sphere| sphere.get_radius() * scale.x)
+    }
+
+    // Just for show, in a replay. Nothing feels it and nothing records it,
+    // and it leaves the terrain alone. Has to happen before it's added.
+    pub fn make_puppet(&mut self) {
+        self.puppet = true;
+        self.base_mut().set_monitorable(false);
+        self.base_mut().set_monitoring(false);
+    }
 }
 
-// A repeating explosion emitter
+// How quickly a warning flashes, in flashes per second
+const WARNING_FLASH_RATE: f64 = 6.0;
+
+// A repeating explosion emitter. The host picks when and where each
+// blast lands and warns everyone ahead of time, so every peer sees
+// the same blast go off at the same moment.
 #[derive(GodotClass)]
 #[class(base=Node3D, init)]
 pub struct RepeatExploder {
     #[init(val=OnReady::from_loaded("res://explosion/rocket_explosion.tscn"))]
     explosion_scene: OnReady<Gd<PackedScene>>,
-    #[init(node="Timer")]
-    timer: OnReady<Gd<Timer>>,
+    // Shown flashing where the next blast will land
+    #[init(node="Warning")]
+    warning: OnReady<Gd<Node3D>>,
+    // Played as the warning starts, if it has a stream
+    #[init(node="WarningSound")]
+    warning_sound: OnReady<Gd<AudioStreamPlayer3D>>,
+    // Seconds from one blast to the next
+    #[export]
+    #[init(val=3.0)]
+    interval: f64,
+    // Seconds of warning before each blast, part of the interval
+    #[export]
+    #[init(val=0.75)]
+    warning_time: f64,
+    // Half the size of the box around the exploder blasts land in
+    #[export]
+    spread: Vector3,
+    #[export]
+    #[init(val=1.0)]
+    explosion_scale: f32,
+    // Switched by arena triggers, see ExploderSwitch
+    #[export]
+    #[init(val=true)]
+    active: bool,
+    // Host only: seconds until the next warning
+    countdown: f64,
+    // The blast that's been warned about, relative to the exploder,
+    // and the seconds until it goes off
+    pending: Option<(Vector3, f64)>,
     base: Base<Node3D>,
 }
 
 #[godot_api]
 impl INode3D for RepeatExploder {
     fn ready(&mut self) {
-        self.timer.start();
-        self.timer
-            .signals()
-            .timeout()
-            .connect_other(&self.to_gd(), Self::on_timer_timeout);
+        self.base_mut().add_to_group(HAZARDS_GROUP);
+        self.warning.set_visible(false);
+        self.countdown = (self.interval - self.warning_time).max(0.0);
+        // The match might have been going a while already
+        if !self.base().is_multiplayer_authority() {
+            let host = self.base().get_multiplayer_authority() as i64;
+            self.base_mut().rpc_id(host, "request_active", &[]);
+        }
+    }
+
+    fn process(&mut self, delta: f64) {
+        if let Some((offset, seconds)) = self.pending.as_mut() {
+            *seconds -= delta;
+            let (offset, seconds) = (*offset, *seconds);
+            if seconds <= 0.0 {
+                self.pending = None;
+                self.warning.set_visible(false);
+                self.spawn_new_explosion(offset);
+            } else {
+                let flash = (seconds * WARNING_FLASH_RATE).fract() < 0.5;
+                self.warning.set_visible(flash);
+            }
+        }
+        if !self.base().is_multiplayer_authority() || !self.active {
+            return;
+        }
+        self.countdown -= delta;
+        if self.countdown <= 0.0 {
+            self.countdown += self.interval.max(self.warning_time).max(0.1);
+            let spread = self.spread;
+            let offset = Vector3::new(
+                randf_range(-spread.x as f64, spread.x as f64) as f32,
+                randf_range(-spread.y as f64, spread.y as f64) as f32,
+                randf_range(-spread.z as f64, spread.z as f64) as f32,
+            );
+            self.base_mut().rpc("warn", vslice![offset]);
+        }
     }
 }
 
 #[godot_api]
 impl RepeatExploder {
+    // Host only, everyone else hears about it
     #[func]
-    fn on_timer_timeout(&mut self) {
-        godot_print!("timeout");
-        self.spawn_new_explosion();
-        self.timer.start();
+    pub fn switch(&mut self, active: bool) {
+        if self.base().is_multiplayer_authority() && active != self.active {
+            self.base_mut().rpc("sync_active", vslice![active]);
+        }
     }
 
-    #[func]
-    pub fn spawn_new_explosion(&mut self) {
-        let explosion = self.explosion_scene
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    #[rpc(authority, call_local, reliable)]
+    fn sync_active(&mut self, active: bool) {
+        self.active = active;
+        // Starts over with a full interval when turned back on
+        self.countdown = (self.interval - self.warning_time).max(0.0);
+    }
+
+    #[rpc(any_peer, call_remote, reliable)]
+    fn request_active(&mut self) {
+        if !self.base().is_multiplayer_authority() {
+            return;
+        }
+        let peer = self.base().get_multiplayer().unwrap().get_remote_sender_id() as i64;
+        let active = self.active;
+        self.base_mut().rpc_id(peer, "sync_active", vslice![active]);
+    }
+
+    // A blast is coming to `offset`, `warning_time` from now
+    #[rpc(authority, call_local, reliable)]
+    fn warn(&mut self, offset: Vector3) {
+        self.pending = Some((offset, self.warning_time));
+        self.warning.set_position(offset);
+        self.warning.set_visible(true);
+        if self.warning_sound.get_stream().is_some() {
+            self.warning_sound.set_position(offset);
+            self.warning_sound.play();
+        }
+    }
+
+    pub fn spawn_new_explosion(&mut self, offset: Vector3) {
+        let mut explosion = self.explosion_scene
             .instantiate_as::<Explosion>();
+        // Explosions are top level, so this has to be global
+        let position = self.base().to_global(offset);
+        explosion.set_position(position);
+        explosion.set_scale(Vector3::ONE * self.explosion_scale);
         self.base_mut().add_child(&explosion);
     }
 }
 
-// A mine. Don't mine it
+// How an ExploderSwitch changes its exploders
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum SwitchAction {
+    #[default]
+    TurnOn = 0,
+    TurnOff = 1,
+    Toggle = 2,
+}
+
+// Turns exploders on or off when a player walks into it.
+// Arenas place these and point them at their exploders.
+#[derive(GodotClass)]
+#[class(base=Area3D, init)]
+pub struct ExploderSwitch {
+    #[export]
+    exploders: Array<Gd<RepeatExploder>>,
+    #[export]
+    action: SwitchAction,
+    // Only works the first time
+    #[export]
+    once: bool,
+    used: bool,
+    base: Base<Area3D>,
+}
+
+#[godot_api]
+impl IArea3D for ExploderSwitch {
+    fn ready(&mut self) {
+        self.signals()
+            .area_entered()
+            .connect_self(Self::on_area_entered);
+    }
+}
+
+#[godot_api]
+impl ExploderSwitch {
+    #[func]
+    fn on_area_entered(&mut self, area: Gd<Area3D>) {
+        if !self.base().is_multiplayer_authority()
+                || (self.once && self.used)
+                || area.try_cast::<Player>().is_err() {
+            return;
+        }
+        self.used = true;
+        for mut exploder in self.exploders.iter_shared() {
+            let active = match self.action {
+                SwitchAction::TurnOn => true,
+                SwitchAction::TurnOff => false,
+                SwitchAction::Toggle => !exploder.bind().is_active(),
+            };
+            exploder.bind_mut().switch(active);
+        }
+    }
+}
+
+// Where a mine is in its life. Sent over the network as its index.
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum MineState {
+    // Just placed or back again, nothing sets it off yet
+    #[default]
+    Arming = 0,
+    Armed = 1,
+    // Blown up, and maybe waiting to come back
+    Spent = 2,
+}
+
+// Seconds between a blast reaching a mine and the mine going off,
+// so chain reactions can be seen running along
+const CHAIN_DELAY: f64 = 0.15;
+// How see-through other players' mines are
+const ENEMY_MINE_TRANSPARENCY: f32 = 0.85;
+
+// A mine. Don't mine it.
+// The host decides when it arms and blows up, and tells everyone.
+// Spent mines stay in the tree, hidden, so every peer keeps the same nodes.
 #[derive(GodotClass)]
 #[class(base=Area3D, init)]
 pub struct Mine {
     #[init(val=OnReady::from_loaded("res://explosion/rocket_explosion.tscn"))]
     explosion_scene: OnReady<Gd<PackedScene>>,
+    #[init(node="CollisionShape3D")]
+    trigger_shape: OnReady<Gd<CollisionShape3D>>,
+    // Only lit once it's armed
+    #[init(node="Light")]
+    light: OnReady<Gd<Node3D>>,
+    // Seconds after being placed before it can go off
+    #[export]
+    #[init(val=1.0)]
+    arming_time: f64,
+    // How close a player has to come to set it off
+    #[export]
+    #[init(val=0.4)]
+    trigger_radius: f32,
+    // The player who placed it, who can walk over it safely.
+    // Zero for the arena's own mines.
+    #[export]
+    owner_id: i64,
+    // Seconds until a spent mine comes back, zero for never
+    #[export]
+    respawn_delay: f64,
+    state: MineState,
+    // Host only: seconds left arming, or until coming back
+    countdown: f64,
+    // Host only: seconds until a blast that reached it sets it off
+    fuse: Option<f64>,
+    // Put down by a player, and gone for good once it goes off
+    disposable: bool,
     base: Base<Area3D>,
 }
 
@@ -104,30 +365,235 @@ impl IArea3D for Mine {
     fn ready(&mut self) {
         self.signals()
             .area_entered()
-            .connect_self(Self::on_stomped);
+            .connect_self(Self::on_area_entered);
+        self.base_mut().add_to_group(HAZARDS_GROUP);
+
+        // Each mine needs its own shape to resize
+        let shape = self.trigger_shape
+            .get_shape()
+            .and_then(|shape| shape.duplicate())
+            .and_then(|shape| shape.try_cast::<CylinderShape3D>().ok());
+        if let Some(mut shape) = shape {
+            shape.set_radius(self.trigger_radius);
+            self.trigger_shape.set_shape(&shape);
+        }
+
+        // Hard to spot unless it's one of ours. Everyone at the owner's
+        // machine shares a screen, so there's no hiding it from them.
+        let own_peer = self.base().get_multiplayer().unwrap().get_unique_id() as i64;
+        if self.owner_id != 0 && peer_of(self.owner_id) != own_peer {
+            for child in self.base().get_children().iter_shared() {
+                if let Ok(mut mesh) = child.try_cast::<MeshInstance3D>() {
+                    mesh.set_transparency(ENEMY_MINE_TRANSPARENCY);
+                }
+            }
+        }
+
+        self.countdown = self.arming_time;
+        self.show_state();
+        // The match might have been going a while already
+        if !self.base().is_multiplayer_authority() {
+            let host = self.base().get_multiplayer_authority() as i64;
+            self.base_mut().rpc_id(host, "request_state", &[]);
+        }
+    }
+
+    fn process(&mut self, delta: f64) {
+        if !self.base().is_multiplayer_authority() {
+            return;
+        }
+        if let Some(fuse) = self.fuse.as_mut() {
+            *fuse -= delta;
+            if *fuse <= 0.0 {
+                self.fuse = None;
+                self.base_mut().rpc("explode", &[]);
+                return;
+            }
+        }
+        match self.state {
+            MineState::Arming => {
+                self.countdown -= delta;
+                if self.countdown <= 0.0 {
+                    self.base_mut().rpc("set_state", vslice![MineState::Armed]);
+                    // Anyone already standing on it doesn't get away
+                    let overlapping = self.base().get_overlapping_areas();
+                    for area in overlapping.iter_shared() {
+                        self.on_area_entered(area);
+                    }
+                }
+            }
+            MineState::Armed => {}
+            MineState::Spent => {
+                if self.respawn_delay > 0.0 {
+                    self.countdown -= delta;
+                    if self.countdown <= 0.0 {
+                        self.countdown = self.arming_time;
+                        self.base_mut().rpc("set_state", vslice![MineState::Arming]);
+                    }
+                }
+            }
+        }
     }
 }
 
 #[godot_api]
 impl Mine {
     #[func]
-    fn on_stomped(&mut self, area: Gd<Area3D>) {
-        if self.base().is_multiplayer_authority() {
-            if let Ok(_player) = area.try_cast::<Player>() {
-                let args = &[];
-                self.base_mut().rpc("explode", args);
+    fn on_area_entered(&mut self, area: Gd<Area3D>) {
+        if !self.base().is_multiplayer_authority() || self.state == MineState::Spent {
+            return;
+        }
+        match area.try_cast::<Player>() {
+            Ok(player) => {
+                if self.state == MineState::Armed
+                        && player.bind().get_player_id() != self.owner_id {
+                    self.base_mut().rpc("explode", &[]);
+                }
+            }
+            // Caught in somebody else's blast, armed or not
+            Err(area) => {
+                if let Ok(explosion) = area.try_cast::<Explosion>()
+                        && explosion.bind().get_time() < 0.2
+                        && self.fuse.is_none() {
+                    self.fuse = Some(CHAIN_DELAY);
+                }
             }
         }
     }
 
-    #[rpc(authority, call_local)]
+    #[rpc(authority, call_local, reliable)]
     pub fn explode(&mut self) {
+        if self.state == MineState::Spent {
+            return;
+        }
         let mut explosion = self.explosion_scene
             .instantiate_as::<Explosion>();
         explosion.set_position(self.base().get_position());
+        explosion.bind_mut().set_owner_id(self.owner_id);
         self.base_mut().add_sibling(&explosion);
-        self.base_mut().queue_free();
+        self.countdown = self.respawn_delay;
+        self.fuse = None;
+        self.state = MineState::Spent;
+        self.show_state();
+        // Every peer frees it at the same point, so the nodes still match
+        if self.disposable {
+            self.base_mut().queue_free();
+        }
+    }
+
+    #[rpc(authority, call_local, reliable)]
+    fn set_state(&mut self, state: MineState) {
+        self.state = state;
+        self.show_state();
+    }
+
+    #[rpc(any_peer, call_remote, reliable)]
+    fn request_state(&mut self) {
+        if !self.base().is_multiplayer_authority() {
+            return;
+        }
+        let peer = self.base().get_multiplayer().unwrap().get_remote_sender_id() as i64;
+        let state = self.state;
+        self.base_mut().rpc_id(peer, "set_state", vslice![state]);
+    }
+
+    fn show_state(&mut self) {
+        let spent = self.state == MineState::Spent;
+        self.base_mut().set_visible(!spent);
+        // Can't be switched off in the middle of a physics callback
+        self.base_mut().set_deferred("monitoring", &(!spent).to_variant());
+        self.light.set_visible(self.state == MineState::Armed);
+    }
+}
+
+// Players' own mines. Owners ask the host to put one down, the host
+// checks they have one left and has every peer lay it.
+#[derive(GodotClass)]
+#[class(base=Node, init)]
+pub struct MineLayer {
+    #[init(val=OnReady::from_loaded("res://explosion/mine.tscn"))]
+    mine_scene: OnReady<Gd<PackedScene>>,
+    #[export]
+    #[init(val=3)]
+    mines_per_life: i64,
+    // Host only: what each player has left this life, if they've used any
+    mines_left: HashMap<i64, i64>,
+    // Host only: names mines the same on every peer
+    laid: i64,
+    base: Base<Node>,
+}
+
+#[godot_api]
+impl INode for MineLayer {
+    fn ready(&mut self) {
+        // The match might have been going a while already
+        if !self.base().is_multiplayer_authority() {
+            let host = self.base().get_multiplayer_authority() as i64;
+            self.base_mut().rpc_id(host, "request_mines", &[]);
+        }
     }
 }
 
+#[godot_api]
+impl MineLayer {
+    // A new life brings a fresh set
+    pub fn refill(&mut self, player_id: i64) {
+        self.mines_left.remove(&player_id);
+    }
 
+    // Called by whichever peer the player belongs to
+    pub fn place(&mut self, player_id: i64, position: Vector3) {
+        let host = self.base().get_multiplayer_authority() as i64;
+        self.base_mut().rpc_id(host, "request_mine", vslice![player_id, position]);
+    }
+
+    #[rpc(any_peer, call_local, reliable)]
+    fn request_mine(&mut self, player_id: i64, position: Vector3) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        // Peers can only speak for their own players
+        if !self.base().is_multiplayer_authority()
+                || peer_of(player_id) != multiplayer.get_remote_sender_id() as i64 {
+            return;
+        }
+        let mines_per_life = self.mines_per_life;
+        let mines_left = self.mines_left.entry(player_id).or_insert(mines_per_life);
+        if *mines_left <= 0 {
+            return;
+        }
+        *mines_left -= 1;
+        self.laid += 1;
+        let name = format!("Mine{}", self.laid);
+        self.base_mut().rpc("lay_mine", vslice![name, player_id, position]);
+    }
+
+    #[rpc(authority, call_local, reliable)]
+    fn lay_mine(&mut self, name: GString, owner_id: i64, position: Vector3) {
+        let mut mine = self.mine_scene.instantiate_as::<Mine>();
+        mine.set_name(name.arg());
+        mine.set_position(position);
+        {
+            let mut mine = mine.bind_mut();
+            mine.owner_id = owner_id;
+            mine.disposable = true;
+        }
+        self.base_mut().add_child(&mine);
+    }
+
+    #[rpc(any_peer, call_remote, reliable)]
+    fn request_mines(&mut self) {
+        if !self.base().is_multiplayer_authority() {
+            return;
+        }
+        let peer = self.base().get_multiplayer().unwrap().get_remote_sender_id() as i64;
+        let mines: Vec<Gd<Mine>> = self.base()
+            .get_children()
+            .iter_shared()
+            .filter_map(|child| child.try_cast::<Mine>().ok())
+            .collect();
+        for mine in mines {
+            let owner_id = mine.bind().owner_id;
+            let args = vslice![mine.get_name().to_string(), owner_id, mine.get_position()];
+            self.base_mut().rpc_id(peer, "lay_mine", args);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use godot::prelude::*;
+use godot::global::MouseButtonMask;
+
+#[allow(unused_imports)]
+use godot::classes::{Camera3D, ICamera3D, Input, InputEvent, InputEventMouseMotion};
+
+// How far up or down the camera can look, in radians
+const PITCH_LIMIT: f32 = 1.5;
+// Holding shift moves this much faster
+const SPRINT_SCALE: f32 = 3.0;
+
+// A camera that flies around on its own. Moves with the movement
+// actions, rises with jump and sinks with ragdoll, and looks around
+// while the right mouse button is held.
+#[derive(GodotClass)]
+#[class(init, base=Camera3D)]
+pub struct FreeCamera {
+    // Meters per second
+    #[export]
+    #[init(val=8.0)]
+    speed: f32,
+    // Radians per pixel of mouse motion
+    #[export]
+    #[init(val=0.005)]
+    look_sensitivity: f32,
+    base: Base<Camera3D>,
+}
+
+#[godot_api]
+impl ICamera3D for FreeCamera {
+    fn process(&mut self, delta: f64) {
+        if !self.base().is_current() {
+            return;
+        }
+        let input = Input::singleton();
+        let movement = input.get_vector("left", "right", "forward", "back");
+        let rise = input.get_action_strength("jump") - input.get_action_strength("ragdoll");
+        let mut speed = self.speed;
+        if input.is_key_pressed(godot::global::Key::SHIFT) {
+            speed *= SPRINT_SCALE;
+        }
+        let direction = self.base().get_basis() * Vector3::new(movement.x, 0.0, movement.y)
+            + Vector3::UP * rise;
+        let position = self.base().get_position()
+            + direction.limit_length(Some(1.0)) * speed * delta as f32;
+        self.base_mut().set_position(position);
+    }
+
+    fn unhandled_input(&mut self, event: Gd<InputEvent>) {
+        if !self.base().is_current() {
+            return;
+        }
+        if let Ok(motion) = event.try_cast::<InputEventMouseMotion>()
+                && motion.get_button_mask().is_set(MouseButtonMask::RIGHT) {
+            let relative = motion.get_relative() * self.look_sensitivity;
+            let mut rotation = self.base().get_rotation();
+            rotation.y -= relative.x;
+            rotation.x = (rotation.x - relative.y).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+            rotation.z = 0.0;
+            self.base_mut().set_rotation(rotation);
+        }
+    }
+}
@@ -1,13 +1,25 @@
 use godot::prelude::*;
 use crate::player_spawner::NPlayers;
 use crate::pause_menu::PauseMenu;
+use crate::match_settings::MatchSettings;
+use crate::profile::LifetimeStats;
+use crate::replay::{Replay, ReplayRecorder};
+use crate::replay_viewer::ReplayViewer;
+use crate::spectator::Spectator;
+use crate::arena::Arena;
+use crate::terrain::DestructibleTerrain;
+use crate::explosion::MineLayer;
+
+use crate::settings::{Settings, SettingsSection, AudioSettings};
 
 #[allow(unused_imports)]
 use godot::classes::{
     Node, INode,
     Node3D, INode3D,
-    ConfigFile,
+    Timer, AudioStreamPlayer, Control, Input, GridMap,
 };
+use godot::classes::input::MouseMode;
+use godot::global::linear_to_db;
 
 
 #[derive(GodotClass)]
@@ -17,76 +29,196 @@ pub struct Game {
     player_spawner: OnEditor<Gd<NPlayers>>,
     #[export]
     pause_menu: OnEditor<Gd<PauseMenu>>,
-    #[init(val=0.7)]
-    global_mouse_sensitivity: f64,
     #[export]
-    #[init(val=GString::from("user://settings.cfg"))]
-    settings_config_name: GString,
+    music_player: OnEditor<Gd<AudioStreamPlayer>>,
+    #[export]
+    spectator: OnEditor<Gd<Spectator>>,
+    #[export]
+    hud: OnEditor<Gd<Control>>,
+    #[export]
+    mine_layer: OnEditor<Gd<MineLayer>>,
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
+    #[init(val=OnReady::from_loaded("res://replay/replay_viewer.tscn"))]
+    replay_viewer_scene: OnReady<Gd<PackedScene>>,
+    // Set when we're watching a replay instead of playing
+    replay: Option<Replay>,
+    // Joined to watch, with no players of our own
+    spectator_only: bool,
+    // Loaded from the match settings on entering the tree
+    arena: Option<Arena>,
+    music_base_volume_db: f32,
+    match_settings: MatchSettings,
+    // Only counts the local player, for the profile
+    local_knockouts: i64,
+    local_rockets_fired: i64,
     base: Base<Node3D>,
 }
 
 #[godot_api]
 impl INode3D for Game {
+    fn enter_tree(&mut self) {
+        // Before anything else is ready, so it all has somewhere to stand
+        let settings = &self.match_settings;
+        let root = self.arena
+            .get_or_insert_with(|| Arena::instantiate(settings))
+            .root();
+        if root.get_parent().is_none() {
+            self.base_mut().add_child(&root);
+            self.base_mut().move_child(&root, 0);
+        }
+    }
+
     fn ready(&mut self) {
         let gd_ref = self.to_gd();
 
-        let mut config_file = ConfigFile::new_gd();
-        config_file.load(&self.settings_config_name);
-
-        let mut ms: f64 = self.global_mouse_sensitivity;
-        let value: Variant = config_file.get_value_ex(
-            "Controls",
-            "mouse_sensitivity",
-        ).default(&Variant::from(ms)).done();
-        ms = if let Ok(value) = value.try_to::<f64>() {
-            self.global_mouse_sensitivity = value;
-            value
-        } else {
-            self.global_mouse_sensitivity
-        };
-        
-        self.pause_menu.bind_mut().get_sensitivity_slider()
-            .unwrap().set_value(ms);
-
-        self.pause_menu
+        // The music volume setting is relative to the scene's own mix
+        self.music_base_volume_db = self.music_player.get_volume_db();
+        self.apply_music_volume();
+        self.settings
             .signals()
-            .unpause()
-            .builder()
-            .connect_other_mut(&gd_ref, |this| {
-                let mut config_file = ConfigFile::new_gd();
-                config_file.set_value(
-                    "Controls",
-                    "mouse_sensitivity",
-                    &Variant::from(this.global_mouse_sensitivity),
-                );
-                config_file.save(&this.settings_config_name);
+            .section_changed()
+            .connect_other(&gd_ref, |this, section: GString| {
+                if section.to_string() == AudioSettings::NAME {
+                    this.apply_music_volume();
+                }
             });
 
+        // Watchers need the mouse for their controls
         self.pause_menu
-            .bind_mut()
-            .get_sensitivity_slider()
-            .unwrap()
             .signals()
-            .value_changed()
-            .builder()
-            .connect_other_mut(&gd_ref, |this, value: f64| {
-                this.global_mouse_sensitivity = value;
-                this.signals().mouse_sensitivity_changed().emit(value);
+            .unpause()
+            .connect_other(&gd_ref, |this| {
+                if this.is_replay() || this.spectator.bind().is_active() {
+                    Input::singleton().set_mouse_mode(MouseMode::VISIBLE);
+                }
             });
 
-        self.signals().mouse_sensitivity_changed().emit(ms);
+        if let Some(replay) = self.replay.clone() {
+            self.start_replay(replay);
+            return;
+        }
 
+        // Not in replays, they can be wound back to before anything broke
+        if self.match_settings.destructible_terrain
+                && let Some(arena) = &self.arena
+                && let Ok(grid) = arena.root().try_cast::<GridMap>() {
+            let mut terrain = DestructibleTerrain::new_alloc();
+            terrain.set_name("Terrain");
+            terrain.bind_mut().set_grid(grid);
+            self.base_mut().add_child(&terrain);
+        }
+
+        if self.settings.bind().replays().record {
+            let mut recorder = ReplayRecorder::new_alloc();
+            recorder.bind_mut().set_match_settings(self.match_settings.clone());
+            self.base_mut().add_child(&recorder);
+        }
+
+        // The host keeps the clock
+        let time_limit = self.match_settings.time_limit;
+        if self.base().is_multiplayer_authority() && time_limit > 0 {
+            let mut timer = Timer::new_alloc();
+            timer.set_one_shot(true);
+            timer.set_wait_time(time_limit as f64 * 60.0);
+            timer.signals()
+                .timeout()
+                .connect_other(&gd_ref, |this| {
+                    this.signals().match_over().emit();
+                });
+            self.base_mut().add_child(&timer);
+            timer.start();
+        }
+
+        if self.spectator_only {
+            self.start_spectating();
+        }
     }
 }
 
 #[godot_api]
 impl Game {
     // Called only by the host
-    pub fn initialize_authority(&mut self, player_info: &VarDictionary) {
-        self.player_spawner.bind_mut().initialize_authority(player_info);
+    pub fn initialize_authority(&mut self, roster: &VarDictionary) {
+        self.player_spawner.bind_mut().initialize_authority(roster);
+    }
+
+    // Must be called before the game enters the tree
+    pub fn set_match_settings(&mut self, settings: MatchSettings) {
+        self.match_settings = settings;
     }
 
+    pub fn get_match_settings(&self) -> &MatchSettings {
+        &self.match_settings
+    }
+
+    // Must be called before the game enters the tree. Plays there
+    // instead of the arena the match settings pick.
+    pub fn set_arena(&mut self, arena: Arena) {
+        self.arena = Some(arena);
+    }
+
+    // Only None before the game enters the tree
+    pub fn get_arena(&self) -> Option<&Arena> {
+        self.arena.as_ref()
+    }
+
+    // Must be called before the game enters the tree, instead of
+    // initialize_authority. Nobody plays, the replay is shown instead.
+    pub fn set_replay(&mut self, replay: Replay) {
+        self.match_settings = replay.settings.clone();
+        self.replay = Some(replay);
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    fn start_replay(&mut self, replay: Replay) {
+        let mut viewer = self.replay_viewer_scene.instantiate_as::<ReplayViewer>();
+        viewer.bind_mut().set_replay(replay);
+        self.base_mut().add_child(&viewer);
+        self.hud.set_visible(false);
+        Input::singleton().set_mouse_mode(MouseMode::VISIBLE);
+    }
+
+    // Must be called before the game enters the tree
+    pub fn set_spectator_only(&mut self, spectator_only: bool) {
+        self.spectator_only = spectator_only;
+    }
+
+    // Swap our own view for the spectator cameras, for good
+    pub fn start_spectating(&mut self) {
+        self.hud.set_visible(false);
+        self.spectator.bind_mut().start();
+        Input::singleton().set_mouse_mode(MouseMode::VISIBLE);
+    }
+
+    pub fn record_knockout(&mut self) {
+        self.local_knockouts += 1;
+    }
+
+    pub fn record_rocket_fired(&mut self) {
+        self.local_rockets_fired += 1;
+    }
+
+    pub fn get_local_stats(&self) -> LifetimeStats {
+        LifetimeStats {
+            // Watching isn't playing a match
+            matches_played: if self.is_replay() || self.spectator_only { 0 } else { 1 },
+            knockouts: self.local_knockouts,
+            rockets_fired: self.local_rockets_fired,
+        }
+    }
+
+    // Emitted on the host when a time or knockout limit is reached
     #[signal]
-    pub fn mouse_sensitivity_changed(value: f64);
+    pub fn match_over();
+
+    fn apply_music_volume(&mut self) {
+        let volume = self.settings.bind().audio().music_volume;
+        let volume_db = self.music_base_volume_db + linear_to_db(volume) as f32;
+        self.music_player.set_volume_db(volume_db);
+    }
 }
 
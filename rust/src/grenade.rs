@@ -0,0 +1,102 @@
+use godot::prelude::*;
+use godot::classes::node::ProcessMode;
+
+#[allow(unused_imports)]
+use godot::classes::{RigidBody3D, IRigidBody3D, MeshInstance3D, StandardMaterial3D};
+
+use crate::explosion::Explosion;
+use crate::player::{PlayerKinematicBody, PlayerDynamicBody};
+use crate::rocket::{RocketFlight, PROJECTILES_GROUP, body_gravity};
+
+// Bounces around until its fuse runs out, or until it hits a player
+#[derive(GodotClass)]
+#[class(base=RigidBody3D, init)]
+pub struct Grenade {
+    #[init(val=OnReady::from_loaded("res://explosion/rocket_explosion.tscn"))]
+    explosion_scene: OnReady<Gd<PackedScene>>,
+    #[init(node="Mesh")]
+    mesh: OnReady<Gd<MeshInstance3D>>,
+    // Color of whoever threw it
+    #[var]
+    #[init(val=Color::WHITE)]
+    color: Color,
+    // The player who threw it
+    #[var]
+    owner_id: i64,
+    // Seconds from launch to going off
+    #[export]
+    #[init(val=2.0)]
+    fuse: f32,
+    #[export]
+    #[init(val=1.0)]
+    explosion_scale: f32,
+    base: Base<RigidBody3D>
+}
+
+#[godot_api]
+impl IRigidBody3D for Grenade {
+    fn ready(&mut self) {
+        self.signals()
+            .body_entered()
+            .connect_self(Self::on_body_entered);
+
+        // Each grenade needs its own copy to tint
+        let material = self.mesh
+            .get_active_material(0)
+            .and_then(|material| material.duplicate())
+            .and_then(|material| material.try_cast::<StandardMaterial3D>().ok());
+        if let Some(mut material) = material {
+            material.set_albedo(self.color);
+            self.mesh.set_material_override(&material);
+        }
+        self.base_mut().add_to_group(PROJECTILES_GROUP);
+    }
+
+    fn physics_process(&mut self, delta: f32) {
+        self.fuse -= delta;
+        if self.fuse < 0.0 {
+            let pos = self.base().get_position();
+            self.explode(pos);
+        }
+    }
+}
+
+#[godot_api]
+impl Grenade {
+    pub fn flight(&self) -> RocketFlight {
+        RocketFlight::ballistic(body_gravity(&self.base()), self.fuse)
+    }
+
+    // Stop flying, so a replay can move this one around
+    pub fn make_puppet(&mut self) {
+        self.base_mut().set_process_mode(ProcessMode::DISABLED);
+        self.base_mut().remove_from_group(PROJECTILES_GROUP);
+    }
+
+    // The arena only bounces it, players set it off
+    #[func]
+    fn on_body_entered(&mut self, body: Gd<Node>) {
+        if body.clone().try_cast::<PlayerKinematicBody>().is_ok()
+                || body.try_cast::<PlayerDynamicBody>().is_ok() {
+            let pos = self.base().get_position();
+            self.base_mut().rpc("explode", vslice![pos]);
+        }
+    }
+
+    #[rpc(any_peer, call_local, unreliable)]
+    pub fn explode(&mut self, position: Vector3) {
+        // Already went off
+        if !self.base().is_physics_processing() {
+            return;
+        }
+        let mut explosion = self.explosion_scene
+            .instantiate_as::<Explosion>();
+        explosion.set_position(position);
+        explosion.set_scale(Vector3::ONE * self.explosion_scale);
+        explosion.bind_mut().set_owner_id(self.owner_id);
+        self.base_mut().add_sibling(&explosion);
+        // Stops the fuse going off again before it's gone
+        self.base_mut().set_physics_process(false);
+        self.base_mut().queue_free();
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::BTreeMap;
+
+use godot::prelude::*;
+use godot::classes::{
+    ConfigFile, Input, InputEvent, InputMap, Os,
+    InputEventKey, InputEventMouseButton, InputEventJoypadButton, InputEventJoypadMotion,
+};
+use godot::global::{Key, MouseButton, JoyButton, JoyAxis};
+use godot::obj::EngineEnum;
+
+use crate::settings::{SettingsSection, read_value};
+
+// Actions the player may rebind, with the names shown for them
+pub const REBINDABLE_ACTIONS: [(&str, &str); 14] = [
+    ("forward", "Forward"),
+    ("back", "Back"),
+    ("left", "Left"),
+    ("right", "Right"),
+    ("jump", "Jump"),
+    ("shoot", "Shoot"),
+    ("secondary_fire", "Place Mine"),
+    ("switch_weapon", "Switch Weapon"),
+    ("ragdoll", "Ragdoll"),
+    ("pause", "Pause"),
+    ("look_left", "Look Left"),
+    ("look_right", "Look Right"),
+    ("look_up", "Look Up"),
+    ("look_down", "Look Down"),
+];
+
+// Devices a player can be given. Gamepads are their joypad index,
+// and a lone local player just listens to everything.
+pub const ANY_DEVICE: i64 = -2;
+pub const KEYBOARD_MOUSE: i64 = -1;
+// Nobody at the controls, for target dummies
+pub const NO_DEVICE: i64 = -3;
+// Driven by a bot on the host
+pub const BOT_DEVICE: i64 = -4;
+
+// How far a stick or trigger has to move to count as a binding
+const AXIS_BIND_THRESHOLD: f32 = 0.5;
+
+// A single physical input. Stored in settings.cfg as short strings
+// like "key:87", "mouse:1", "joy_button:0" or "joy_axis:5:+".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Key(i32),
+    MouseButton(i32),
+    JoyButton(i32),
+    JoyAxis { axis: i32, positive: bool },
+}
+
+impl Binding {
+    pub fn from_event(event: &Gd<InputEvent>) -> Option<Self> {
+        let event = event.clone();
+        let event = match event.try_cast::<InputEventKey>() {
+            Ok(e) => return Some(Binding::Key(e.get_physical_keycode().ord())),
+            Err(event) => event,
+        };
+        let event = match event.try_cast::<InputEventMouseButton>() {
+            Ok(e) => return Some(Binding::MouseButton(e.get_button_index().ord())),
+            Err(event) => event,
+        };
+        let event = match event.try_cast::<InputEventJoypadButton>() {
+            Ok(e) => return Some(Binding::JoyButton(e.get_button_index().ord())),
+            Err(event) => event,
+        };
+        match event.try_cast::<InputEventJoypadMotion>() {
+            Ok(e) => Some(Binding::JoyAxis {
+                axis: e.get_axis().ord(),
+                positive: e.get_axis_value() >= 0.0,
+            }),
+            Err(_) => None,
+        }
+    }
+
+    // Like `from_event`, but only for a deliberate press,
+    // so releases, key repeats and small stick drift are ignored
+    pub fn from_pressed_event(event: &Gd<InputEvent>) -> Option<Self> {
+        if let Ok(motion) = event.clone().try_cast::<InputEventJoypadMotion>() {
+            if motion.get_axis_value().abs() < AXIS_BIND_THRESHOLD {
+                return None;
+            }
+        } else if !event.is_pressed() || event.is_echo() {
+            return None;
+        }
+        Self::from_event(event)
+    }
+
+    pub fn to_event(self) -> Gd<InputEvent> {
+        let mut event: Gd<InputEvent> = match self {
+            Binding::Key(code) => {
+                let mut e = InputEventKey::new_gd();
+                e.set_physical_keycode(Key::from_ord(code));
+                e.upcast()
+            }
+            Binding::MouseButton(index) => {
+                let mut e = InputEventMouseButton::new_gd();
+                e.set_button_index(MouseButton::from_ord(index));
+                e.upcast()
+            }
+            Binding::JoyButton(index) => {
+                let mut e = InputEventJoypadButton::new_gd();
+                e.set_button_index(JoyButton::from_ord(index));
+                e.upcast()
+            }
+            Binding::JoyAxis { axis, positive } => {
+                let mut e = InputEventJoypadMotion::new_gd();
+                e.set_axis(JoyAxis::from_ord(axis));
+                e.set_axis_value(if positive { 1.0 } else { -1.0 });
+                e.upcast()
+            }
+        };
+        // Any device, like the ones in project.godot
+        event.set_device(-1);
+        event
+    }
+
+    pub fn label(self) -> String {
+        match self {
+            Binding::Key(code) => Os::singleton()
+                .get_keycode_string(Key::from_ord(code))
+                .to_string(),
+            Binding::MouseButton(index) => match index {
+                1 => "Left Click".to_string(),
+                2 => "Right Click".to_string(),
+                3 => "Middle Click".to_string(),
+                4 => "Wheel Up".to_string(),
+                5 => "Wheel Down".to_string(),
+                _ => format!("Mouse {index}"),
+            },
+            Binding::JoyButton(index) => match index {
+                0 => "Pad A".to_string(),
+                1 => "Pad B".to_string(),
+                2 => "Pad X".to_string(),
+                3 => "Pad Y".to_string(),
+                4 => "Pad Back".to_string(),
+                6 => "Pad Start".to_string(),
+                7 => "Left Stick Click".to_string(),
+                8 => "Right Stick Click".to_string(),
+                9 => "Left Bumper".to_string(),
+                10 => "Right Bumper".to_string(),
+                11 => "D-Pad Up".to_string(),
+                12 => "D-Pad Down".to_string(),
+                13 => "D-Pad Left".to_string(),
+                14 => "D-Pad Right".to_string(),
+                _ => format!("Pad Button {index}"),
+            },
+            Binding::JoyAxis { axis, positive } => match (axis, positive) {
+                (0, false) => "Left Stick Left".to_string(),
+                (0, true) => "Left Stick Right".to_string(),
+                (1, false) => "Left Stick Up".to_string(),
+                (1, true) => "Left Stick Down".to_string(),
+                (2, false) => "Right Stick Left".to_string(),
+                (2, true) => "Right Stick Right".to_string(),
+                (3, false) => "Right Stick Up".to_string(),
+                (3, true) => "Right Stick Down".to_string(),
+                (4, _) => "Left Trigger".to_string(),
+                (5, _) => "Right Trigger".to_string(),
+                _ => format!("Pad Axis {axis}{}", if positive { "+" } else { "-" }),
+            },
+        }
+    }
+
+    pub fn encode(self) -> String {
+        match self {
+            Binding::Key(code) => format!("key:{code}"),
+            Binding::MouseButton(index) => format!("mouse:{index}"),
+            Binding::JoyButton(index) => format!("joy_button:{index}"),
+            Binding::JoyAxis { axis, positive } => {
+                format!("joy_axis:{axis}:{}", if positive { "+" } else { "-" })
+            }
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        let mut parts = text.split(':');
+        let kind = parts.next()?;
+        let value: i32 = parts.next()?.parse().ok()?;
+        let binding = match kind {
+            "key" => Binding::Key(value),
+            "mouse" => Binding::MouseButton(value),
+            "joy_button" => Binding::JoyButton(value),
+            "joy_axis" => Binding::JoyAxis {
+                axis: value,
+                positive: match parts.next()? {
+                    "+" => true,
+                    "-" => false,
+                    _ => return None,
+                },
+            },
+            _ => return None,
+        };
+        (parts.next().is_none() && binding.is_known()).then_some(binding)
+    }
+
+    // Godot panics turning an unknown pad button or axis into an event
+    fn is_known(self) -> bool {
+        match self {
+            Binding::Key(_) | Binding::MouseButton(_) => true,
+            Binding::JoyButton(index) => JoyButton::try_from_ord(index).is_some(),
+            Binding::JoyAxis { axis, .. } => JoyAxis::try_from_ord(axis).is_some(),
+        }
+    }
+}
+
+// The bindings currently in the InputMap for an action
+pub fn action_bindings(action: &str) -> Vec<Binding> {
+    InputMap::singleton()
+        .action_get_events(action)
+        .iter_shared()
+        .filter_map(|event| Binding::from_event(&event))
+        .collect()
+}
+
+pub fn device_label(device: i64) -> String {
+    match device {
+        ANY_DEVICE => "Any Device".to_string(),
+        NO_DEVICE => "None".to_string(),
+        BOT_DEVICE => "Computer".to_string(),
+        KEYBOARD_MOUSE => "Keyboard & Mouse".to_string(),
+        pad => format!("Gamepad {}", pad + 1),
+    }
+}
+
+// Keyboards and mice all count as one device
+pub fn event_device(event: &Gd<InputEvent>) -> i64 {
+    if event.clone().try_cast::<InputEventJoypadButton>().is_ok()
+            || event.clone().try_cast::<InputEventJoypadMotion>().is_ok() {
+        event.get_device() as i64
+    } else {
+        KEYBOARD_MOUSE
+    }
+}
+
+pub fn is_event_from_device(event: &Gd<InputEvent>, device: i64) -> bool {
+    device == ANY_DEVICE || event_device(event) == device
+}
+
+// Like `Input::get_action_raw_strength`, but only listening to one device
+pub fn raw_action_strength(action: &str, device: i64) -> f32 {
+    let input = Input::singleton();
+    if device == ANY_DEVICE {
+        return input.get_action_raw_strength(action);
+    }
+    action_bindings(action)
+        .into_iter()
+        .map(|binding| match binding {
+            Binding::Key(code) if device == KEYBOARD_MOUSE
+                && input.is_physical_key_pressed(Key::from_ord(code)) => 1.0,
+            Binding::MouseButton(index) if device == KEYBOARD_MOUSE
+                && input.is_mouse_button_pressed(MouseButton::from_ord(index)) => 1.0,
+            Binding::JoyButton(index) if device >= 0
+                && input.is_joy_button_pressed(device as i32, JoyButton::from_ord(index)) => 1.0,
+            Binding::JoyAxis { axis, positive } if device >= 0 => {
+                let value = input.get_joy_axis(device as i32, JoyAxis::from_ord(axis));
+                if positive { value.max(0.0) } else { (-value).max(0.0) }
+            }
+            _ => 0.0,
+        })
+        .fold(0.0, f32::max)
+}
+
+pub fn is_action_pressed(action: &str, device: i64) -> bool {
+    if device == ANY_DEVICE {
+        return Input::singleton().is_action_pressed(action);
+    }
+    raw_action_strength(action, device) > InputMap::singleton().action_get_deadzone(action)
+}
+
+// Like `Input::get_vector`, but only listening to one device
+pub fn device_vector(
+    negative_x: &str,
+    positive_x: &str,
+    negative_y: &str,
+    positive_y: &str,
+    device: i64,
+    deadzone: f32,
+) -> Vector2 {
+    if device == ANY_DEVICE {
+        return Input::singleton()
+            .get_vector_ex(negative_x, positive_x, negative_y, positive_y)
+            .deadzone(deadzone)
+            .done();
+    }
+    let raw = Vector2::new(
+        raw_action_strength(positive_x, device) - raw_action_strength(negative_x, device),
+        raw_action_strength(positive_y, device) - raw_action_strength(negative_y, device),
+    );
+    // Same radial deadzone and rescale as the engine
+    let length = raw.length();
+    if length <= deadzone {
+        return Vector2::ZERO;
+    }
+    let scaled = ((length - deadzone) / (1.0 - deadzone)).min(1.0);
+    raw / length * scaled
+}
+
+// Only the actions the player changed, everything else
+// keeps the defaults from project.godot
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BindingsSettings {
+    pub actions: BTreeMap<String, Vec<Binding>>,
+}
+
+impl BindingsSettings {
+    // Reset the InputMap to the project defaults, then layer our changes on top
+    pub fn apply(&self) {
+        let mut input_map = InputMap::singleton();
+        input_map.load_from_project_settings();
+        for (action, bindings) in &self.actions {
+            input_map.action_erase_events(action.as_str());
+            for binding in bindings {
+                input_map.action_add_event(action.as_str(), &binding.to_event());
+            }
+        }
+    }
+}
+
+impl SettingsSection for BindingsSettings {
+    const NAME: &'static str = "Bindings";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let mut actions = BTreeMap::new();
+        for (action, _) in REBINDABLE_ACTIONS {
+            if !config.has_section_key(Self::NAME, action) {
+                continue;
+            }
+            let encoded: PackedStringArray =
+                read_value(config, Self::NAME, action, PackedStringArray::new());
+            let bindings = encoded
+                .as_slice()
+                .iter()
+                .filter_map(|text| Binding::decode(&text.to_string()))
+                .collect();
+            actions.insert(action.to_string(), bindings);
+        }
+        Self { actions }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        // Start clean so restored defaults don't linger in the file
+        if config.has_section(Self::NAME) {
+            config.erase_section(Self::NAME);
+        }
+        for (action, bindings) in &self.actions {
+            let encoded: Vec<GString> = bindings
+                .iter()
+                .map(|binding| GString::from(&binding.encode()))
+                .collect();
+            config.set_value(
+                Self::NAME,
+                action.as_str(),
+                &PackedStringArray::from(encoded.as_slice()).to_variant(),
+            );
+        }
+    }
+
+    fn validated(mut self) -> Self {
+        self.actions
+            .retain(|action, _| REBINDABLE_ACTIONS.iter().any(|(a, _)| a == action));
+        for bindings in self.actions.values_mut() {
+            let mut unique = Vec::new();
+            for binding in bindings.drain(..) {
+                if binding.is_known() && !unique.contains(&binding) {
+                    unique.push(binding);
+                }
+            }
+            *bindings = unique;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let bindings = [
+            Binding::Key(87),
+            Binding::MouseButton(1),
+            Binding::JoyButton(0),
+            Binding::JoyAxis { axis: 5, positive: true },
+            Binding::JoyAxis { axis: 1, positive: false },
+        ];
+        for binding in bindings {
+            assert_eq!(Binding::decode(&binding.encode()), Some(binding));
+        }
+    }
+
+    #[test]
+    fn malformed_strings_decode_to_none() {
+        let malformed = [
+            "", "key", "key:", "key:w", "key:87:1", "mouse:1:+", "pad:0",
+            "joy_axis:5", "joy_axis:5:*", "joy_axis:5:+:+", "joy_button:0:+",
+        ];
+        for text in malformed {
+            assert_eq!(Binding::decode(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn unknown_pad_ordinals_decode_to_none() {
+        for text in ["joy_button:99", "joy_button:-5", "joy_axis:9:+", "joy_axis:99:-"] {
+            assert_eq!(Binding::decode(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn validated_drops_unknown_pad_bindings() {
+        let mut actions = BTreeMap::new();
+        actions.insert("shoot".to_string(), vec![
+            Binding::JoyButton(99),
+            Binding::JoyAxis { axis: 5, positive: true },
+            Binding::JoyAxis { axis: 42, positive: true },
+            Binding::JoyAxis { axis: 5, positive: true },
+        ]);
+        let settings = BindingsSettings { actions }.validated();
+        assert_eq!(
+            settings.actions["shoot"],
+            vec![Binding::JoyAxis { axis: 5, positive: true }],
+        );
+    }
+}
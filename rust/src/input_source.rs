@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+
+use godot::prelude::*;
+use godot::classes::{
+    Input, InputEvent, InputEventJoypadMotion, InputEventMouseMotion, FileAccess,
+};
+use godot::classes::file_access::ModeFlags;
+use godot::classes::input::MouseMode;
+
+use crate::input_bindings::{
+    ANY_DEVICE, KEYBOARD_MOUSE, NO_DEVICE,
+    is_event_from_device, is_action_pressed, raw_action_strength, device_vector,
+};
+use crate::player::PLAYERS_GROUP;
+use crate::settings::Settings;
+
+// Aim assist kicks in when an enemy is within this angle
+// of the crosshair and no further away than the range
+const AIM_ASSIST_ANGLE: f32 = 0.15;
+const AIM_ASSIST_RANGE: f32 = 40.0;
+// How much of the stick's turn speed is left near an enemy
+const AIM_ASSIST_SLOWDOWN: f32 = 0.4;
+
+// Matches the deadzone on the movement actions in project.godot
+const MOVE_DEADZONE: f32 = 0.2;
+
+// Mouse speed in pixels per second that gets the full acceleration,
+// and the most acceleration can multiply a movement by
+const MOUSE_ACCEL_REFERENCE: f32 = 2400.0;
+const MOUSE_ACCEL_MAX: f32 = 3.0;
+
+// An analog trigger shoots once it's pulled past the first, and
+// has to let go past the second before it can shoot again
+const TRIGGER_PRESS: f32 = 0.5;
+const TRIGGER_RELEASE: f32 = 0.3;
+
+// One physics tick's worth of controls for a player,
+// whoever or whatever is at them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputCommand {
+    // Same axes as the movement actions, x to the right and y backwards
+    pub movement: Vector2,
+    // Yaw and pitch in radians, positive turns right and looks down
+    pub look: Vector2,
+    // Held down
+    pub jump: bool,
+    // The shoot button, for automatic weapons
+    pub firing: bool,
+    // Pressed this tick
+    pub shoot: bool,
+    pub secondary: bool,
+    pub switch_weapon: bool,
+    pub ragdoll: bool,
+}
+
+impl InputCommand {
+    pub fn to_dict(self) -> VarDictionary {
+        vdict! {
+            "movement": self.movement,
+            "look": self.look,
+            "jump": self.jump,
+            "firing": self.firing,
+            "shoot": self.shoot,
+            "secondary": self.secondary,
+            "switch_weapon": self.switch_weapon,
+            "ragdoll": self.ragdoll,
+        }
+    }
+
+    // Missing keys mean nothing was pressed
+    pub fn from_dict(dict: &VarDictionary) -> Self {
+        let vector = |key: &str| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<Vector2>().ok())
+                .unwrap_or_default()
+        };
+        let flag = |key: &str| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<bool>().ok())
+                .unwrap_or_default()
+        };
+        Self {
+            movement: vector("movement").limit_length(Some(1.0)),
+            look: vector("look"),
+            jump: flag("jump"),
+            firing: flag("firing"),
+            shoot: flag("shoot"),
+            secondary: flag("secondary"),
+            switch_weapon: flag("switch_weapon"),
+            ragdoll: flag("ragdoll"),
+        }
+    }
+}
+
+// What a source gets to know about its own player each tick
+pub struct PlayerView {
+    pub own_id: InstanceId,
+    pub position: Vector3,
+    pub velocity: Vector3,
+    // Of the body, which only ever yaws
+    pub basis: Basis,
+    pub eye: Vector3,
+    // Where the camera points
+    pub aim: Vector3,
+    pub muzzle: Vector3,
+    pub on_floor: bool,
+    pub loaded: bool,
+    // Our own bodies, for anything casting rays
+    pub exclude: Array<Rid>,
+}
+
+// Anything that can drive a player: people, bots, the network, a file
+pub trait InputSource {
+    // Raw events as they come in, for what can't just be polled
+    fn handle_event(&mut self, _event: &Gd<InputEvent>) {}
+
+    // Commands handed in from outside. Only queued sources keep them.
+    fn queue(&mut self, _command: InputCommand) {}
+
+    // Called once every physics tick
+    fn poll(&mut self, view: &PlayerView, world: &Gd<Node3D>, delta: f32) -> InputCommand;
+}
+
+// Someone at this machine on one device. Goes quiet in the pause menu.
+pub struct LocalSource {
+    device: i64,
+    settings: Gd<Settings>,
+    base_mouse_sensitivity: f32,
+    // Mouse motion and presses since the last tick
+    mouse_motion: Vector2,
+    shoot: bool,
+    secondary: bool,
+    switch_weapon: bool,
+    ragdoll: bool,
+    // Shoot is pulled past TRIGGER_PRESS and not yet let go
+    trigger_held: bool,
+}
+
+impl LocalSource {
+    pub fn new(device: i64, settings: Gd<Settings>, base_mouse_sensitivity: f32) -> Self {
+        Self {
+            device,
+            settings,
+            base_mouse_sensitivity,
+            mouse_motion: Vector2::ZERO,
+            shoot: false,
+            secondary: false,
+            switch_weapon: false,
+            ragdoll: false,
+            trigger_held: false,
+        }
+    }
+
+    // Mouse look for one tick. Accelerated by how fast it moved over
+    // the whole tick, so it doesn't matter how often the mouse reports.
+    fn mouse_look(&mut self, delta: f32) -> Vector2 {
+        let relative = std::mem::take(&mut self.mouse_motion);
+        if relative == Vector2::ZERO || delta <= 0.0 {
+            return Vector2::ZERO;
+        }
+        let controls = self.settings.bind().controls().clone();
+        let speed = relative.length() / delta;
+        let acceleration = (1.0 + controls.mouse_acceleration as f32
+            * speed / MOUSE_ACCEL_REFERENCE).min(MOUSE_ACCEL_MAX);
+        let base = self.base_mouse_sensitivity * acceleration;
+        Vector2::new(
+            relative.x * base * controls.horizontal_sensitivity as f32,
+            relative.y * base * controls.vertical_sensitivity as f32,
+        )
+    }
+
+    // Right stick look, scaled for one tick
+    fn stick_look(&self, view: &PlayerView, world: &Gd<Node3D>, delta: f32) -> Vector2 {
+        let controls = self.settings.bind().controls().clone();
+        let stick = device_vector(
+            "look_left", "look_right", "look_up", "look_down",
+            self.device, controls.stick_deadzone as f32);
+        // Already rescaled past the deadzone, so this runs from 0 to 1
+        let deflection = stick.length();
+        if deflection == 0.0 {
+            return Vector2::ZERO;
+        }
+        let mut speed = deflection.powf(controls.stick_curve as f32)
+            * controls.stick_sensitivity as f32;
+        if controls.aim_assist && enemy_near_crosshair(view, world) {
+            speed *= AIM_ASSIST_SLOWDOWN;
+        }
+        stick / deflection * speed * delta
+    }
+}
+
+impl InputSource for LocalSource {
+    fn handle_event(&mut self, event: &Gd<InputEvent>) {
+        if !playing() || !is_event_from_device(event, self.device) {
+            return;
+        }
+        if event.is_action_pressed("ragdoll") {
+            self.ragdoll = true;
+        }
+        // Triggers are left to `poll`, a button can be pressed and let go
+        // between two ticks so it's caught here
+        if event.is_action_pressed("shoot")
+                && event.clone().try_cast::<InputEventJoypadMotion>().is_err() {
+            self.shoot = true;
+        }
+        if event.is_action_pressed("secondary_fire") {
+            self.secondary = true;
+        }
+        if event.is_action_pressed("switch_weapon") {
+            self.switch_weapon = true;
+        }
+        if (self.device == ANY_DEVICE || self.device == KEYBOARD_MOUSE)
+                && let Ok(motion) = event.clone().try_cast::<InputEventMouseMotion>() {
+            self.mouse_motion += motion.get_relative();
+        }
+    }
+
+    fn poll(&mut self, view: &PlayerView, world: &Gd<Node3D>, delta: f32) -> InputCommand {
+        let mut command = InputCommand {
+            look: self.mouse_look(delta),
+            shoot: std::mem::take(&mut self.shoot),
+            secondary: std::mem::take(&mut self.secondary),
+            switch_weapon: std::mem::take(&mut self.switch_weapon),
+            ragdoll: std::mem::take(&mut self.ragdoll),
+            ..Default::default()
+        };
+        if !playing() {
+            return InputCommand::default();
+        }
+        command.movement = device_vector(
+            "left", "right", "forward", "back", self.device, MOVE_DEADZONE);
+        command.jump = is_action_pressed("jump", self.device);
+        let trigger = raw_action_strength("shoot", self.device);
+        if self.trigger_held {
+            self.trigger_held = trigger > TRIGGER_RELEASE;
+        } else if trigger >= TRIGGER_PRESS {
+            self.trigger_held = true;
+            command.shoot = true;
+        }
+        command.firing = self.trigger_held;
+        command.look += self.stick_look(view, world, delta);
+        if self.settings.bind().controls().invert_y {
+            command.look.y = -command.look.y;
+        }
+        command
+    }
+}
+
+// Commands handed in from elsewhere, like a test script.
+// Stands still once it runs dry.
+#[derive(Default)]
+pub struct QueuedSource {
+    commands: VecDeque<InputCommand>,
+}
+
+impl InputSource for QueuedSource {
+    fn queue(&mut self, command: InputCommand) {
+        self.commands.push_back(command);
+    }
+
+    fn poll(&mut self, _view: &PlayerView, _world: &Gd<Node3D>, _delta: f32) -> InputCommand {
+        self.commands.pop_front().unwrap_or_default()
+    }
+}
+
+// Commands another peer sends over for a player this machine runs,
+// one per tick. They come over unreliably, so when one goes missing
+// whatever was being held stays held, but nothing gets pressed again.
+#[derive(Default)]
+pub struct NetworkSource {
+    commands: VecDeque<InputCommand>,
+    last: InputCommand,
+}
+
+impl InputSource for NetworkSource {
+    fn queue(&mut self, command: InputCommand) {
+        self.commands.push_back(command);
+    }
+
+    fn poll(&mut self, _view: &PlayerView, _world: &Gd<Node3D>, _delta: f32) -> InputCommand {
+        if let Some(command) = self.commands.pop_front() {
+            self.last = command;
+            return command;
+        }
+        InputCommand {
+            movement: self.last.movement,
+            jump: self.last.jump,
+            firing: self.last.firing,
+            ..Default::default()
+        }
+    }
+}
+
+// Plays back commands saved by `save_commands`, one per tick
+pub struct RecordedSource {
+    commands: VecDeque<InputCommand>,
+}
+
+impl RecordedSource {
+    pub fn load(path: &GString) -> Option<Self> {
+        let file = FileAccess::open(path, ModeFlags::READ)?;
+        let commands = file
+            .get_var()
+            .try_to::<VarArray>()
+            .ok()?
+            .iter_shared()
+            .filter_map(|command| command.try_to::<VarDictionary>().ok())
+            .map(|command| InputCommand::from_dict(&command))
+            .collect();
+        Some(Self { commands })
+    }
+}
+
+impl InputSource for RecordedSource {
+    fn poll(&mut self, _view: &PlayerView, _world: &Gd<Node3D>, _delta: f32) -> InputCommand {
+        self.commands.pop_front().unwrap_or_default()
+    }
+}
+
+// Stored as an array of command dictionaries
+pub fn save_commands(path: &GString, commands: &[InputCommand]) -> bool {
+    let Some(mut file) = FileAccess::open(path, ModeFlags::WRITE) else {
+        return false;
+    };
+    let commands: VarArray = commands
+        .iter()
+        .map(|command| command.to_dict().to_variant())
+        .collect();
+    file.store_var(&commands.to_variant())
+}
+
+// The source a device gets by default
+pub fn device_source(
+    device: i64,
+    settings: Gd<Settings>,
+    base_mouse_sensitivity: f32,
+) -> Box<dyn InputSource> {
+    if device == NO_DEVICE {
+        Box::new(QueuedSource::default())
+    } else {
+        Box::new(LocalSource::new(device, settings, base_mouse_sensitivity))
+    }
+}
+
+// Not sitting in a menu
+fn playing() -> bool {
+    Input::singleton().get_mouse_mode() == MouseMode::CAPTURED
+}
+
+fn enemy_near_crosshair(view: &PlayerView, world: &Gd<Node3D>) -> bool {
+    let Some(mut tree) = world.get_tree() else {
+        return false;
+    };
+    tree.get_nodes_in_group(PLAYERS_GROUP)
+        .iter_shared()
+        .filter(|node| node.instance_id() != view.own_id)
+        .filter_map(|node| node.try_cast::<Node3D>().ok())
+        .any(|player| {
+            let offset = player.get_global_position() - view.eye;
+            offset.length() < AIM_ASSIST_RANGE
+                && view.aim.angle_to(offset) < AIM_ASSIST_ANGLE
+        })
+}
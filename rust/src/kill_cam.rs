@@ -0,0 +1,250 @@
+use std::collections::{HashSet, VecDeque};
+
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{Control, IControl, Camera3D, Engine, InputEvent};
+
+use crate::explosion::EXPLOSIONS_GROUP;
+use crate::game::Game;
+use crate::player::{Player, PLAYERS_GROUP};
+use crate::replay::{ReplayFrame, ReplayStage, fill_roster};
+use crate::rocket::PROJECTILES_GROUP;
+use crate::settings::Settings;
+
+// Seconds of the match kept around in case someone gets knocked out
+const KILL_CAM_BUFFER_SECONDS: f64 = 4.0;
+// How much of the rocket's flight is shown before the blast
+const KILL_CAM_LEAD_SECONDS: f64 = 1.5;
+// Lingers on the last frame this long before handing back
+const KILL_CAM_HOLD_SECONDS: f64 = 0.75;
+// A blast this close to the victim counts as the one that did it
+const KNOCKOUT_BLAST_RADIUS: f32 = 4.0;
+
+// Replays the last few seconds after a knockout, from a camera watching
+// the rocket come in and the blast that sent the victim flying.
+// Only ever looks at what this machine saw.
+#[derive(GodotClass)]
+#[class(init, base=Control)]
+pub struct KillCam {
+    #[export]
+    camera: OnEditor<Gd<Camera3D>>,
+    #[init(node="/root/Game")]
+    game_root: OnReady<Gd<Game>>,
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
+    // Whether the match allows it at all
+    enabled: bool,
+    #[init(val=60.0)]
+    tick_rate: f64,
+    // The last few seconds, oldest first
+    recent: VecDeque<ReplayFrame>,
+    seen_explosions: HashSet<InstanceId>,
+    // The rest are only set while it's showing
+    stage: Option<ReplayStage>,
+    frames: Vec<ReplayFrame>,
+    shown_frame: Option<usize>,
+    time: f64,
+    projectile_id: Option<i64>,
+    victim: Option<Gd<Player>>,
+    previous_camera: Option<Gd<Camera3D>>,
+    // Live nodes tucked out of sight, to show again when we're done
+    hidden: Vec<Gd<Node3D>>,
+    base: Base<Control>,
+}
+
+#[godot_api]
+impl IControl for KillCam {
+    fn ready(&mut self) {
+        self.base_mut().set_visible(false);
+        let game = self.game_root.clone();
+        self.enabled = !game.bind().is_replay()
+            && game.bind().get_match_settings().allows_kill_cam();
+        self.tick_rate = Engine::singleton().get_physics_ticks_per_second() as f64;
+    }
+
+    fn physics_process(&mut self, _delta: f64) {
+        if !self.enabled || self.is_showing() {
+            return;
+        }
+        let Some(mut tree) = self.base().get_tree() else {
+            return;
+        };
+        let frame = ReplayFrame::capture(&mut tree, &mut self.seen_explosions);
+        self.recent.push_back(frame);
+        let capacity = (KILL_CAM_BUFFER_SECONDS * self.tick_rate) as usize;
+        while self.recent.len() > capacity {
+            self.recent.pop_front();
+        }
+    }
+
+    fn process(&mut self, delta: f64) {
+        if !self.is_showing() {
+            return;
+        }
+        self.hide_live_world();
+        self.time += delta;
+        let index = (self.time * self.tick_rate) as usize;
+        if index >= self.frames.len() {
+            let end = self.frames.len() as f64 / self.tick_rate + KILL_CAM_HOLD_SECONDS;
+            if self.time >= end {
+                self.finish();
+            }
+            return;
+        }
+        if self.shown_frame == Some(index) {
+            return;
+        }
+        let first = self.shown_frame.map_or(index, |shown| shown + 1);
+        let explosions: Vec<Vector3> = self.frames[first..=index]
+            .iter()
+            .flat_map(|frame| frame.explosions.iter().copied())
+            .collect();
+        let frame = self.frames[index].clone();
+        self.shown_frame = Some(index);
+        if let Some(stage) = self.stage.as_mut() {
+            stage.show_frame(&frame);
+            stage.explode(&explosions);
+        }
+        self.aim_camera(&frame);
+    }
+
+    fn unhandled_input(&mut self, event: Gd<InputEvent>) {
+        if self.is_showing()
+                && (event.is_action_pressed("jump") || event.is_action_pressed("shoot")) {
+            if let Some(mut viewport) = self.base().get_viewport() {
+                viewport.set_input_as_handled();
+            }
+            self.finish();
+        }
+    }
+}
+
+#[godot_api]
+impl KillCam {
+    // Emitted once a kill cam started by `play` is over or skipped
+    #[signal]
+    pub fn finished(player: Gd<Player>);
+
+    pub fn is_showing(&self) -> bool {
+        self.stage.is_some()
+    }
+
+    // Starts showing how `victim` got knocked out. Returns false if
+    // there's nothing to show, or nobody wants to see it.
+    pub fn play(&mut self, victim: Gd<Player>) -> bool {
+        if !self.enabled || self.is_showing() || !self.settings.bind().replays().kill_cam {
+            return false;
+        }
+        let victim_id = victim.bind().get_player_id();
+        let recent: Vec<ReplayFrame> = self.recent.iter().cloned().collect();
+        let Some((blast_index, blast)) = knockout_blast(&recent, victim_id) else {
+            // Fell off on their own
+            return false;
+        };
+        // Whichever rocket or grenade was nearest the blast just before it went off
+        self.projectile_id = blast_index
+            .checked_sub(1)
+            .and_then(|index| recent[index].projectiles()
+                .min_by(|(_, a), (_, b)| {
+                    a.position.distance_to(blast).total_cmp(&b.position.distance_to(blast))
+                })
+                .map(|(id, _)| *id));
+        let lead = (KILL_CAM_LEAD_SECONDS * self.tick_rate) as usize;
+        self.frames = recent[blast_index.saturating_sub(lead)..].to_vec();
+
+        // Off to the side of the projectile's path, looking across it
+        let came_from = self.projectile_id
+            .and_then(|projectile_id| self.frames.iter().find_map(|frame| {
+                frame.projectile(projectile_id).map(|state| state.position)
+            }))
+            .unwrap_or(blast + Vector3::BACK);
+        let mut along = (blast - came_from).normalized_or_zero();
+        if along == Vector3::ZERO {
+            along = Vector3::FORWARD;
+        }
+        let side = along.cross(Vector3::UP).normalized_or_zero();
+        let position = blast - along * 6.0 + side * 4.0 + Vector3::UP * 3.0;
+        self.camera.set_global_position(position);
+        self.previous_camera = self.base().get_viewport().and_then(|viewport| viewport.get_camera_3d());
+        self.camera.make_current();
+
+        let mut roster = VarDictionary::new();
+        if let Some(mut tree) = self.base().get_tree() {
+            fill_roster(&mut roster, &mut tree);
+        }
+        self.stage = Some(ReplayStage::new(self.game_root.clone().upcast(), roster));
+        self.victim = Some(victim);
+        self.time = 0.0;
+        self.shown_frame = None;
+        self.base_mut().set_visible(true);
+        true
+    }
+
+    fn finish(&mut self) {
+        if let Some(mut stage) = self.stage.take() {
+            stage.clear();
+        }
+        self.frames.clear();
+        for mut node in self.hidden.drain(..) {
+            if node.is_instance_valid() {
+                node.set_visible(true);
+            }
+        }
+        if let Some(mut camera) = self.previous_camera.take()
+                && camera.is_instance_valid() {
+            camera.make_current();
+        }
+        self.base_mut().set_visible(false);
+        if let Some(victim) = self.victim.take() {
+            self.signals().finished().emit(&victim);
+        }
+    }
+
+    // Follow the projectile in, then the victim once it's gone
+    fn aim_camera(&mut self, frame: &ReplayFrame) {
+        let projectile = self.projectile_id.and_then(|projectile_id| {
+            frame.projectile(projectile_id).map(|state| state.position)
+        });
+        let victim_id = self.victim.as_ref().map(|victim| victim.bind().get_player_id());
+        let victim = frame.players
+            .iter()
+            .find(|(id, _)| Some(*id) == victim_id)
+            .map(|(_, state)| state.position);
+        let Some(target) = projectile.or(victim) else {
+            return;
+        };
+        let direction = (target - self.camera.get_global_position()).normalized_or_zero();
+        // look_at can't cope with looking straight up or down
+        if direction.cross(Vector3::UP).length() > 0.01 {
+            self.camera.look_at(target);
+        }
+    }
+
+    // Whatever's really happening keeps going, it just isn't shown
+    fn hide_live_world(&mut self) {
+        let Some(mut tree) = self.base().get_tree() else {
+            return;
+        };
+        for group in [PLAYERS_GROUP, PROJECTILES_GROUP, EXPLOSIONS_GROUP] {
+            let nodes = tree.get_nodes_in_group(group);
+            for mut node in nodes.iter_shared().filter_map(|node| node.try_cast::<Node3D>().ok()) {
+                if node.is_visible() {
+                    node.set_visible(false);
+                    self.hidden.push(node);
+                }
+            }
+        }
+    }
+}
+
+// The latest blast that went off close to the victim, and which frame it's in
+fn knockout_blast(frames: &[ReplayFrame], victim_id: i64) -> Option<(usize, Vector3)> {
+    frames.iter().enumerate().rev().find_map(|(index, frame)| {
+        let (_, victim) = frame.players.iter().find(|(id, _)| *id == victim_id)?;
+        frame.explosions
+            .iter()
+            .find(|blast| blast.distance_to(victim.position) < KNOCKOUT_BLAST_RADIUS)
+            .map(|blast| (index, *blast))
+    })
+}
@@ -0,0 +1,62 @@
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{VBoxContainer, IVBoxContainer, Label};
+
+use crate::kill_volume::KnockoutReason;
+
+// How long a line stays up, and how many fit at once
+const FEED_LINE_SECONDS: f64 = 5.0;
+const MAX_FEED_LINES: usize = 5;
+
+// A running list of who just got knocked out and how, newest at the bottom
+#[derive(GodotClass)]
+#[class(init, base=VBoxContainer)]
+pub struct KillFeed {
+    // Each line and the seconds it has left
+    lines: Vec<(Gd<Label>, f64)>,
+    base: Base<VBoxContainer>,
+}
+
+#[godot_api]
+impl IVBoxContainer for KillFeed {
+    fn process(&mut self, delta: f64) {
+        self.lines.retain_mut(|(label, seconds)| {
+            *seconds -= delta;
+            if *seconds <= 0.0 {
+                label.queue_free();
+                return false;
+            }
+            // Fade out over the last second
+            label.set_modulate(Color::WHITE.with_alpha(seconds.min(1.0) as f32));
+            true
+        });
+    }
+}
+
+#[godot_api]
+impl KillFeed {
+    // `by` is whoever's rocket or mine did it, if it wasn't their own
+    pub fn add_knockout(
+        &mut self,
+        name: &GString,
+        color: Color,
+        reason: KnockoutReason,
+        by: Option<&GString>,
+    ) {
+        let mut label = Label::new_alloc();
+        label.set_text(&match by {
+            Some(by) => format!("{name} {}, thanks to {by}", reason.feed_text()),
+            None => format!("{name} {}", reason.feed_text()),
+        });
+        label.add_theme_color_override("font_color", color);
+        label.add_theme_color_override("font_outline_color", Color::BLACK);
+        label.add_theme_constant_override("outline_size", 4);
+        self.base_mut().add_child(&label);
+        self.lines.push((label, FEED_LINE_SECONDS));
+        while self.lines.len() > MAX_FEED_LINES {
+            let (mut oldest, _) = self.lines.remove(0);
+            oldest.queue_free();
+        }
+    }
+}
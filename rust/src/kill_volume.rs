@@ -0,0 +1,46 @@
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{Area3D, IArea3D};
+
+// Why somebody got knocked out. Sent over the network as its index.
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum KnockoutReason {
+    // Dropped below the arena
+    #[default]
+    Fell = 0,
+    // Sent over the top or out past the sides
+    BlastedOut = 1,
+    // Touched something deadly on the arena itself
+    Hazard = 2,
+}
+
+impl KnockoutReason {
+    // How the kill feed puts it, after the player's name
+    pub fn feed_text(self) -> &'static str {
+        match self {
+            KnockoutReason::Fell => "fell off",
+            KnockoutReason::BlastedOut => "was blasted out",
+            KnockoutReason::Hazard => "hit a hazard",
+        }
+    }
+}
+
+// Anyone who touches one of these is out, for the given reason.
+// Arenas place them over pits, lava, ceilings and the like.
+#[derive(GodotClass)]
+#[class(init, base=Area3D)]
+pub struct KillVolume {
+    #[export]
+    #[init(val=KnockoutReason::Hazard)]
+    reason: KnockoutReason,
+    base: Base<Area3D>,
+}
+
+#[godot_api]
+impl KillVolume {
+    pub fn reason(&self) -> KnockoutReason {
+        self.reason
+    }
+}
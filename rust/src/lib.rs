@@ -12,4 +12,28 @@ mod player;
 mod player_spawner;
 mod explosion;
 mod rocket;
+mod grenade;
+mod weapon;
 mod pause_menu;
+mod match_settings;
+mod roster;
+mod profile;
+mod settings;
+mod input_bindings;
+mod rebind_menu;
+mod split_screen;
+mod bot;
+mod input_source;
+mod replay;
+mod free_camera;
+mod replay_viewer;
+mod kill_cam;
+mod scoreboard;
+mod spectator;
+mod arena;
+mod kill_volume;
+mod kill_feed;
+mod arena_generator;
+mod custom_arena;
+mod arena_editor;
+mod terrain;
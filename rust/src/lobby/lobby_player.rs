@@ -1,11 +1,13 @@
 use godot::prelude::*;
 
+use crate::roster::peer_of;
+
 #[allow(unused_imports)]
 use godot::classes::{
     Node, INode,
     Label,
     Control, IControl,
-    Button, Os, ColorPickerButton
+    Button, Os, ColorPickerButton, CheckButton,
 };
 
 #[derive(GodotClass)]
@@ -16,13 +18,37 @@ pub struct LobbyPlayer {
     name_label: OnEditor<Gd<Label>>,
     #[export]
     color_picker: OnEditor<Gd<ColorPickerButton>>,
+    #[export]
+    ready_button: OnEditor<Gd<CheckButton>>,
+    // Last color we told anyone about
+    color: Color,
 
     base: Base<Control>,
 }
 
 #[godot_api]
 impl IControl for LobbyPlayer {
+    fn ready(&mut self) {
+        self.ready_button
+            .signals()
+            .toggled()
+            .connect_other(&self.to_gd(), |this, ready: bool| {
+                this.signals().ready_toggled().emit(ready);
+            });
 
+        // Dragging around the picker changes the color every frame,
+        // so only send it on once the popup's put away
+        self.color_picker
+            .signals()
+            .popup_closed()
+            .connect_other(&self.to_gd(), |this| {
+                let color = this.color_picker.get_pick_color();
+                if color != this.color {
+                    this.color = color;
+                    this.signals().color_picked().emit(color);
+                }
+            });
+    }
 }
 
 #[godot_api]
@@ -34,15 +60,40 @@ impl LobbyPlayer {
         // Don't want other players editing our color
         if !is_local {
             self.color_picker.set_disabled(true);
+            self.ready_button.set_disabled(true);
         }
     }
 
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+
+    // Update the ready state without echoing it back over the network
+    pub fn set_player_ready(&mut self, ready: bool) {
+        self.ready_button.set_pressed_no_signal(ready);
+    }
+
+    #[signal]
+    pub fn ready_toggled(ready: bool);
+
+    pub fn focus_ready_button(&mut self) {
+        self.ready_button.grab_focus();
+    }
+
+    // Shows the color without emitting `color_picked`
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+        self.color_picker.set_pick_color(color);
+    }
+
+    #[signal]
+    pub fn color_picked(color: Color);
+
     #[func]
     pub fn on_peer_disconnected(&mut self, id: i64) {
-        // Check if *this* is the disconnected player
-        if id == self.id {
+        // Check if *this* is the disconnected player, or sat at their machine
+        if id == peer_of(self.id) {
             self.base_mut().queue_free();
         }
     }
 }
-
@@ -0,0 +1,391 @@
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Control, IControl,
+    OptionButton, SpinBox, CheckBox, Button,
+};
+use godot::global::randi_range;
+
+use crate::match_settings::{
+    MatchSettings, GameMode, MAX_DUMMIES, MAX_BOT_FILL,
+    MAX_RESPAWN_DELAY, MAX_SPAWN_PROTECTION, MAX_ARENA_SEED,
+};
+use crate::arena::{ArenaInfo, ARENAS};
+use crate::custom_arena::{
+    ArenaFile, CUSTOM_ARENAS_DIR, arena_path, list_custom_arenas, own_arena_hash,
+};
+use crate::arena_generator::{
+    GeneratorParams, MIN_ARENA_SIZE, MAX_ARENA_SIZE, MAX_GAPS,
+    MAX_HEIGHT_VARIATION, MAX_MINE_DENSITY, MIN_SPAWN_COUNT, MAX_SPAWN_COUNT,
+};
+use crate::bot::BotDifficulty;
+use crate::weapon::WeaponKind;
+
+
+// Host-only panel for the match rules. Clients see the same panel,
+// but read-only and kept up to date by the host.
+#[derive(GodotClass)]
+#[class(init, base=Control)]
+pub struct MatchSettingsPanel {
+    #[export]
+    arena_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    mode_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    ko_limit_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    time_limit_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    dummies_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    bot_fill_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    bot_difficulty_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    respawn_delay_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    spawn_protection_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    low_gravity_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    fast_reload_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    big_blasts_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    destructible_terrain_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    auto_start_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    competitive_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    arena_vote_check: OnEditor<Gd<CheckBox>>,
+    // Gets a check box for every weapon
+    #[export]
+    loadout_box: OnEditor<Gd<Control>>,
+    // Same order as WeaponKind::ALL
+    loadout_checks: Vec<Gd<CheckBox>>,
+    // Only shown while a generated arena is picked
+    #[export]
+    generator_box: OnEditor<Gd<Control>>,
+    #[export]
+    seed_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    new_seed_button: OnEditor<Gd<Button>>,
+    #[export]
+    arena_size_spin: OnEditor<Gd<SpinBox>>,
+    // Gaps and mine density are shown as percentages
+    #[export]
+    gaps_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    height_variation_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    mine_density_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    spawn_count_spin: OnEditor<Gd<SpinBox>>,
+    // Ids of the custom arenas listed after the built in ones
+    custom_arenas: Vec<String>,
+    base: Base<Control>,
+}
+
+#[godot_api]
+impl IControl for MatchSettingsPanel {
+    fn ready(&mut self) {
+        let gd_ref = self.to_gd();
+
+        self.refresh_custom_arenas();
+        self.mode_option.clear();
+        for mode in GameMode::ALL {
+            self.mode_option.add_item(mode.label());
+        }
+        self.dummies_spin.set_max(MAX_DUMMIES as f64);
+        self.bot_fill_spin.set_max(MAX_BOT_FILL as f64);
+        self.respawn_delay_spin.set_max(MAX_RESPAWN_DELAY);
+        self.spawn_protection_spin.set_max(MAX_SPAWN_PROTECTION);
+        self.seed_spin.set_max(MAX_ARENA_SEED as f64);
+        self.arena_size_spin.set_min(MIN_ARENA_SIZE as f64);
+        self.arena_size_spin.set_max(MAX_ARENA_SIZE as f64);
+        self.gaps_spin.set_max(MAX_GAPS * 100.0);
+        self.height_variation_spin.set_max(MAX_HEIGHT_VARIATION as f64);
+        self.mine_density_spin.set_max(MAX_MINE_DENSITY * 100.0);
+        self.spawn_count_spin.set_min(MIN_SPAWN_COUNT as f64);
+        self.spawn_count_spin.set_max(MAX_SPAWN_COUNT as f64);
+        self.bot_difficulty_option.clear();
+        for difficulty in BotDifficulty::ALL {
+            self.bot_difficulty_option.add_item(difficulty.label());
+        }
+        for kind in WeaponKind::ALL {
+            let mut check = CheckBox::new_alloc();
+            check.set_text(kind.label());
+            self.loadout_box.add_child(&check);
+            self.loadout_checks.push(check);
+        }
+        self.apply(&MatchSettings::default());
+
+        self.arena_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, _index: i64| {
+                this.update_generator();
+                this.on_changed();
+            });
+        self.mode_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, _index: i64| {
+                this.update_arenas();
+                this.on_changed();
+            });
+        self.ko_limit_spin
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        self.time_limit_spin
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        self.dummies_spin
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        self.bot_fill_spin
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        self.respawn_delay_spin
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        self.spawn_protection_spin
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        self.bot_difficulty_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, _index: i64| this.on_changed());
+        for spin in [
+            &self.seed_spin,
+            &self.arena_size_spin,
+            &self.gaps_spin,
+            &self.height_variation_spin,
+            &self.mine_density_spin,
+            &self.spawn_count_spin,
+        ] {
+            spin
+                .signals()
+                .value_changed()
+                .connect_other(&gd_ref, |this, _value: f64| this.on_changed());
+        }
+        // Changing the seed already lets everyone know
+        self.new_seed_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                let seed = randi_range(0, MAX_ARENA_SEED);
+                this.seed_spin.set_value(seed as f64);
+            });
+        for check in [
+            &self.low_gravity_check,
+            &self.fast_reload_check,
+            &self.big_blasts_check,
+            &self.destructible_terrain_check,
+            &self.auto_start_check,
+            &self.competitive_check,
+            &self.arena_vote_check,
+        ] {
+            check
+                .signals()
+                .toggled()
+                .connect_other(&gd_ref, |this, _on: bool| this.on_changed());
+        }
+        for check in &self.loadout_checks {
+            check
+                .signals()
+                .toggled()
+                .connect_other(&gd_ref, |this, _on: bool| this.on_changed());
+        }
+    }
+}
+
+#[godot_api]
+impl MatchSettingsPanel {
+    #[signal]
+    pub fn settings_changed(settings: VarDictionary);
+
+    // Lists the built in arenas again, then every custom one that loads
+    pub fn refresh_custom_arenas(&mut self) {
+        let selected = self.arena_option.get_selected();
+        self.arena_option.clear();
+        for arena in &ARENAS {
+            self.arena_option.add_item(&arena.label());
+        }
+        self.custom_arenas.clear();
+        for id in list_custom_arenas() {
+            match ArenaFile::load(&arena_path(CUSTOM_ARENAS_DIR, &id)) {
+                Ok(file) => {
+                    self.arena_option.add_item(&format!("{} (custom)", file.name));
+                    self.custom_arenas.push(id);
+                }
+                Err(error) => godot_warn!("Skipping custom arena: {error}"),
+            }
+        }
+        if selected >= 0 && selected < self.arena_option.get_item_count() {
+            self.arena_option.select(selected);
+        }
+    }
+
+    pub fn get_settings(&self) -> MatchSettings {
+        let selected = self.arena_option.get_selected() as i64;
+        let custom_arena = selected
+            .checked_sub(ARENAS.len() as i64)
+            .and_then(|index| self.custom_arenas.get(index as usize))
+            .cloned()
+            .unwrap_or_default();
+        let custom_arena_hash = own_arena_hash(&custom_arena);
+        MatchSettings {
+            arena: selected.min(ARENAS.len() as i64 - 1),
+            custom_arena,
+            custom_arena_hash,
+            arena_vote: self.arena_vote_check.is_pressed(),
+            arena_seed: self.seed_spin.get_value() as i64,
+            generator: GeneratorParams {
+                size: self.arena_size_spin.get_value() as i64,
+                gaps: self.gaps_spin.get_value() / 100.0,
+                height_variation: self.height_variation_spin.get_value() as i64,
+                mine_density: self.mine_density_spin.get_value() / 100.0,
+                spawn_count: self.spawn_count_spin.get_value() as i64,
+            },
+            mode: GameMode::from_index(self.mode_option.get_selected() as i64),
+            ko_limit: self.ko_limit_spin.get_value() as i64,
+            time_limit: self.time_limit_spin.get_value() as i64,
+            low_gravity: self.low_gravity_check.is_pressed(),
+            fast_reload: self.fast_reload_check.is_pressed(),
+            big_blasts: self.big_blasts_check.is_pressed(),
+            destructible_terrain: self.destructible_terrain_check.is_pressed(),
+            auto_start: self.auto_start_check.is_pressed(),
+            dummies: self.dummies_spin.get_value() as i64,
+            bot_fill: self.bot_fill_spin.get_value() as i64,
+            bot_difficulty: BotDifficulty::from_index(
+                self.bot_difficulty_option.get_selected() as i64),
+            competitive: self.competitive_check.is_pressed(),
+            respawn_delay: self.respawn_delay_spin.get_value(),
+            spawn_protection: self.spawn_protection_spin.get_value(),
+            loadout: self.get_loadout(),
+        }
+    }
+
+    // Falls back to the default rather than leave everyone unarmed
+    fn get_loadout(&self) -> Vec<WeaponKind> {
+        let loadout: Vec<WeaponKind> = WeaponKind::ALL
+            .into_iter()
+            .zip(&self.loadout_checks)
+            .filter(|(_, check)| check.is_pressed())
+            .map(|(kind, _)| kind)
+            .collect();
+        if loadout.is_empty() {
+            MatchSettings::default().loadout
+        } else {
+            loadout
+        }
+    }
+
+    // Show the given settings without emitting any change signals
+    pub fn apply(&mut self, settings: &MatchSettings) {
+        self.arena_option.select(settings.arena as i32);
+        if settings.is_custom_arena() {
+            // Clients list the host's custom arena even before they have it
+            let index = match self.custom_arenas.iter().position(|id| *id == settings.custom_arena) {
+                Some(index) => index,
+                None => {
+                    self.arena_option.add_item(&format!("{} (custom)", settings.custom_arena));
+                    self.custom_arenas.push(settings.custom_arena.clone());
+                    self.custom_arenas.len() - 1
+                }
+            };
+            self.arena_option.select((ARENAS.len() + index) as i32);
+        }
+        self.mode_option.select(settings.mode.to_index() as i32);
+        self.arena_vote_check.set_pressed_no_signal(settings.arena_vote);
+        self.update_arenas();
+        self.seed_spin.set_value_no_signal(settings.arena_seed as f64);
+        self.arena_size_spin.set_value_no_signal(settings.generator.size as f64);
+        self.gaps_spin.set_value_no_signal(settings.generator.gaps * 100.0);
+        self.height_variation_spin
+            .set_value_no_signal(settings.generator.height_variation as f64);
+        self.mine_density_spin.set_value_no_signal(settings.generator.mine_density * 100.0);
+        self.spawn_count_spin.set_value_no_signal(settings.generator.spawn_count as f64);
+        self.ko_limit_spin.set_value_no_signal(settings.ko_limit as f64);
+        self.time_limit_spin.set_value_no_signal(settings.time_limit as f64);
+        self.dummies_spin.set_value_no_signal(settings.dummies as f64);
+        self.bot_fill_spin.set_value_no_signal(settings.bot_fill as f64);
+        self.bot_difficulty_option.select(settings.bot_difficulty.to_index() as i32);
+        self.low_gravity_check.set_pressed_no_signal(settings.low_gravity);
+        self.fast_reload_check.set_pressed_no_signal(settings.fast_reload);
+        self.big_blasts_check.set_pressed_no_signal(settings.big_blasts);
+        self.destructible_terrain_check.set_pressed_no_signal(settings.destructible_terrain);
+        self.auto_start_check.set_pressed_no_signal(settings.auto_start);
+        self.competitive_check.set_pressed_no_signal(settings.competitive);
+        self.respawn_delay_spin.set_value_no_signal(settings.respawn_delay);
+        self.spawn_protection_spin.set_value_no_signal(settings.spawn_protection);
+        for (kind, check) in WeaponKind::ALL.into_iter().zip(self.loadout_checks.iter_mut()) {
+            check.set_pressed_no_signal(settings.loadout.contains(&kind));
+        }
+    }
+
+    pub fn set_editable(&mut self, editable: bool) {
+        self.arena_option.set_disabled(!editable);
+        self.mode_option.set_disabled(!editable);
+        self.ko_limit_spin.set_editable(editable);
+        self.time_limit_spin.set_editable(editable);
+        self.dummies_spin.set_editable(editable);
+        self.bot_fill_spin.set_editable(editable);
+        self.bot_difficulty_option.set_disabled(!editable);
+        self.low_gravity_check.set_disabled(!editable);
+        self.fast_reload_check.set_disabled(!editable);
+        self.big_blasts_check.set_disabled(!editable);
+        self.destructible_terrain_check.set_disabled(!editable);
+        self.auto_start_check.set_disabled(!editable);
+        self.competitive_check.set_disabled(!editable);
+        self.arena_vote_check.set_disabled(!editable);
+        for check in self.loadout_checks.iter_mut() {
+            check.set_disabled(!editable);
+        }
+        self.respawn_delay_spin.set_editable(editable);
+        self.spawn_protection_spin.set_editable(editable);
+        self.seed_spin.set_editable(editable);
+        self.new_seed_button.set_disabled(!editable);
+        self.arena_size_spin.set_editable(editable);
+        self.gaps_spin.set_editable(editable);
+        self.height_variation_spin.set_editable(editable);
+        self.mine_density_spin.set_editable(editable);
+        self.spawn_count_spin.set_editable(editable);
+    }
+
+    // Arenas that can't host the chosen mode can't be picked
+    fn update_arenas(&mut self) {
+        let mode = GameMode::from_index(self.mode_option.get_selected() as i64);
+        for (index, arena) in ARENAS.iter().enumerate() {
+            self.arena_option.set_item_disabled(index as i32, !arena.supports(mode));
+        }
+        // Custom arenas suit any mode
+        let selected = self.arena_option.get_selected() as i64;
+        if selected < ARENAS.len() as i64
+                && !ArenaInfo::get(selected).supports(mode)
+                && let Some(index) = ARENAS.iter().position(|arena| arena.supports(mode)) {
+            self.arena_option.select(index as i32);
+        }
+        self.update_generator();
+    }
+
+    fn update_generator(&mut self) {
+        let selected = self.arena_option.get_selected() as i64;
+        let generated = selected < ARENAS.len() as i64 && ArenaInfo::get(selected).generated;
+        self.generator_box.set_visible(generated);
+    }
+
+    fn on_changed(&mut self) {
+        let settings = self.get_settings().to_dict();
+        self.signals().settings_changed().emit(&settings);
+    }
+}
@@ -1,20 +1,34 @@
 use godot::classes::enet_connection::CompressionMode;
-#[allow(unused_imports)]
 use godot::classes::object::ConnectFlags;
 use godot::classes::{
-    Button, ENetMultiplayerPeer, Label, LineEdit, LinkButton, ProjectSettings,
+    Button, ENetMultiplayerPeer, OfflineMultiplayerPeer,
+    Label, LineEdit, LinkButton, ProjectSettings,
     Control, IControl,
-    Input, ConfigFile,
+    Input, OptionButton, SpinBox, CheckBox, FileDialog, DirAccess,
 };
 use godot::classes::input::{MouseMode};
-use godot::global::Error;
+use godot::global::{Error, randf_range};
 use godot::prelude::*;
 
 mod lobby_player;
+mod match_settings_panel;
 use lobby_player::{LobbyPlayer};
+use match_settings_panel::MatchSettingsPanel;
 use crate::game::Game;
+use crate::match_settings::{MatchSettings, MAX_DUMMIES};
+use crate::roster::{
+    RosterEntry, DEFAULT_PLAYER_COLOR, MAX_LOCAL_PLAYERS,
+    player_id, peer_of, computer_player_id, count_players,
+};
+use crate::profile::{PlayerProfile, ControlScheme};
+use crate::input_bindings::{ANY_DEVICE, device_label};
+use crate::settings::Settings;
+use crate::rebind_menu::RebindMenu;
+use crate::replay::{Replay, REPLAYS_DIR};
+use crate::arena::{ArenaInfo, ARENAS};
+use crate::arena_editor::ArenaEditor;
+use crate::custom_arena::{ArenaFile, find_arena_text, own_arena_hash, store_received_arena};
 
-const DEFAULT_PORT: i32 = 8910;
 
 #[derive(GodotClass)]
 #[class(init, base=Control)]
@@ -46,9 +60,51 @@ pub struct Lobby {
     #[export]
     find_public_ip_button: OnEditor<Gd<LinkButton>>,
     #[export]
-    #[init(val=GString::from("user://settings.cfg"))]
-    settings_config_name: GString,
-    player_names_dict: VarDictionary,
+    match_settings_panel: OnEditor<Gd<MatchSettingsPanel>>,
+    #[export]
+    servers_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    favorite_button: OnEditor<Gd<Button>>,
+    #[export]
+    controls_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    rebind_button: OnEditor<Gd<Button>>,
+    #[export]
+    local_players_spin: OnEditor<Gd<SpinBox>>,
+    #[export]
+    practice_button: OnEditor<Gd<Button>>,
+    #[export]
+    leave_button: OnEditor<Gd<Button>>,
+    #[export]
+    replay_button: OnEditor<Gd<Button>>,
+    #[export]
+    record_replays_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    replay_dialog: OnEditor<Gd<FileDialog>>,
+    #[export]
+    editor_button: OnEditor<Gd<Button>>,
+    #[export]
+    spectate_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    arena_vote_box: OnEditor<Gd<Control>>,
+    #[export]
+    arena_vote_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    arena_preview_label: OnEditor<Gd<Label>>,
+    #[export]
+    rebind_menu: OnEditor<Gd<RebindMenu>>,
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
+    roster: VarDictionary,
+    #[init(val=DEFAULT_PLAYER_COLOR)]
+    local_color: Color,
+    player_ready_dict: VarDictionary,
+    // Which arena each machine voted for
+    arena_votes: VarDictionary,
+    // Host only: the custom arena hash each client has a copy of
+    custom_arena_hashes: VarDictionary,
+    match_settings: MatchSettings,
+    profile: PlayerProfile,
     peer: Option<Gd<ENetMultiplayerPeer>>,
     base: Base<Control>,
 }
@@ -89,16 +145,44 @@ impl IControl for Lobby {
             .connect_other_gd(&gd_ref, |mut this: Gd<Self>, id: i64| {
                 
                 let mut multiplayer = this.bind().base().get_multiplayer().unwrap();
-                if multiplayer.is_server() {
-                } else {
+                if !multiplayer.is_server() {
                     this.bind_mut().show_lobby();
                 }
 
-                let name: GString = this.bind().name_input.get_text().clone();
+                let self_id = multiplayer.get_unique_id() as i64;
+                let ready = this.bind().is_player_ready(self_id);
+                let entries = this.bind().roster_of_peer(self_id);
 
                 // Tell the other player about yourself
-                this.rpc_id(id, "register_player", vslice![name]);
+                this.rpc_id(id, "register_player", vslice![entries, ready]);
+                let vote = this.bind().arena_votes.get(self_id);
+                if let Some(vote) = vote {
+                    this.rpc_id(id, "vote_arena", &[vote]);
+                }
+
+                // Bring the new player up to date on the rules
+                if multiplayer.is_server() {
+                    let settings = this.bind().match_settings.to_dict();
+                    this.rpc_id(id, "sync_match_settings", vslice![settings]);
+                }
+            });
 
+        multiplayer
+            .signals()
+            .peer_disconnected()
+            .builder()
+            .connect_other_mut(&gd_ref, |this, id: i64| {
+                let player_ids: Vec<i64> = this.roster.keys_shared().typed::<i64>().collect();
+                for player_id in player_ids {
+                    if peer_of(player_id) == id {
+                        this.roster.remove(player_id);
+                    }
+                }
+                this.player_ready_dict.remove(id);
+                this.arena_votes.remove(id);
+                this.custom_arena_hashes.remove(id);
+                this.update_arena_vote();
+                this.update_start_button();
             });
 
         // multiplayer
@@ -148,22 +232,132 @@ impl IControl for Lobby {
                 this.on_join_btn_pressed();
             });
 
+        self.practice_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::on_practice_btn_pressed);
+
+        self.leave_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                this.end_game("");
+            });
+
+        self.replay_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                // The dialog can't open a folder that isn't there yet
+                DirAccess::make_dir_recursive_absolute(REPLAYS_DIR);
+                this.replay_dialog.set_current_dir(REPLAYS_DIR);
+                this.replay_dialog.popup_centered();
+            });
+        self.replay_dialog
+            .signals()
+            .file_selected()
+            .connect_other(&gd_ref, Self::start_replay);
+        self.editor_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::open_arena_editor);
+        let record = self.settings.bind().replays().record;
+        self.record_replays_check.set_pressed_no_signal(record);
+        self.record_replays_check
+            .signals()
+            .toggled()
+            .connect_other(&gd_ref, |this, on: bool| {
+                let mut replays = this.settings.bind().replays().clone();
+                replays.record = on;
+                this.settings.bind_mut().set_replays(replays);
+                this.settings.bind_mut().save();
+            });
+
         self.start_game_button
             .signals()
             .pressed()
             .connect_other(&gd_ref, Self::on_start_game_pressed);
-        
-        let mut config_file = ConfigFile::new_gd();
-        config_file.load(&self.settings_config_name);
-        let ip_text: GString = config_file
-            .get_value_ex("Lobby", "prev_ip_address")
-            .default(&Variant::from("127.0.0.1"))
-            .done()
-            .try_to::<GString>()
-            .unwrap()
-            ;
-        self.address.set_text(&ip_text);
 
+        self.match_settings_panel
+            .signals()
+            .settings_changed()
+            .connect_other(&gd_ref, Self::on_match_settings_changed);
+
+        for arena in &ARENAS {
+            self.arena_vote_option.add_item(arena.name);
+        }
+        self.arena_vote_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, index: i64| {
+                this.base_mut().rpc("vote_arena", vslice![index]);
+            });
+
+        let lobby_settings = self.settings.bind().lobby().clone();
+        self.address.set_text(&lobby_settings.prev_ip_address);
+        self.match_settings = lobby_settings.match_settings;
+        let match_settings = self.match_settings.clone();
+        self.match_settings_panel.bind_mut().apply(&match_settings);
+        self.update_arena_vote();
+
+        // Pick up where we left off last launch
+        self.profile = PlayerProfile::load();
+        self.name_input.set_text(&self.profile.display_name);
+        self.local_color = self.profile.color;
+        if let Some(address) = self.profile.recent_servers.first() {
+            self.address.set_text(address);
+        }
+        self.controls_option.clear();
+        for scheme in ControlScheme::ALL {
+            self.controls_option.add_item(scheme.label());
+        }
+        self.controls_option.select(self.profile.preferred_controls.to_index() as i32);
+        self.local_players_spin.set_min(1.0);
+        self.local_players_spin.set_max(MAX_LOCAL_PLAYERS as f64);
+        self.refresh_server_list();
+
+        self.servers_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, index: i64| {
+                let address = this.servers_option.get_item_text(index as i32);
+                this.address.set_text(&address);
+                this.update_favorite_button();
+            });
+        self.favorite_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                let address = this.address.get_text();
+                this.profile.toggle_favorite(&address);
+                this.profile.save();
+                this.refresh_server_list();
+            });
+        self.address
+            .signals()
+            .text_changed()
+            .connect_other(&gd_ref, |this, _text: GString| {
+                this.update_favorite_button();
+            });
+        self.controls_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, index: i64| {
+                this.profile.preferred_controls = ControlScheme::from_index(index);
+                this.profile.save();
+            });
+        self.rebind_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                this.rebind_menu.bind_mut().open();
+            });
+        self.rebind_menu
+            .signals()
+            .closed()
+            .connect_other(&gd_ref, |this| {
+                this.rebind_button.grab_focus();
+            });
     }
 }
 
@@ -181,16 +375,364 @@ impl Lobby {
     }
 
     fn on_start_game_pressed(&mut self) {
-        if self.base().get_multiplayer().unwrap().is_server() {
-            self.base_mut().rpc("start_game_remote", &[]);
+        if self.base().get_multiplayer().unwrap().is_server()
+                && !self.base().has_node("/root/Game") {
+            // Remember these rules for next time
+            self.save_config();
+            if self.match_settings.arena_vote {
+                self.match_settings.arena = self.voted_arena();
+                self.match_settings.custom_arena.clear();
+                let match_settings = self.match_settings.clone();
+                self.match_settings_panel.bind_mut().apply(&match_settings);
+            }
+            // Reliable calls arrive in order, so anyone without the
+            // custom arena has it before the match starts
+            if self.match_settings.is_custom_arena() {
+                // In case the file changed since it was picked
+                let hash = own_arena_hash(&self.match_settings.custom_arena);
+                self.match_settings.custom_arena_hash = hash;
+                let peers = self.base().get_multiplayer().unwrap().get_peers();
+                for peer in peers.as_slice() {
+                    let confirmed = self.custom_arena_hashes
+                        .get(*peer as i64)
+                        .and_then(|confirmed| confirmed.try_to::<i64>().ok());
+                    if confirmed != Some(hash) {
+                        self.send_custom_arena(*peer as i64);
+                    }
+                }
+            }
+            let settings = self.match_settings.to_dict();
+            self.base_mut().rpc("start_game_remote", vslice![settings]);
             self.start_game_authority();
         }
     }
 
+    fn on_match_settings_changed(&mut self, settings: VarDictionary) {
+        if self.base().get_multiplayer().unwrap().is_server() {
+            self.match_settings = MatchSettings::from_dict(&settings);
+            self.base_mut().rpc("sync_match_settings", vslice![settings]);
+            self.update_arena_vote();
+            self.update_start_button();
+        }
+    }
+
+    #[rpc(authority, call_remote, reliable)]
+    fn sync_match_settings(&mut self, settings: VarDictionary) {
+        let previous = self.match_settings.clone();
+        self.match_settings = MatchSettings::from_dict(&settings);
+        let match_settings = self.match_settings.clone();
+        self.match_settings_panel.bind_mut().apply(&match_settings);
+        self.update_arena_vote();
+        if match_settings.custom_arena != previous.custom_arena
+                || match_settings.custom_arena_hash != previous.custom_arena_hash {
+            self.check_custom_arena();
+        }
+    }
+
+    // Clients tell the host whether they need its custom arena
+    fn check_custom_arena(&mut self) {
+        if !self.match_settings.is_custom_arena() {
+            return;
+        }
+        let id = self.match_settings.custom_arena.clone();
+        let hash = self.match_settings.custom_arena_hash;
+        if find_arena_text(&id, hash).is_some() {
+            self.base_mut().rpc_id(1, "confirm_custom_arena", vslice![hash]);
+        } else {
+            self.set_status(&format!("Downloading arena {id}..."), true);
+            self.base_mut().rpc_id(1, "request_custom_arena", &[]);
+        }
+    }
+
+    #[rpc(any_peer, call_remote, reliable)]
+    fn confirm_custom_arena(&mut self, hash: i64) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        let id = multiplayer.get_remote_sender_id() as i64;
+        self.custom_arena_hashes.set(id, hash);
+    }
+
+    #[rpc(any_peer, call_remote, reliable)]
+    fn request_custom_arena(&mut self) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        if multiplayer.is_server() {
+            let id = multiplayer.get_remote_sender_id() as i64;
+            self.send_custom_arena(id);
+        }
+    }
+
+    fn send_custom_arena(&mut self, peer: i64) {
+        let id = self.match_settings.custom_arena.clone();
+        let hash = self.match_settings.custom_arena_hash;
+        let Some(json) = find_arena_text(&id, hash) else {
+            godot_error!("Custom arena {id} changed or went missing, can't send it");
+            return;
+        };
+        // Sent is as good as confirmed, it can't arrive any later than the match
+        self.custom_arena_hashes.set(peer, hash);
+        self.base_mut().rpc_id(peer, "receive_custom_arena", vslice![
+            GString::from(&id), GString::from(&json)
+        ]);
+    }
+
+    #[rpc(authority, call_remote, reliable)]
+    fn receive_custom_arena(&mut self, id: GString, json: GString) {
+        match store_received_arena(&id.to_string(), &json.to_string()) {
+            Ok(file) => {
+                self.set_status(&format!("Got arena {}.", file.name), true);
+                self.update_arena_vote();
+            }
+            Err(error) => self.set_status(&format!("Bad arena from host: {error}"), false),
+        }
+    }
+
+    // One vote per machine, however many sit at it
+    #[rpc(any_peer, call_local, reliable)]
+    fn vote_arena(&mut self, arena: i64) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        let id = multiplayer.get_remote_sender_id() as i64;
+        self.arena_votes.set(id, arena.clamp(0, ARENAS.len() as i64 - 1));
+        self.update_arena_vote();
+    }
+
+    // Shows the vote tally, or just what the host picked
+    fn update_arena_vote(&mut self) {
+        let voting = self.match_settings.arena_vote;
+        self.arena_vote_box.set_visible(voting);
+        let mode = self.match_settings.mode;
+        for (index, arena) in ARENAS.iter().enumerate() {
+            let votes = self.arena_votes
+                .values_array()
+                .iter_shared()
+                .filter(|vote| vote.try_to::<i64>().ok() == Some(index as i64))
+                .count();
+            let text = match votes {
+                0 => arena.name.to_string(),
+                1 => format!("{} (1 vote)", arena.name),
+                _ => format!("{} ({votes} votes)", arena.name),
+            };
+            self.arena_vote_option.set_item_text(index as i32, &text);
+            self.arena_vote_option.set_item_disabled(index as i32, !arena.supports(mode));
+        }
+
+        let own_id = self.base().get_multiplayer().unwrap().get_unique_id() as i64;
+        let own_vote = self.arena_votes.get(own_id).and_then(|vote| vote.try_to::<i64>().ok());
+        let shown = match own_vote {
+            Some(vote) if voting => vote,
+            _ => self.match_settings.arena,
+        };
+        // Nothing picked until we vote, so any pick counts as one
+        self.arena_vote_option.select(own_vote.map_or(-1, |vote| vote as i32));
+        if self.match_settings.is_custom_arena() && !voting {
+            let settings = &self.match_settings;
+            let text = match find_arena_text(&settings.custom_arena, settings.custom_arena_hash)
+                .map(|json| ArenaFile::parse(&json)) {
+                Some(Ok(file)) => format!("{} (custom)\n{}", file.name, file.preview),
+                Some(Err(error)) => format!("{} (custom)\n{error}", settings.custom_arena),
+                None => format!("{} (custom)\nWaiting for the host to send it.",
+                    settings.custom_arena),
+            };
+            self.arena_preview_label.set_text(&text);
+            return;
+        }
+        let arena = ArenaInfo::get(shown);
+        self.arena_preview_label.set_text(&format!("{}\n{}", arena.label(), arena.preview));
+    }
+
+    // Most votes among the arenas that suit the match, ties settled
+    // at random. Nobody voting leaves it to the host.
+    fn voted_arena(&self) -> i64 {
+        let mode = self.match_settings.mode;
+        let players = count_players(&self.match_roster());
+        let mut candidates: Vec<i64> = (0..ARENAS.len() as i64)
+            .filter(|index| {
+                let arena = ArenaInfo::get(*index);
+                arena.supports(mode) && arena.fits(players)
+            })
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..ARENAS.len() as i64)
+                .filter(|index| ArenaInfo::get(*index).supports(mode))
+                .collect();
+        }
+        let tally = |arena: i64| {
+            self.arena_votes
+                .values_array()
+                .iter_shared()
+                .filter(|vote| vote.try_to::<i64>().ok() == Some(arena))
+                .count()
+        };
+        let most = candidates.iter().map(|arena| tally(*arena)).max().unwrap_or(0);
+        if most == 0 {
+            return self.match_settings.arena;
+        }
+        let winners: Vec<i64> = candidates
+            .into_iter()
+            .filter(|arena| tally(*arena) == most)
+            .collect();
+        let index = randf_range(0.0, winners.len() as f64) as usize;
+        winners[index.min(winners.len() - 1)]
+    }
+
+    #[rpc(any_peer, call_local, reliable)]
+    fn set_player_ready(&mut self, ready: bool) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        let id = multiplayer.get_remote_sender_id() as i64;
+        self.player_ready_dict.set(id, ready);
+        // Everyone at that machine readies up together
+        for mut lobby_player in self.lobby_players_of_peer(id) {
+            lobby_player.bind_mut().set_player_ready(ready);
+        }
+        self.update_start_button();
+    }
+
+    #[rpc(any_peer, call_local, reliable)]
+    fn set_player_color(&mut self, color: Color) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        let id = multiplayer.get_remote_sender_id() as i64;
+        if let Some(entry) = self.roster.get(id) {
+            let mut entry = RosterEntry::from_dict(&entry.to());
+            entry.color = color;
+            self.roster.set(id, entry.to_dict());
+        }
+        if let Some(mut lobby_player) = self.find_lobby_player(id) {
+            lobby_player.bind_mut().set_color(color);
+        }
+    }
+
+    fn is_player_ready(&self, id: i64) -> bool {
+        self.player_ready_dict
+            .get(id)
+            .and_then(|v| v.try_to::<bool>().ok())
+            .unwrap_or(false)
+    }
+
+    fn all_players_ready(&self) -> bool {
+        !self.roster.is_empty()
+            && self.roster
+                .keys_shared()
+                .typed::<i64>()
+                .all(|id| self.is_player_ready(peer_of(id)))
+    }
+
+    // Only the host can start, and only once everyone is ready
+    fn update_start_button(&mut self) {
+        if !self.base().get_multiplayer().unwrap().is_server() {
+            return;
+        }
+        let all_ready = self.all_players_ready();
+        self.start_game_button.set_disabled(!all_ready);
+        if all_ready && self.match_settings.auto_start {
+            self.on_start_game_pressed();
+        }
+    }
+
+    fn find_lobby_player(&self, id: i64) -> Option<Gd<LobbyPlayer>> {
+        self.players_joined_container
+            .get_children()
+            .iter_shared()
+            .filter_map(|child| child.try_cast::<LobbyPlayer>().ok())
+            .find(|lobby_player| lobby_player.bind().get_id() == id)
+    }
+
+    fn lobby_players_of_peer(&self, peer_id: i64) -> Vec<Gd<LobbyPlayer>> {
+        self.players_joined_container
+            .get_children()
+            .iter_shared()
+            .filter_map(|child| child.try_cast::<LobbyPlayer>().ok())
+            .filter(|lobby_player| peer_of(lobby_player.bind().get_id()) == peer_id)
+            .collect()
+    }
+
+    // Everyone playing at one machine, in roster form
+    fn roster_of_peer(&self, peer_id: i64) -> VarDictionary {
+        let mut entries = VarDictionary::new();
+        for (id, entry) in self.roster.iter_shared().typed::<i64, VarDictionary>() {
+            if peer_of(id) == peer_id {
+                entries.set(id, entry);
+            }
+        }
+        entries
+    }
+
+    // Add the players at this machine to the roster and the lobby.
+    // The first is us, the rest share the screen on their own gamepads.
+    fn add_local_players(&mut self, peer_id: i64) {
+        let count = (self.local_players_spin.get_value() as i64).clamp(1, MAX_LOCAL_PLAYERS);
+        let prefers_gamepad = self.profile.preferred_controls == ControlScheme::Gamepad;
+        let name = self.name_input.get_text();
+        if self.spectate_check.is_pressed() {
+            let mut entry = RosterEntry::new(name, self.local_color);
+            entry.device = ANY_DEVICE;
+            entry.spectator = true;
+            let id = player_id(peer_id, 0);
+            self.add_new_lobby_player(id, &card_name(&entry), entry.color, true);
+            self.roster.set(id, entry.to_dict());
+            return;
+        }
+        for local_index in 0..count {
+            let device = if count == 1 {
+                ANY_DEVICE
+            } else if prefers_gamepad {
+                local_index
+            } else {
+                // Keyboard and mouse first, then pads in order
+                local_index - 1
+            };
+            let mut entry = RosterEntry::new(name.clone(), self.local_color);
+            entry.device = device;
+            if local_index > 0 {
+                entry.name = GString::from(&format!("{name} {}", local_index + 1));
+                entry.color = guest_color(self.local_color, local_index);
+            }
+            let id = player_id(peer_id, local_index);
+            let card_name: GString = if count == 1 {
+                entry.name.clone()
+            } else {
+                GString::from(&format!("{} ({})", entry.name, device_label(device)))
+            };
+            self.add_new_lobby_player(id, &card_name, entry.color, local_index == 0);
+            self.roster.set(id, entry.to_dict());
+        }
+    }
+
+    // Whether we joined only to watch
+    fn is_spectating(&self) -> bool {
+        let own_id = self.base().get_multiplayer().unwrap().get_unique_id() as i64;
+        self.roster_of_peer(own_id)
+            .iter_shared()
+            .filter_map(|(_, entry)| entry.try_to::<VarDictionary>().ok())
+            .any(|entry| RosterEntry::from_dict(&entry).spectator)
+    }
+
+    // The lobby's players plus anything the host added for the match
+    fn match_roster(&self) -> VarDictionary {
+        let mut roster = self.roster.clone();
+        for n in 0..self.match_settings.dummies {
+            roster.set(computer_player_id(n), RosterEntry::dummy(n).to_dict());
+        }
+        // Bots are numbered after the most dummies there can be
+        for n in 0..self.match_settings.bot_count(count_players(&self.roster)) {
+            roster.set(computer_player_id(MAX_DUMMIES + n), RosterEntry::bot(n).to_dict());
+        }
+        roster
+    }
+
+    fn clear_lobby_players(&mut self) {
+        for mut child in self.players_joined_container.get_children().iter_shared() {
+            child.queue_free();
+        }
+        self.roster.clear();
+        self.player_ready_dict.clear();
+        self.arena_votes.clear();
+        self.custom_arena_hashes.clear();
+    }
+
     #[rpc(authority, call_remote, reliable)]
-    fn start_game_remote(&mut self) {
+    fn start_game_remote(&mut self, settings: VarDictionary) {
         // Instantiate the game scene
-        let game = load::<PackedScene>("res://game.tscn").instantiate_as::<Game>();
+        let mut game = load::<PackedScene>("res://game.tscn").instantiate_as::<Game>();
+        game.bind_mut().set_match_settings(MatchSettings::from_dict(&settings));
+        game.bind_mut().set_spectator_only(self.is_spectating());
 
         self.base_mut()
             .get_tree()
@@ -204,7 +746,9 @@ impl Lobby {
     fn start_game_authority(&mut self) {
         // Start the game, and also kick off the player spawner
         let mut game = load::<PackedScene>("res://game.tscn").instantiate_as::<Game>();
-        game.bind_mut().initialize_authority(&self.player_names_dict);
+        game.bind_mut().set_match_settings(self.match_settings.clone());
+        game.bind_mut().set_spectator_only(self.is_spectating());
+        game.bind_mut().initialize_authority(&self.match_roster());
 
         game.bind_mut()
             .get_pause_menu()
@@ -216,6 +760,14 @@ impl Lobby {
                 this.base_mut().rpc("end_game_to_lobby", &[]);
             });
 
+        game.signals()
+            .match_over()
+            .builder()
+            .flags(ConnectFlags::DEFERRED)
+            .connect_other_mut(&self.to_gd(), |this| {
+                this.base_mut().rpc("end_game_to_lobby", &[]);
+            });
+
         self.base_mut()
             .get_tree()
             .unwrap()
@@ -229,6 +781,7 @@ impl Lobby {
     fn end_game_to_lobby(&mut self) {
         // Everyone ends the game and return to the lobby
         if self.base().has_node("/root/Game") {
+            self.record_match_stats();
             self.base().get_node_as::<Node>("/root/Game").queue_free();
             // Free the mouse cursor
             let mut input = Input::singleton();
@@ -237,6 +790,16 @@ impl Lobby {
         self.base_mut().show();
         self.show_lobby();
 
+        // Everyone has to ready up again for the next round
+        let ids: Vec<i64> = self.player_ready_dict.keys_shared().typed::<i64>().collect();
+        for id in ids {
+            self.player_ready_dict.set(id, false);
+            if let Some(mut lobby_player) = self.find_lobby_player(id) {
+                lobby_player.bind_mut().set_player_ready(false);
+            }
+        }
+        self.update_start_button();
+
         self.set_status("Game ended", false);
     }
 
@@ -244,6 +807,7 @@ impl Lobby {
         if self.base().has_node("/root/Game") {
             // Erase immediately, otherwise network might show
             // errors (this is why we connected deferred above).
+            self.record_match_stats();
             self.base().get_node_as::<Node>("/root/Game").free();
             self.base_mut().show();
             // Free the mouse cursor
@@ -254,6 +818,7 @@ impl Lobby {
         let mut multiplayer = self.base().get_multiplayer().unwrap();
         multiplayer.set_multiplayer_peer(Gd::null_arg()); // Remove peer.
 
+        self.clear_lobby_players();
         self.hide_lobby();
 
         self.set_status(with_error, false);
@@ -261,10 +826,15 @@ impl Lobby {
 
     fn on_host_btn_pressed(&mut self) {
         self.save_config();
+        self.profile.display_name = self.name_input.get_text();
+        self.profile.save();
         let mut peer = ENetMultiplayerPeer::new_gd();
         self.peer = Some(peer.clone());
-        // Set a maximum of ... let's say 10 players.
-        let err = peer.create_server_ex(DEFAULT_PORT).max_clients(9).done();
+        let network = self.settings.bind().network().clone();
+        let err = peer
+            .create_server_ex(network.port as i32)
+            .max_clients(network.max_clients as i32)
+            .done();
         if err != Error::OK {
             // Is another server running?
             self.set_status("Can't host, address in use.", false);
@@ -285,15 +855,108 @@ impl Lobby {
             .unwrap()
             .set_title(&format!("{application_name}: Server"));
         // Only show hosting instructions when relevant.
+        self.port_forward_label.set_text(&format!(
+            "If you want non-LAN clients to connect,\n\
+             make sure the port {} in UDP\n\
+             is forwarded on your router.",
+            network.port,
+        ));
         self.port_forward_label.set_visible(true);
         self.find_public_ip_button.set_visible(true);
-        let name = self.name_input.get_text();
-        self.add_new_lobby_player(
-            peer.get_unique_id() as i64,
-            &name,
-            true
-        );
-        self.player_names_dict.set(1 as i64, name);
+        self.start_game_button.set_visible(true);
+        self.match_settings_panel.bind_mut().set_editable(true);
+        self.add_local_players(peer.get_unique_id() as i64);
+        self.player_ready_dict.set(1_i64, false);
+        self.update_start_button();
+    }
+
+    // Host a lobby nobody else can join, without opening a port
+    fn on_practice_btn_pressed(&mut self) {
+        self.save_config();
+        self.profile.display_name = self.name_input.get_text();
+        self.profile.save();
+        self.peer = None;
+        let peer = OfflineMultiplayerPeer::new_gd();
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        multiplayer.set_multiplayer_peer(&peer);
+        self.show_lobby();
+        let application_name = ProjectSettings::singleton()
+            .get_setting("application/config/name")
+            .to_string();
+        self.base_mut()
+            .get_window()
+            .unwrap()
+            .set_title(&format!("{application_name}: Practice"));
+        self.port_forward_label.set_visible(false);
+        self.find_public_ip_button.set_visible(false);
+        self.start_game_button.set_visible(true);
+        self.match_settings_panel.bind_mut().set_editable(true);
+        self.add_local_players(peer.get_unique_id() as i64);
+        // Nobody to wait for
+        self.player_ready_dict.set(1_i64, true);
+        for mut lobby_player in self.lobby_players_of_peer(1) {
+            lobby_player.bind_mut().set_player_ready(true);
+        }
+        self.update_start_button();
+    }
+
+    // Watch a saved match. Like practice there's nobody to talk to,
+    // but the game still expects a peer.
+    fn start_replay(&mut self, path: GString) {
+        let Some(replay) = Replay::load(&path) else {
+            self.set_status("Can't read that replay.", false);
+            return;
+        };
+        self.peer = None;
+        let peer = OfflineMultiplayerPeer::new_gd();
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        multiplayer.set_multiplayer_peer(&peer);
+
+        let mut game = load::<PackedScene>("res://game.tscn").instantiate_as::<Game>();
+        game.bind_mut().set_replay(replay);
+        game.bind_mut()
+            .get_pause_menu()
+            .unwrap()
+            .signals()
+            .exit_pressed()
+            .builder()
+            .flags(ConnectFlags::DEFERRED)
+            .connect_other_mut(&self.to_gd(), |this| {
+                this.end_game("");
+            });
+
+        self.base_mut()
+            .get_tree()
+            .unwrap()
+            .get_root()
+            .unwrap()
+            .add_child(&game);
+        self.base_mut().hide();
+    }
+
+    fn open_arena_editor(&mut self) {
+        let editor = load::<PackedScene>("res://editor/arena_editor.tscn")
+            .instantiate_as::<ArenaEditor>();
+        let mut closing = editor.clone();
+        editor
+            .signals()
+            .closed()
+            .builder()
+            .flags(ConnectFlags::DEFERRED)
+            .connect_other_mut(&self.to_gd(), move |this| {
+                closing.queue_free();
+                // Newly saved arenas should be pickable straight away
+                this.match_settings_panel.bind_mut().refresh_custom_arenas();
+                this.base_mut().show();
+            });
+
+        self.base_mut()
+            .get_tree()
+            .unwrap()
+            .get_root()
+            .unwrap()
+            .add_child(&editor);
+        self.base_mut().hide();
     }
 
     fn on_join_btn_pressed(&mut self) {
@@ -303,10 +966,15 @@ impl Lobby {
             self.set_status("IP address is invalid.", false);
             return;
         }
+        self.profile.display_name = self.name_input.get_text();
+        self.profile.add_recent_server(&ip);
+        self.profile.save();
+        self.refresh_server_list();
 
         let mut peer = ENetMultiplayerPeer::new_gd();
         self.peer = Some(peer.clone());
-        peer.create_client(&ip, DEFAULT_PORT);
+        let port = self.settings.bind().network().port;
+        peer.create_client(&ip, port as i32);
         peer.get_host()
             .unwrap()
             .compress(CompressionMode::RANGE_CODER);
@@ -321,35 +989,94 @@ impl Lobby {
             .get_window()
             .unwrap()
             .set_title(&format!("{application_name}: Client"));
-        self.add_new_lobby_player(
-            peer.get_unique_id() as i64,
-            &self.name_input.get_text(),
-            true
-        );
+        self.start_game_button.set_visible(false);
+        self.match_settings_panel.bind_mut().set_editable(false);
+        self.add_local_players(peer.get_unique_id() as i64);
     }
 
-    fn save_config(&self) {
-        let mut config_file = ConfigFile::new_gd();
-        config_file.set_value(
-            "Lobby",
-            "prev_ip_address",
-            &Variant::from(self.address.get_text()),
-        );
-        config_file.save(&self.settings_config_name);
+    // Fold the finished match into the lifetime stats
+    fn record_match_stats(&mut self) {
+        let game = self.base().get_node_as::<Game>("/root/Game");
+        let stats = game.bind().get_local_stats();
+        self.profile.add_match_stats(&stats);
+        self.profile.save();
+    }
 
+    fn refresh_server_list(&mut self) {
+        self.servers_option.clear();
+        let servers = self.profile.known_servers();
+        let favorites = self.profile.favorite_servers.len();
+        for (i, address) in servers.iter().enumerate() {
+            // Favorites come first, split from the recent ones
+            if i == favorites && i > 0 {
+                self.servers_option.add_separator();
+            }
+            self.servers_option.add_item(address);
+        }
+        self.servers_option.select(-1);
+        self.servers_option.set_disabled(servers.is_empty());
+        self.update_favorite_button();
     }
 
+    fn update_favorite_button(&mut self) {
+        let address = self.address.get_text();
+        let text = if self.profile.is_favorite(&address) { "Unfavorite" } else { "Favorite" };
+        self.favorite_button.set_text(text);
+    }
+
+    fn save_config(&mut self) {
+        let mut lobby_settings = self.settings.bind().lobby().clone();
+        lobby_settings.prev_ip_address = self.address.get_text();
+        lobby_settings.match_settings = self.match_settings.clone();
+        let mut settings = self.settings.bind_mut();
+        settings.set_lobby(lobby_settings);
+        settings.save();
+    }
+
+    // `entries` is every player at the sender's machine
     #[rpc(any_peer, call_remote, reliable)]
-    fn register_player(&mut self, name: GString) {
+    fn register_player(&mut self, entries: VarDictionary, ready: bool) {
         let mut multiplayer = self.base().get_multiplayer().unwrap();
-        let id = multiplayer.get_remote_sender_id() as i64;
-        self.add_new_lobby_player(id, &name, false);
-        self.player_names_dict.set(id, name);
+        let peer_id = multiplayer.get_remote_sender_id() as i64;
+        for (id, entry) in entries.iter_shared().typed::<i64, VarDictionary>() {
+            // Peers can only speak for their own players
+            if peer_of(id) != peer_id {
+                continue;
+            }
+            let entry = RosterEntry::from_dict(&entry);
+            self.add_new_lobby_player(id, &card_name(&entry), entry.color, false);
+            self.roster.set(id, entry.to_dict());
+        }
+        for mut lobby_player in self.lobby_players_of_peer(peer_id) {
+            lobby_player.bind_mut().set_player_ready(ready);
+        }
+        self.player_ready_dict.set(peer_id, ready);
+        self.update_start_button();
     }
 
-    fn add_new_lobby_player(&mut self, id: i64, name: &GString, is_self: bool) {
+    fn add_new_lobby_player(&mut self, id: i64, name: &GString, color: Color, is_self: bool) {
         let mut lobby_player: Gd<LobbyPlayer> = self.lobby_player_scene.instantiate_as();
-        lobby_player.bind_mut().initialize(id, &name, is_self);
+        lobby_player.bind_mut().initialize(id, name, is_self);
+        lobby_player.bind_mut().set_color(color);
+        lobby_player.set_name(&id.to_string());
+
+        if is_self {
+            lobby_player
+                .signals()
+                .ready_toggled()
+                .connect_other(&self.to_gd(), |this, ready: bool| {
+                    this.base_mut().rpc("set_player_ready", vslice![ready]);
+                });
+            lobby_player
+                .signals()
+                .color_picked()
+                .connect_other(&self.to_gd(), |this, color: Color| {
+                    this.local_color = color;
+                    this.profile.color = color;
+                    this.profile.save();
+                    this.base_mut().rpc("set_player_color", vslice![color]);
+                });
+        }
 
         // Connect signals to the LobbyPlayer
         let multiplayer = self.base().get_multiplayer().unwrap();
@@ -359,6 +1086,9 @@ impl Lobby {
             .connect_other(&lobby_player, LobbyPlayer::on_peer_disconnected);
 
         self.players_joined_container.add_child(&lobby_player);
+        if is_self {
+            lobby_player.bind_mut().focus_ready_button();
+        }
     }
 
     fn show_lobby(&mut self) {
@@ -369,5 +1099,23 @@ impl Lobby {
     fn hide_lobby(&mut self) {
         self.join_panel.show();
         self.created_lobby.hide();
+        // Gamepads need something focused to navigate from
+        self.host_button.grab_focus();
     }
 }
+
+// What the lobby shows for someone
+fn card_name(entry: &RosterEntry) -> GString {
+    if entry.spectator {
+        GString::from(&format!("{} (Spectator)", entry.name))
+    } else {
+        entry.name.clone()
+    }
+}
+
+// Split screen guests get their own colors, spread around the wheel
+fn guest_color(color: Color, local_index: i64) -> Color {
+    let mut hsv = color.to_hsv();
+    hsv.h += local_index as f32 / MAX_LOCAL_PLAYERS as f32;
+    hsv.normalized_wrapped_h().to_rgb()
+}
@@ -0,0 +1,244 @@
+use godot::prelude::*;
+
+use crate::arena::ARENAS;
+use crate::arena_generator::GeneratorParams;
+use crate::custom_arena::is_valid_id;
+use crate::bot::BotDifficulty;
+use crate::weapon::WeaponKind;
+
+pub const MAX_DUMMIES: i64 = 8;
+// Most players bots can top a match up to
+pub const MAX_BOT_FILL: i64 = 8;
+// In seconds
+pub const MAX_RESPAWN_DELAY: f64 = 10.0;
+pub const MAX_SPAWN_PROTECTION: f64 = 10.0;
+// Kept small enough to type in, and to fit a SpinBox
+pub const MAX_ARENA_SEED: i64 = 999_999;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GameMode {
+    // The match ends when somebody has been knocked out `ko_limit` times
+    #[default]
+    FreeForAll,
+    // Players are eliminated after `ko_limit` knockouts, last one standing wins
+    Stock,
+}
+
+impl GameMode {
+    pub const ALL: [GameMode; 2] = [GameMode::FreeForAll, GameMode::Stock];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            GameMode::FreeForAll => "Free For All",
+            GameMode::Stock => "Stock Lives",
+        }
+    }
+
+    pub fn to_index(self) -> i64 {
+        Self::ALL.iter().position(|m| *m == self).unwrap_or(0) as i64
+    }
+
+    pub fn from_index(index: i64) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+}
+
+// Everything the host decides about a match before it starts.
+// Sent over the network as a dictionary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchSettings {
+    // Index into ARENAS
+    pub arena: i64,
+    // Players pick the arena between them, the host's pick breaks no-shows
+    pub arena_vote: bool,
+    // Only the seed and these go to clients, they build generated arenas themselves
+    pub arena_seed: i64,
+    pub generator: GeneratorParams,
+    // A custom arena's id, played instead of `arena` when set. Clients
+    // without a copy matching the hash get one sent over by the host.
+    pub custom_arena: String,
+    pub custom_arena_hash: i64,
+    pub mode: GameMode,
+    // Zero means no limit
+    pub ko_limit: i64,
+    // In minutes, zero means no limit
+    pub time_limit: i64,
+    pub low_gravity: bool,
+    pub fast_reload: bool,
+    pub big_blasts: bool,
+    // Blasts wear the arena's cells down until they crumble
+    pub destructible_terrain: bool,
+    pub auto_start: bool,
+    // Target dummies spawned alongside the players
+    pub dummies: i64,
+    // Bots join until there are this many players, zero means no bots
+    pub bot_fill: i64,
+    pub bot_difficulty: BotDifficulty,
+    // No kill cams or other help between knockouts
+    pub competitive: bool,
+    // Seconds between a knockout and coming back
+    pub respawn_delay: f64,
+    // Seconds after respawning that blasts do nothing, cut short by firing
+    pub spawn_protection: f64,
+    // Weapons every player carries, in switching order. Never empty.
+    pub loadout: Vec<WeaponKind>,
+}
+
+impl Default for MatchSettings {
+    fn default() -> Self {
+        Self {
+            arena: 0,
+            arena_vote: false,
+            arena_seed: 0,
+            generator: GeneratorParams::default(),
+            custom_arena: String::new(),
+            custom_arena_hash: 0,
+            mode: GameMode::FreeForAll,
+            ko_limit: 10,
+            time_limit: 5,
+            low_gravity: false,
+            fast_reload: false,
+            big_blasts: false,
+            destructible_terrain: false,
+            auto_start: false,
+            dummies: 0,
+            bot_fill: 0,
+            bot_difficulty: BotDifficulty::Normal,
+            competitive: false,
+            respawn_delay: 2.0,
+            spawn_protection: 2.0,
+            loadout: vec![WeaponKind::Bazooka],
+        }
+    }
+}
+
+impl MatchSettings {
+    pub fn to_dict(&self) -> VarDictionary {
+        vdict! {
+            "arena": self.arena,
+            "arena_vote": self.arena_vote,
+            "arena_seed": self.arena_seed,
+            "generator": self.generator.to_dict(),
+            "custom_arena": self.custom_arena.clone(),
+            "custom_arena_hash": self.custom_arena_hash,
+            "mode": self.mode.to_index(),
+            "ko_limit": self.ko_limit,
+            "time_limit": self.time_limit,
+            "low_gravity": self.low_gravity,
+            "fast_reload": self.fast_reload,
+            "big_blasts": self.big_blasts,
+            "destructible_terrain": self.destructible_terrain,
+            "auto_start": self.auto_start,
+            "dummies": self.dummies,
+            "bot_fill": self.bot_fill,
+            "bot_difficulty": self.bot_difficulty.to_index(),
+            "competitive": self.competitive,
+            "respawn_delay": self.respawn_delay,
+            "spawn_protection": self.spawn_protection,
+            "loadout": self.loadout
+                .iter()
+                .map(|kind| kind.to_index())
+                .collect::<PackedInt64Array>(),
+        }
+    }
+
+    // Missing or mistyped keys fall back to the defaults
+    pub fn from_dict(dict: &VarDictionary) -> Self {
+        let defaults = Self::default();
+        let int = |key: &str, default: i64| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<i64>().ok())
+                .unwrap_or(default)
+        };
+        let float = |key: &str, default: f64| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<f64>().ok())
+                .unwrap_or(default)
+        };
+        let flag = |key: &str, default: bool| {
+            dict.get(key)
+                .and_then(|v| v.try_to::<bool>().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            arena: int("arena", defaults.arena)
+                .clamp(0, ARENAS.len() as i64 - 1),
+            arena_vote: flag("arena_vote", defaults.arena_vote),
+            arena_seed: int("arena_seed", defaults.arena_seed)
+                .clamp(0, MAX_ARENA_SEED),
+            generator: dict.get("generator")
+                .and_then(|v| v.try_to::<VarDictionary>().ok())
+                .map(|generator| GeneratorParams::from_dict(&generator))
+                .unwrap_or(defaults.generator),
+            custom_arena: dict.get("custom_arena")
+                .and_then(|v| v.try_to::<GString>().ok())
+                .map(|id| id.to_string())
+                .filter(|id| is_valid_id(id))
+                .unwrap_or(defaults.custom_arena),
+            custom_arena_hash: int("custom_arena_hash", defaults.custom_arena_hash),
+            mode: GameMode::from_index(int("mode", defaults.mode.to_index())),
+            ko_limit: int("ko_limit", defaults.ko_limit).max(0),
+            time_limit: int("time_limit", defaults.time_limit).max(0),
+            low_gravity: flag("low_gravity", defaults.low_gravity),
+            fast_reload: flag("fast_reload", defaults.fast_reload),
+            big_blasts: flag("big_blasts", defaults.big_blasts),
+            destructible_terrain: flag("destructible_terrain", defaults.destructible_terrain),
+            auto_start: flag("auto_start", defaults.auto_start),
+            dummies: int("dummies", defaults.dummies).clamp(0, MAX_DUMMIES),
+            bot_fill: int("bot_fill", defaults.bot_fill).clamp(0, MAX_BOT_FILL),
+            bot_difficulty: BotDifficulty::from_index(
+                int("bot_difficulty", defaults.bot_difficulty.to_index())),
+            competitive: flag("competitive", defaults.competitive),
+            respawn_delay: float("respawn_delay", defaults.respawn_delay)
+                .clamp(0.0, MAX_RESPAWN_DELAY),
+            spawn_protection: float("spawn_protection", defaults.spawn_protection)
+                .clamp(0.0, MAX_SPAWN_PROTECTION),
+            loadout: dict.get("loadout")
+                .and_then(|v| v.try_to::<PackedInt64Array>().ok())
+                .map(|indices| loadout(indices.as_slice()))
+                .filter(|loadout| !loadout.is_empty())
+                .unwrap_or(defaults.loadout),
+        }
+    }
+
+    pub fn is_custom_arena(&self) -> bool {
+        !self.custom_arena.is_empty()
+    }
+
+    // How many bots a lobby of `players` gets
+    pub fn bot_count(&self, players: i64) -> i64 {
+        (self.bot_fill - players).max(0)
+    }
+
+    // Whether that many knockouts means no more respawning
+    pub fn is_out(&self, knockouts: i64) -> bool {
+        self.mode == GameMode::Stock && self.ko_limit > 0 && knockouts >= self.ko_limit
+    }
+
+    pub fn allows_kill_cam(&self) -> bool {
+        !self.competitive
+    }
+
+    pub fn gravity_scale(&self) -> f32 {
+        if self.low_gravity { 0.5 } else { 1.0 }
+    }
+
+    pub fn reload_speed_scale(&self) -> f64 {
+        if self.fast_reload { 2.0 } else { 1.0 }
+    }
+
+    pub fn knockback_scale(&self) -> f32 {
+        if self.big_blasts { 1.5 } else { 1.0 }
+    }
+}
+
+// Known weapons only, each once
+fn loadout(indices: &[i64]) -> Vec<WeaponKind> {
+    let mut loadout = Vec::new();
+    for kind in indices.iter().filter_map(|index| WeaponKind::from_index(*index)) {
+        if !loadout.contains(&kind) {
+            loadout.push(kind);
+        }
+    }
+    loadout
+}
@@ -1,10 +1,12 @@
 use godot::prelude::*;
 use godot::classes::input::MouseMode;
+use crate::settings::{Settings, ControlsSettings};
+use crate::rebind_menu::RebindMenu;
 
 #[allow(unused_imports)]
 use godot::classes::{
     Control, IControl, Button, Input, InputEvent,
-    Slider,
+    Slider, CheckBox,
 };
 
 
@@ -16,9 +18,43 @@ pub struct PauseMenu {
     #[export]
     exit_button: OnEditor<Gd<Button>>,
     #[export]
-    sensitivity_slider: OnEditor<Gd<Slider>>,
+    horizontal_sensitivity_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    vertical_sensitivity_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    invert_y_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    mouse_acceleration_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    pitch_limit_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    fov_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    master_volume_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    music_volume_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    fullscreen_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    vsync_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    stick_sensitivity_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    stick_deadzone_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    stick_curve_slider: OnEditor<Gd<Slider>>,
+    #[export]
+    aim_assist_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    kill_cam_check: OnEditor<Gd<CheckBox>>,
+    #[export]
+    controls_button: OnEditor<Gd<Button>>,
+    #[export]
+    rebind_menu: OnEditor<Gd<RebindMenu>>,
     #[export]
     paused: bool,
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
     base: Base<Control>
 }
 
@@ -40,6 +76,103 @@ impl IControl for PauseMenu {
             .pressed()
             .connect_other(&self.to_gd(), Self::unpause);
 
+        let controls = self.settings.bind().controls().clone();
+        let mut slider = self.horizontal_sensitivity_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.horizontal_sensitivity,
+            |c, value| c.horizontal_sensitivity = value);
+        let mut slider = self.vertical_sensitivity_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.vertical_sensitivity,
+            |c, value| c.vertical_sensitivity = value);
+        let mut slider = self.mouse_acceleration_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.mouse_acceleration,
+            |c, value| c.mouse_acceleration = value);
+        let mut slider = self.pitch_limit_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.pitch_limit,
+            |c, value| c.pitch_limit = value);
+        let mut slider = self.fov_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.fov,
+            |c, value| c.fov = value);
+        let mut check = self.invert_y_check.clone();
+        self.connect_controls_check(&mut check, controls.invert_y,
+            |c, on| c.invert_y = on);
+
+        let mut slider = self.stick_sensitivity_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.stick_sensitivity,
+            |c, value| c.stick_sensitivity = value);
+        let mut slider = self.stick_deadzone_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.stick_deadzone,
+            |c, value| c.stick_deadzone = value);
+        let mut slider = self.stick_curve_slider.clone();
+        self.connect_controls_slider(&mut slider, controls.stick_curve,
+            |c, value| c.stick_curve = value);
+        let mut check = self.aim_assist_check.clone();
+        self.connect_controls_check(&mut check, controls.aim_assist,
+            |c, on| c.aim_assist = on);
+
+        let audio = self.settings.bind().audio().clone();
+        self.master_volume_slider.set_value_no_signal(audio.master_volume);
+        self.master_volume_slider
+            .signals()
+            .value_changed()
+            .connect_other(&self.to_gd(), |this, value: f64| {
+                let mut audio = this.settings.bind().audio().clone();
+                audio.master_volume = value;
+                this.settings.bind_mut().set_audio(audio);
+            });
+        self.music_volume_slider.set_value_no_signal(audio.music_volume);
+        self.music_volume_slider
+            .signals()
+            .value_changed()
+            .connect_other(&self.to_gd(), |this, value: f64| {
+                let mut audio = this.settings.bind().audio().clone();
+                audio.music_volume = value;
+                this.settings.bind_mut().set_audio(audio);
+            });
+
+        let video = self.settings.bind().video().clone();
+        self.fullscreen_check.set_pressed_no_signal(video.fullscreen);
+        self.fullscreen_check
+            .signals()
+            .toggled()
+            .connect_other(&self.to_gd(), |this, on: bool| {
+                let mut video = this.settings.bind().video().clone();
+                video.fullscreen = on;
+                this.settings.bind_mut().set_video(video);
+            });
+        self.vsync_check.set_pressed_no_signal(video.vsync);
+        self.vsync_check
+            .signals()
+            .toggled()
+            .connect_other(&self.to_gd(), |this, on: bool| {
+                let mut video = this.settings.bind().video().clone();
+                video.vsync = on;
+                this.settings.bind_mut().set_video(video);
+            });
+
+        let replays = self.settings.bind().replays().clone();
+        self.kill_cam_check.set_pressed_no_signal(replays.kill_cam);
+        self.kill_cam_check
+            .signals()
+            .toggled()
+            .connect_other(&self.to_gd(), |this, on: bool| {
+                let mut replays = this.settings.bind().replays().clone();
+                replays.kill_cam = on;
+                this.settings.bind_mut().set_replays(replays);
+            });
+
+        self.controls_button
+            .signals()
+            .pressed()
+            .connect_other(&self.to_gd(), |this| {
+                this.rebind_menu.bind_mut().open();
+            });
+        // Hand focus back so a gamepad can keep navigating
+        self.rebind_menu
+            .signals()
+            .closed()
+            .connect_other(&self.to_gd(), |this| {
+                this.controls_button.grab_focus();
+            });
     }
 
     fn input(&mut self, event: Gd<InputEvent>) {
@@ -58,6 +191,8 @@ impl PauseMenu {
         self.paused = true;
         self.base_mut().set_visible(true);
         Input::singleton().set_mouse_mode(MouseMode::VISIBLE);
+        // Something has to hold focus for gamepad navigation
+        self.unpause_button.grab_focus();
     }
 
     #[func]
@@ -65,6 +200,8 @@ impl PauseMenu {
         self.paused = false;
         self.base_mut().set_visible(false);
         Input::singleton().set_mouse_mode(MouseMode::CAPTURED);
+        // Done tweaking, keep whatever was changed
+        self.settings.bind_mut().save();
         self.signals().unpause().emit();
     }
 
@@ -79,4 +216,39 @@ impl PauseMenu {
     #[signal]
     pub fn exit_pressed();
 
+    // Show the current value and write changes straight back to the Controls section
+    fn connect_controls_slider(
+        &self,
+        slider: &mut Gd<Slider>,
+        value: f64,
+        apply: fn(&mut ControlsSettings, f64),
+    ) {
+        slider.set_value_no_signal(value);
+        slider
+            .signals()
+            .value_changed()
+            .connect_other(&self.to_gd(), move |this, value: f64| {
+                let mut controls = this.settings.bind().controls().clone();
+                apply(&mut controls, value);
+                this.settings.bind_mut().set_controls(controls);
+            });
+    }
+
+    fn connect_controls_check(
+        &self,
+        check: &mut Gd<CheckBox>,
+        pressed: bool,
+        apply: fn(&mut ControlsSettings, bool),
+    ) {
+        check.set_pressed_no_signal(pressed);
+        check
+            .signals()
+            .toggled()
+            .connect_other(&self.to_gd(), move |this, on: bool| {
+                let mut controls = this.settings.bind().controls().clone();
+                apply(&mut controls, on);
+                this.settings.bind_mut().set_controls(controls);
+            });
+    }
+
 }
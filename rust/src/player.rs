@@ -5,17 +5,35 @@ use godot::classes::{RigidBody3D, IRigidBody3D, CharacterBody3D, ICharacterBody3
     Input, InputEvent, Camera3D, InputEventMouseMotion, MeshInstance3D, Timer,
     InputEventAction, Area3D, IArea3D, CollisionShape3D,
     Label3D,
-    AnimationPlayer,
+    AnimationPlayer, StandardMaterial3D,
 };
-use godot::classes::input::MouseMode;
 use godot::classes::ProjectSettings;
-use std::f32::consts::{TAU, PI};
+use godot::classes::node::ProcessMode;
+use std::f32::consts::TAU;
 use godot::global::{wrapf};
 use num::clamp;
 
 use crate::explosion::Explosion;
-use crate::rocket::Rocket;
+use crate::weapon::WeaponSlot;
 use crate::game::Game;
+use crate::settings::{Settings, SettingsSection, ControlsSettings};
+use crate::input_bindings::{ANY_DEVICE, NO_DEVICE, BOT_DEVICE};
+use crate::input_source::{
+    InputCommand, InputSource, NetworkSource, PlayerView, RecordedSource,
+    device_source, save_commands,
+};
+use crate::bot::{Bot, BotDifficulty, cast};
+use crate::roster::local_index_of;
+use crate::replay::PlayerState;
+use crate::arena::{default_bounds, out_of_bounds_reason};
+use crate::kill_volume::{KillVolume, KnockoutReason};
+
+// Group every Player joins, for anything that needs to find them all
+pub const PLAYERS_GROUP: &str = "players";
+// Seconds a blast still counts towards a knockout, for crediting whoever made it
+const CREDIT_SECONDS: f64 = 5.0;
+// How far below a player there can be ground to put a mine on
+const MINE_REACH: f32 = 3.0;
 
 #[derive(GodotClass)]
 #[class(base=Area3D, init)]
@@ -33,22 +51,39 @@ pub struct Player {
     #[var]
     #[init(node="NameLabel")]
     name_label: OnReady<Gd<Label3D>>,
-    #[init(val=OnReady::from_loaded("res://rocket/rocket.tscn"))]
-    rocket_scene: OnReady<Gd<PackedScene>>,
     #[init(node="/root/Game")]
     game_root: OnReady<Gd<Game>>,
-    #[export]
-    #[init(val=15.0)]
-    rocket_init_vel: f32,
-    #[export]
-    #[init(val=3.0)]
-    rocket_init_roll_vel: f32,
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
+    // Whoever's blast last hit us, and for how many more seconds that counts
+    last_hit: Option<(i64, f64)>,
     ragdoll: bool,
     is_out_of_bounds: bool,
     #[var]
     ko_count: i32,
-    #[init(val=true)]
-    bazooka_loaded: bool,
+    // The host's loadout, and which of it is in hand
+    weapons: Vec<WeaponSlot>,
+    weapon: usize,
+    #[init(val=1.0)]
+    reload_speed: f64,
+    #[init(val=1.0)]
+    knockback_scale: f32,
+    // Leaving these is a knockout
+    #[init(val=default_bounds())]
+    bounds: Aabb,
+    // Seconds left shrugging off blasts after a respawn
+    spawn_protection: f64,
+    #[init(val=Color::WHITE)]
+    color: Color,
+    player_id: i64,
+    // Whatever drives this player, if it's ours
+    input_source: Option<Box<dyn InputSource>>,
+    // Every command polled since recording started
+    input_recording: Option<Vec<InputCommand>>,
+    // The peer sending our commands over, when they aren't made here
+    input_peer: Option<i64>,
+    // Commands we send over for this player, when another machine runs it
+    remote_source: Option<Box<dyn InputSource>>,
     init_pos: Vector3,
     init_rot: Vector3,
     base: Base<Area3D>
@@ -56,7 +91,22 @@ pub struct Player {
 
 #[godot_api]
 impl IArea3D for Player {
-    fn physics_process(&mut self, _delta: f32) {
+    fn physics_process(&mut self, delta: f32) {
+        if self.base().is_multiplayer_authority() {
+            self.run_input(delta);
+        } else {
+            self.send_input(delta);
+        }
+        self.spawn_protection = (self.spawn_protection - delta as f64).max(0.0);
+        for slot in self.weapons.iter_mut() {
+            slot.tick(delta as f64);
+        }
+        if let Some((_, seconds)) = self.last_hit.as_mut() {
+            *seconds -= delta as f64;
+            if *seconds <= 0.0 {
+                self.last_hit = None;
+            }
+        }
         if self.ragdoll {
             self.player_kinematic_body.set_position(
                 self.player_dynamic_body.get_position()
@@ -80,29 +130,16 @@ impl IArea3D for Player {
             let args = vslice![self.ragdoll];
             self.base_mut().rpc("sync_ragdoll", args);
             // Out of bounds condition
-            if !self.is_out_of_bounds && pos.y < -10.0 {
-                let gd_ref = self.to_gd();
-                self.is_out_of_bounds = true;
-                self.ko_count += 1;
-                self.signals().out_of_bounds().emit(&gd_ref);
+            if let Some(reason) = out_of_bounds_reason(self.bounds, pos) {
+                self.knock_out(reason);
             }
         }
     }
 
     fn input(&mut self, event: Gd<InputEvent>) {
-        if self.base().is_multiplayer_authority() 
-                && Input::singleton().get_mouse_mode() == MouseMode::CAPTURED {
-            if event.is_action_pressed("ragdoll") {
-                godot_print!("ragdoll activated");
-                self.begin_ragdoll();
-            }
-
-            if !self.ragdoll && event.is_action_pressed("shoot") {
-                let rocket_pos = self.player_kinematic_body.bind().get_aim_position();
-                let rocket_rot = self.player_kinematic_body.bind().get_aim_rotation();
-                let base_vel = self.player_kinematic_body.get_velocity();
-                self.base_mut().rpc("shoot_rocket", vslice![rocket_pos, rocket_rot, base_vel]);
-            }
+        if self.base().is_multiplayer_authority()
+                && let Some(source) = self.input_source.as_mut() {
+            source.handle_event(&event);
         }
     }
 
@@ -117,35 +154,35 @@ impl IArea3D for Player {
         self.player_kinematic_body.set_rotation(rot);
 
         self.end_ragdoll();
+        self.set_device(ANY_DEVICE);
+
+        if let Some(arena) = self.game_root.bind().get_arena() {
+            self.bounds = arena.bounds();
+        }
+
+        // Apply the host's mutators
+        let settings = self.game_root.bind().get_match_settings().clone();
+        self.knockback_scale = settings.knockback_scale();
+        self.weapons = settings.loadout.iter().map(|kind| WeaponSlot::new(*kind)).collect();
+        self.reload_speed = settings.reload_speed_scale();
+        self.animation_player.set_speed_scale(settings.reload_speed_scale() as f32);
+        self.player_kinematic_body.bind_mut().scale_gravity(settings.gravity_scale());
+        let gravity_scale = self.player_dynamic_body.get_gravity_scale();
+        self.player_dynamic_body.set_gravity_scale(gravity_scale * settings.gravity_scale());
 
         self.ragdoll_timer
             .signals()
             .timeout()
             .connect_other(&self.to_gd(), Self::end_ragdoll);
 
-        self.animation_player
-            .signals()
-            .animation_finished()
-            .connect_other(&self.to_gd(), |this, anim_name: StringName| {
-                if anim_name == "reload".into() { 
-                    this.bazooka_loaded = true;
-                }
-            });
-
         self.signals()
             .area_entered()
             .connect_self(Self::on_area_entered);
 
         if self.base().is_multiplayer_authority() {
             self.name_label.set_visible(false);
-            self.game_root
-                .signals()
-                .mouse_sensitivity_changed()
-                .connect_other(
-                    &*self.player_kinematic_body,
-                    PlayerKinematicBody::on_mouse_sensitivity_changed
-                );
         }
+        self.base_mut().add_to_group(PLAYERS_GROUP);
     }    
 }
 
@@ -154,13 +191,28 @@ impl Player {
 
     #[func]
     pub fn on_area_entered(&mut self, area: Gd<Area3D>) {
+        let area = match area.try_cast::<KillVolume>() {
+            Ok(volume) => {
+                let reason = volume.bind().reason();
+                self.knock_out(reason);
+                return;
+            }
+            Err(area) => area,
+        };
+        if self.is_spawn_protected() {
+            return;
+        }
         if let Ok(explosion) = area.try_cast::<Explosion>() {
             if explosion.bind().get_time() < 0.2 {
                 self.begin_ragdoll();
+                let owner_id = explosion.bind().get_owner_id();
+                if owner_id != 0 && owner_id != self.player_id {
+                    self.last_hit = Some((owner_id, CREDIT_SECONDS));
+                }
                 let radius_vec = self.player_dynamic_body.get_position()
                     - explosion.get_position();
                 let new_velocity =
-                    radius_vec.normalized_or_zero() * 20.0;
+                    radius_vec.normalized_or_zero() * 20.0 * self.knockback_scale;
                     // + Vector3::UP * 15.0;
                 self.player_dynamic_body.set_linear_velocity(new_velocity);
             }
@@ -168,10 +220,22 @@ impl Player {
     }
 
     #[signal]
-    pub fn out_of_bounds(&mut player: Gd<Player>);
+    pub fn out_of_bounds(&mut player: Gd<Player>, reason: KnockoutReason);
 
+    // Only counts on the machine the player belongs to, once per life
+    fn knock_out(&mut self, reason: KnockoutReason) {
+        if !self.base().is_multiplayer_authority() || self.is_out_of_bounds {
+            return;
+        }
+        let gd_ref = self.to_gd();
+        self.is_out_of_bounds = true;
+        self.ko_count += 1;
+        self.signals().out_of_bounds().emit(&gd_ref, reason);
+    }
+
+    // Blasts can't touch them for `protection` seconds, or until they fire
     #[rpc(authority, call_local)]
-    pub fn respawn(&mut self, pos: Vector3) {
+    pub fn respawn(&mut self, pos: Vector3, protection: f64) {
         // Reset to a given spawn point
         self.base_mut().set_position(pos);
         self.player_dynamic_body.set_position(pos);
@@ -183,6 +247,26 @@ impl Player {
         self.player_dynamic_body.set_angular_velocity(Vector3::ZERO);
         self.end_ragdoll();
         self.is_out_of_bounds = false;
+        self.spawn_protection = protection;
+        self.last_hit = None;
+        let player_id = self.player_id;
+        if let Some(mut mine_layer) = self.game_root.bind().get_mine_layer() {
+            mine_layer.bind_mut().refill(player_id);
+        }
+    }
+
+    // Who gets the credit for knocking us out, if anyone
+    pub fn last_attacker(&self) -> Option<i64> {
+        self.last_hit.map(|(player_id, _)| player_id)
+    }
+
+    pub fn is_spawn_protected(&self) -> bool {
+        self.spawn_protection > 0.0
+    }
+
+    #[rpc(authority, call_local, reliable)]
+    pub fn eliminate(&mut self) {
+        self.base_mut().queue_free();
     }
 
     fn begin_ragdoll(&mut self) {
@@ -211,33 +295,242 @@ impl Player {
         }
     }
 
-    #[rpc(authority, call_local, reliable)]
-    pub fn shoot_rocket(&mut self, position: Vector3, rotation: Vector3,
-                        base_velocity: Vector3) {
-        if self.bazooka_loaded {
-            let mut rocket: Gd<Rocket> = self.rocket_scene.instantiate_as();
-            rocket.set_multiplayer_authority(
-                self.base().get_multiplayer_authority()
-            );
-            rocket.set_position(position);
-            rocket.set_rotation(rotation);
-            let rocket_basis = rocket.get_basis();
-            rocket.set_linear_velocity(
-                rocket_basis * Vector3::FORWARD * self.rocket_init_vel
-                + base_velocity
-            );
-            rocket.set_angular_velocity(
-                rocket_basis * Vector3::FORWARD * self.rocket_init_roll_vel
-            );
-            self.base_mut()
-                .get_tree()
-                .unwrap()
-                .get_root()
-                .unwrap()
-                .add_child(&rocket);
+    fn fire(&mut self) {
+        if !self.weapons.get(self.weapon).is_some_and(WeaponSlot::can_fire) {
+            return;
+        }
+        let rocket_pos = self.player_kinematic_body.bind().get_aim_position();
+        let rocket_rot = self.player_kinematic_body.bind().get_aim_rotation();
+        let base_vel = self.player_kinematic_body.get_velocity();
+        // The profile only tracks the first local player
+        if local_index_of(self.player_id) == 0 {
+            self.game_root.bind_mut().record_rocket_fired();
+        }
+        let weapon = self.weapon as i64;
+        self.base_mut().rpc("shoot", vslice![rocket_pos, rocket_rot, base_vel, weapon]);
+    }
+
+    fn switch_weapon(&mut self) {
+        if !self.weapons.is_empty() {
+            self.weapon = (self.weapon + 1) % self.weapons.len();
+        }
+    }
+
+    // Hand the controls to a bot. `waypoints` are spots worth walking to.
+    // It sticks to the first weapon in the loadout.
+    pub fn make_bot(&mut self, difficulty: BotDifficulty, waypoints: Vec<Vector3>) {
+        self.weapon = 0;
+        let Some(weapon) = self.weapons.first().map(WeaponSlot::weapon) else {
+            return;
+        };
+        // The flight model lives on the projectile, so ask one
+        let Some(projectile) = weapon.bind().projectile() else {
+            return;
+        };
+        let flight = projectile.flight();
+        projectile.free();
+        let launch_speed = weapon.bind().get_launch_speed();
+        self.set_device(BOT_DEVICE);
+        self.set_input_source(Box::new(
+            Bot::new(difficulty, flight, launch_speed, waypoints)
+        ));
+    }
+
+    pub fn set_input_source(&mut self, source: Box<dyn InputSource>) {
+        self.input_source = Some(source);
+    }
 
-            self.bazooka_loaded = false;
-            self.animation_player.play_ex().name("reload").done();
+    fn run_input(&mut self, delta: f32) {
+        let view = self.view();
+        let world = self.to_gd().upcast::<Node3D>();
+        let Some(source) = self.input_source.as_mut() else {
+            return;
+        };
+        let command = source.poll(&view, &world, delta);
+        if let Some(recording) = self.input_recording.as_mut() {
+            recording.push(command);
+        }
+        self.apply_command(command);
+    }
+
+    // Only while we're driving a player another machine runs
+    fn send_input(&mut self, delta: f32) {
+        let view = self.view();
+        let world = self.to_gd().upcast::<Node3D>();
+        let Some(source) = self.remote_source.as_mut() else {
+            return;
+        };
+        let command = source.poll(&view, &world, delta).to_dict();
+        let owner = self.base().get_multiplayer_authority() as i64;
+        self.base_mut().rpc_id(owner, "receive_input_command", vslice![command]);
+    }
+
+    // Let `peer` drive this player from their machine, while this one
+    // keeps running it. Only works on the machine the player belongs to.
+    #[func]
+    pub fn take_input_from_peer(&mut self, peer: i64) {
+        if !self.base().is_multiplayer_authority() {
+            return;
+        }
+        self.input_peer = Some(peer);
+        self.set_input_source(Box::new(NetworkSource::default()));
+        self.base_mut().rpc_id(peer, "send_input_to_owner", &[]);
+    }
+
+    #[rpc(authority, call_remote, reliable)]
+    fn send_input_to_owner(&mut self) {
+        let sensitivity = self.player_kinematic_body.bind().get_base_mouse_sensitivity();
+        let settings = (*self.settings).clone();
+        self.remote_source = Some(device_source(ANY_DEVICE, settings, sensitivity as f32));
+    }
+
+    #[rpc(any_peer, call_remote, unreliable_ordered)]
+    fn receive_input_command(&mut self, command: VarDictionary) {
+        let sender = self.base().get_multiplayer().unwrap().get_remote_sender_id() as i64;
+        if !self.base().is_multiplayer_authority() || self.input_peer != Some(sender) {
+            return;
+        }
+        if let Some(source) = self.input_source.as_mut() {
+            source.queue(InputCommand::from_dict(&command));
+        }
+    }
+
+    fn apply_command(&mut self, command: InputCommand) {
+        {
+            let mut body = self.player_kinematic_body.bind_mut();
+            body.turn(command.look.x, command.look.y);
+            body.set_command(command);
+        }
+        if command.ragdoll {
+            self.begin_ragdoll();
+        }
+        if command.switch_weapon {
+            self.switch_weapon();
+        }
+        let triggered = self.weapons
+            .get(self.weapon)
+            .is_some_and(|slot| slot.is_triggered(&command));
+        if triggered && !self.ragdoll {
+            self.fire();
+        }
+        if command.secondary && !self.ragdoll {
+            self.place_mine();
+        }
+    }
+
+    // On the ground under us. The host decides whether we get to.
+    fn place_mine(&mut self) {
+        if self.is_out_of_bounds {
+            return;
+        }
+        let view = self.view();
+        let world = self.to_gd().upcast::<Node3D>();
+        let Some(ground) = cast(
+            &world, view.position, view.position + Vector3::DOWN * MINE_REACH, &view.exclude,
+        ) else {
+            return;
+        };
+        let player_id = self.player_id;
+        if let Some(mut mine_layer) = self.game_root.bind().get_mine_layer() {
+            mine_layer.bind_mut().place(player_id, ground);
+        }
+    }
+
+    fn view(&self) -> PlayerView {
+        let body = self.player_kinematic_body.clone();
+        let camera = body.bind().get_camera();
+        let mut exclude = Array::new();
+        exclude.push(body.get_rid());
+        exclude.push(self.player_dynamic_body.get_rid());
+        PlayerView {
+            own_id: self.base().instance_id(),
+            position: body.get_global_position(),
+            velocity: body.get_velocity(),
+            basis: body.get_global_basis(),
+            eye: camera.get_global_position(),
+            aim: -camera.get_global_basis().col_c(),
+            muzzle: body.bind().get_aim_position(),
+            on_floor: body.is_on_floor(),
+            loaded: self.weapons.get(self.weapon).is_some_and(WeaponSlot::can_fire),
+            exclude,
+        }
+    }
+
+    // Take commands from a file saved by `save_input_recording`
+    #[func]
+    pub fn play_input_file(&mut self, path: GString) -> bool {
+        let Some(source) = RecordedSource::load(&path) else {
+            return false;
+        };
+        self.set_input_source(Box::new(source));
+        true
+    }
+
+    // Only does anything while the current source is a queue,
+    // like a target dummy's
+    #[func]
+    pub fn queue_input_command(&mut self, command: VarDictionary) {
+        if let Some(source) = self.input_source.as_mut() {
+            source.queue(InputCommand::from_dict(&command));
+        }
+    }
+
+    #[func]
+    pub fn start_input_recording(&mut self) {
+        self.input_recording = Some(Vec::new());
+    }
+
+    #[func]
+    pub fn save_input_recording(&mut self, path: GString) -> bool {
+        let Some(commands) = self.input_recording.take() else {
+            return false;
+        };
+        save_commands(&path, &commands)
+    }
+
+    // The owner already checked the weapon could fire
+    #[rpc(authority, call_local, reliable)]
+    pub fn shoot(&mut self, position: Vector3, rotation: Vector3,
+                 base_velocity: Vector3, weapon: i64) {
+        let Some(slot) = self.weapons.get_mut(weapon as usize) else {
+            return;
+        };
+        let weapon = slot.weapon();
+        let Some(mut projectile) = weapon.bind().projectile() else {
+            return;
+        };
+        let emptied = slot.use_round(self.reload_speed);
+        projectile.set_shooter(self.player_id, self.color);
+        let mut body = projectile.body();
+        body.set_multiplayer_authority(
+            self.base().get_multiplayer_authority()
+        );
+        body.set_position(position);
+        body.set_rotation(rotation);
+        let body_basis = body.get_basis();
+        body.set_linear_velocity(
+            body_basis * Vector3::FORWARD * weapon.bind().get_launch_speed()
+            + base_velocity
+        );
+        body.set_angular_velocity(
+            body_basis * Vector3::FORWARD * weapon.bind().get_spin()
+        );
+        self.base_mut()
+            .get_tree()
+            .unwrap()
+            .get_root()
+            .unwrap()
+            .add_child(&body);
+
+        self.spawn_protection = 0.0;
+        if emptied {
+            // The animation takes a second at normal speed
+            let speed = 1.0 / weapon.bind().get_reload_time().max(0.01);
+            self.animation_player
+                .play_ex()
+                .name("reload")
+                .custom_speed(speed as f32)
+                .done();
         }
     }
 
@@ -245,6 +538,109 @@ impl Player {
     pub fn set_camera_current(&mut self, enabled: bool) {
         self.player_kinematic_body.bind_mut().set_camera_current(enabled);
     }
+
+    pub fn get_camera(&self) -> Gd<Camera3D> {
+        self.player_kinematic_body.bind().get_camera()
+    }
+
+    pub fn get_player_id(&self) -> i64 {
+        self.player_id
+    }
+
+    pub fn get_color(&self) -> Color {
+        self.color
+    }
+
+    // Where this player is and how it's posed, for replays
+    pub fn replay_state(&self) -> PlayerState {
+        let rotation = if self.ragdoll {
+            self.player_dynamic_body.get_rotation()
+        } else {
+            self.player_kinematic_body.get_rotation()
+        };
+        PlayerState {
+            position: self.player_dynamic_body.get_position(),
+            rotation,
+            pitch: self.get_camera().get_rotation().x,
+            ragdoll: self.ragdoll,
+        }
+    }
+
+    // Stop simulating, so a replay can move this one around
+    pub fn make_puppet(&mut self) {
+        self.input_source = None;
+        self.base_mut().set_process_mode(ProcessMode::DISABLED);
+        self.player_kinematic_body.set_process_mode(ProcessMode::DISABLED);
+        self.player_dynamic_body.set_process_mode(ProcessMode::DISABLED);
+        self.name_label.set_visible(true);
+        // Bots and recorders shouldn't see it
+        self.base_mut().remove_from_group(PLAYERS_GROUP);
+    }
+
+    pub fn show_replay_state(&mut self, state: &PlayerState) {
+        if state.ragdoll != self.ragdoll {
+            if state.ragdoll {
+                self.begin_ragdoll();
+            } else {
+                self.end_ragdoll();
+            }
+        }
+        self.base_mut().set_position(state.position);
+        self.player_dynamic_body.set_position(state.position);
+        self.player_kinematic_body.set_position(state.position);
+        if state.ragdoll {
+            self.player_dynamic_body.set_rotation(state.rotation);
+        } else {
+            self.player_kinematic_body.bind_mut().sync_state(
+                false, state.position, Vector3::ZERO,
+                state.rotation, Vector3::new(state.pitch, 0.0, 0.0));
+        }
+    }
+
+    pub fn set_player_id(&mut self, player_id: i64) {
+        self.player_id = player_id;
+    }
+
+    // Only read input from one device, for split screen
+    pub fn set_device(&mut self, device: i64) {
+        self.player_kinematic_body.bind_mut().set_device(device);
+        // Nobody is looking through this one's eyes
+        if device == NO_DEVICE || device == BOT_DEVICE {
+            self.name_label.set_visible(true);
+        }
+        let sensitivity = self.player_kinematic_body.bind().get_base_mouse_sensitivity();
+        let settings = (*self.settings).clone();
+        self.set_input_source(device_source(device, settings, sensitivity as f32));
+    }
+
+    // Show the name to every split screen camera but our own
+    pub fn show_name_on_layer(&mut self, layer_mask: u32) {
+        self.name_label.set_layer_mask(layer_mask);
+        self.name_label.set_visible(true);
+    }
+
+    // Tint the body, name and rocket trail with the lobby color
+    #[func]
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+        self.name_label.set_modulate(color);
+        self.player_kinematic_body.bind_mut().set_color(color);
+        let mut dynamic_mesh = self.player_dynamic_body
+            .get_node_as::<MeshInstance3D>("PlayerMesh");
+        tint_mesh(&mut dynamic_mesh, color);
+    }
+}
+
+// Multiplies the mesh's own material by the given color
+fn tint_mesh(mesh_instance: &mut Gd<MeshInstance3D>, color: Color) {
+    let material = mesh_instance
+        .get_mesh()
+        .and_then(|mesh| mesh.surface_get_material(0))
+        .and_then(|material| material.duplicate())
+        .and_then(|material| material.try_cast::<StandardMaterial3D>().ok());
+    let mut material = material.unwrap_or_else(StandardMaterial3D::new_gd);
+    material.set_albedo(color);
+    mesh_instance.set_material_override(&material);
 }
 
 
@@ -256,6 +652,7 @@ pub struct PlayerKinematicBody {
     mesh: OnReady<Gd<MeshInstance3D>>,
     rocket_mesh: OnReady<Gd<MeshInstance3D>>,
     parent_player: OnReady<Gd<Player>>,
+    settings: OnReady<Gd<Settings>>,
     jumping: bool,
     #[export]
     gravity: Vector3,
@@ -269,7 +666,10 @@ pub struct PlayerKinematicBody {
     max_speed: f32,
     #[export]
     base_mouse_sensitivity: f64,
-    mouse_sensitivity_mult: f64,
+    controls: ControlsSettings,
+    device: i64,
+    // This tick's movement, set by the Player before we run
+    command: InputCommand,
     base: Base<CharacterBody3D>
 }
 
@@ -284,6 +684,7 @@ impl ICharacterBody3D for PlayerKinematicBody {
             mesh: OnReady::from_node("PlayerMesh"),
             rocket_mesh: OnReady::from_node("Bazooka/RocketMesh"),
             parent_player: OnReady::from_node(".."),
+            settings: OnReady::from_node("/root/Settings"),
             jumping: true,
             gravity: project_settings.get_setting("physics/3d/default_gravity").to::<f32>() *
                 project_settings.get_setting("physics/3d/default_gravity_vector").to::<Vector3>(),
@@ -292,12 +693,23 @@ impl ICharacterBody3D for PlayerKinematicBody {
             deaccel: 12.0,
             max_speed: 4.0,
             base_mouse_sensitivity: 0.007,
-            mouse_sensitivity_mult: 0.5,
+            controls: ControlsSettings::default(),
+            device: ANY_DEVICE,
+            command: InputCommand::default(),
             base,
         }
     }
 
     fn ready(&mut self) {
+        self.apply_controls_settings();
+        self.settings
+            .signals()
+            .section_changed()
+            .connect_other(&self.to_gd(), |this, section: GString| {
+                if section.to_string() == ControlsSettings::NAME {
+                    this.apply_controls_settings();
+                }
+            });
     }
 
     fn physics_process(&mut self, delta: f32) {
@@ -307,21 +719,9 @@ impl ICharacterBody3D for PlayerKinematicBody {
             let mut vertical_velocity = velocity.y;
             let mut horizontal_velocity = Vector3::new(velocity.x, 0.0, velocity.z);
 
-            // Declare input commands
-            let movement_vec2: Vector2;
-            let jump_attempt: bool;
-
-            // Get inputs, if not paused
-            let input = Input::singleton();
-            if input.get_mouse_mode() == MouseMode::CAPTURED {
-                // Not paused
-                movement_vec2 = input.get_vector("left", "right", "forward", "back");
-                jump_attempt = input.is_action_pressed("jump");
-            } else {
-                // Paused
-                movement_vec2 = Vector2::ZERO;
-                jump_attempt = false;
-            }
+            // Whatever the input source asked for this tick
+            let movement_vec2 = self.command.movement;
+            let jump_attempt = self.command.jump;
 
             let basis = self.base().get_basis();
             let mut movement_direction =
@@ -368,31 +768,6 @@ impl ICharacterBody3D for PlayerKinematicBody {
 
         self.base_mut().move_and_slide();
     }
-    
-    fn input(&mut self, event: Gd<InputEvent>) {
-        if self.base().is_multiplayer_authority()
-                && Input::singleton().get_mouse_mode() == MouseMode::CAPTURED {
-            if let Ok(e) = event.try_cast::<InputEventMouseMotion>() {
-                // Set the Kinematic Player yaw rotation
-                let motion_vec = e.get_relative()
-                    * (self.base_mouse_sensitivity * self.mouse_sensitivity_mult) as f32;
-                let mut rotation = self.base().get_rotation();
-                rotation.y = wrapf(
-                    (rotation.y - motion_vec.x) as f64,
-                    0.0, TAU as f64) as f32;
-
-                // Set the Camera pitch rotation
-                let mut cam_rotation = self.camera.get_rotation();
-                cam_rotation.x = clamp::<f32>(cam_rotation.x - motion_vec.y,
-                    -PI/2.0, PI/2.0);
-                self.base_mut().set_rotation(rotation);
-                self.camera.set_rotation(cam_rotation);
-                self.mesh.set_rotation(cam_rotation);
-                let bazooka_rotation = cam_rotation + Vector3::UP * self.bazooka.get_rotation().y;
-                self.bazooka.set_rotation(bazooka_rotation);
-            }
-        }
-    }
 }
 
 #[godot_api]
@@ -432,8 +807,54 @@ impl PlayerKinematicBody {
         self.camera.set_current(enabled);
     }
 
-    pub fn on_mouse_sensitivity_changed(&mut self, value: f64) {
-        self.mouse_sensitivity_mult = value;
+    pub fn get_camera(&self) -> Gd<Camera3D> {
+        (*self.camera).clone()
+    }
+
+    pub fn set_device(&mut self, device: i64) {
+        self.device = device;
+        if device == BOT_DEVICE {
+            // The host's preferences are for the host
+            self.controls = ControlsSettings::default();
+        }
+    }
+
+    pub fn set_command(&mut self, command: InputCommand) {
+        self.command = command;
+    }
+
+    fn apply_controls_settings(&mut self) {
+        if self.device == BOT_DEVICE {
+            return;
+        }
+        self.controls = self.settings.bind().controls().clone();
+        self.camera.set_fov(self.controls.fov as f32);
+    }
+
+    // Yaw the body and pitch the camera, both in radians
+    pub fn turn(&mut self, yaw: f32, pitch: f32) {
+        let pitch_limit = (self.controls.pitch_limit as f32).to_radians();
+        let mut rotation = self.base().get_rotation();
+        rotation.y = wrapf(
+            (rotation.y - yaw) as f64,
+            0.0, TAU as f64) as f32;
+
+        let mut cam_rotation = self.camera.get_rotation();
+        cam_rotation.x = clamp::<f32>(cam_rotation.x - pitch,
+            -pitch_limit, pitch_limit);
+        self.base_mut().set_rotation(rotation);
+        self.camera.set_rotation(cam_rotation);
+        self.mesh.set_rotation(cam_rotation);
+        let bazooka_rotation = cam_rotation + Vector3::UP * self.bazooka.get_rotation().y;
+        self.bazooka.set_rotation(bazooka_rotation);
+    }
+
+    pub fn scale_gravity(&mut self, scale: f32) {
+        self.gravity *= scale;
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        tint_mesh(&mut self.mesh, color);
     }
 }
 
@@ -4,8 +4,29 @@ use godot::classes::{
     Label,
 };
 
-use crate::player::Player;
+use godot::global::randf_range;
 
+use crate::player::{Player, PLAYERS_GROUP};
+use crate::rocket::PROJECTILES_GROUP;
+use crate::game::Game;
+use crate::match_settings::GameMode;
+use crate::roster::{RosterEntry, peer_of, local_index_of, count_players};
+use crate::scoreboard::Score;
+use crate::split_screen::SplitScreen;
+use crate::kill_cam::KillCam;
+use crate::kill_feed::KillFeed;
+use crate::kill_volume::KnockoutReason;
+use crate::input_bindings::{ANY_DEVICE, KEYBOARD_MOUSE, NO_DEVICE, BOT_DEVICE};
+
+// Spawn points are scored by the danger around them. Players,
+// projectiles and hazards further away than this don't count.
+const SPAWN_DANGER_RADIUS: f32 = 15.0;
+// A rocket or grenade in the air is worse than somebody standing around
+const PROJECTILE_DANGER_WEIGHT: f32 = 2.0;
+// A mine sitting still is easier to avoid than either
+const HAZARD_DANGER_WEIGHT: f32 = 0.5;
+// Points within this much of the safest one are all fair game
+const SPAWN_DANGER_SLACK: f32 = 1.0;
 
 // A spawner for any number of players
 #[derive(GodotClass)]
@@ -13,53 +34,128 @@ use crate::player::Player;
 pub struct NPlayers {
     #[init(val=OnReady::from_loaded("res://player/player.tscn"))]
     player_scene: OnReady<Gd<PackedScene>>,
-    #[init(node="../Arena1/SpawnPoints")]
-    spawn_points_container: OnReady<Gd<Node>>,
+    #[init(node="/root/Game")]
+    game_root: OnReady<Gd<Game>>,
     #[export]
     ko_label: OnEditor<Gd<Label>>,
-    player_info: VarDictionary,
+    #[export]
+    split_screen: OnEditor<Gd<SplitScreen>>,
+    #[export]
+    kill_cam: OnEditor<Gd<KillCam>>,
+    // The respawn countdown, for the one full screen player
+    #[export]
+    respawn_label: OnEditor<Gd<Label>>,
+    #[export]
+    kill_feed: OnEditor<Gd<KillFeed>>,
+    roster: VarDictionary,
+    // The one local player who sees the whole screen, if any
+    kill_cam_player: Option<i64>,
+    // People at this machine still in the match
+    local_humans: Vec<i64>,
+    // Everyone's, kept on every machine
+    scores: Vec<Score>,
+    // Host only: players that ran out of lives
+    eliminated: Vec<i64>,
+    // Our players waiting to come back, and how many seconds are left
+    pending_respawns: Vec<(Gd<Player>, f64)>,
     base: Base<Node>,
 }
 
 #[godot_api]
 impl INode for NPlayers {
     fn ready(&mut self) {
-        // self.base().get_node_as::<Node>("../Arena1");
+        self.kill_cam
+            .signals()
+            .finished()
+            .connect_other(&self.to_gd(), Self::finish_knockout);
 
         if self.base().is_multiplayer_authority() {
-            let player_info = self.player_info.clone();
-            for (id, name) in player_info.iter_shared().typed::<i64, GString>() {
-                self.base_mut().rpc("spawn_player", vslice![id, name]);
+            let roster = self.roster.clone();
+            for (id, entry) in roster.iter_shared().typed::<i64, VarDictionary>() {
+                let entry = RosterEntry::from_dict(&entry);
+                if entry.spectator {
+                    continue;
+                }
+                self.base_mut().rpc(
+                    "spawn_player",
+                    vslice![id, entry.name, entry.color, entry.device],
+                );
             }
         }
     }
+
+    fn process(&mut self, delta: f64) {
+        self.pending_respawns.retain(|(player, _)| player.is_instance_valid());
+        for (_, seconds) in &mut self.pending_respawns {
+            *seconds -= delta;
+        }
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_respawns)
+            .into_iter()
+            .partition(|(_, seconds)| *seconds <= 0.0);
+        // Still waiting while the others pick where to spawn
+        self.pending_respawns = waiting;
+        for (player, seconds) in self.pending_respawns.clone() {
+            let player_id = player.bind().get_player_id();
+            self.show_status(player_id, &format!("Respawning in {}", seconds.ceil()));
+        }
+        for (player, _) in due {
+            self.respawn_now(player);
+        }
+    }
 }
 
 #[godot_api]
 impl NPlayers {
     // Called only by the host
-    pub fn initialize_authority(&mut self, player_info: &VarDictionary) {
-        self.player_info = player_info.clone()
+    pub fn initialize_authority(&mut self, roster: &VarDictionary) {
+        self.roster = roster.clone()
     }
 
+    // `device` is only used by the machine the player sits at.
+    // Anything but ANY_DEVICE means it's sharing the screen.
     #[rpc(authority, call_local, reliable)]
-    pub fn spawn_player(&mut self, peer_id: i64, name: GString) {
+    pub fn spawn_player(&mut self, player_id: i64, name: GString, color: Color, device: i64) {
         // Crate player instance
         let mut player: Gd<Player> = self.player_scene.instantiate_as();
 
-        player.set_position(self.sample_spawn_point());
+        player.set_position(self.sample_spawn_point(player_id));
 
         // Set player authority and camera state
-        player.set_multiplayer_authority(peer_id as i32);
+        player.set_multiplayer_authority(peer_of(player_id) as i32);
+        player.set_name(&player_id.to_string());
+        player.bind_mut().set_player_id(player_id);
         // Must set multiplayer authority before adding
         // to scene tree so that it inherits correctly
         self.base_mut().add_child(&player);
 
         player.bind_mut().get_name_label().set_text(&name);
+        player.bind_mut().set_color(color);
+        self.scores.push(Score {
+            player_id,
+            name: name.clone(),
+            color,
+            knockouts: 0,
+            out: false,
+        });
 
         if player.is_multiplayer_authority() {
-            // Set camera as the current
-            player.bind_mut().set_camera_current(true);
+            if device == BOT_DEVICE {
+                let difficulty = self.game_root.bind().get_match_settings().bot_difficulty;
+                let waypoints = self.spawn_point_positions();
+                player.bind_mut().make_bot(difficulty, waypoints);
+            } else {
+                player.bind_mut().set_device(device);
+            }
+            if device != BOT_DEVICE && device != NO_DEVICE {
+                self.local_humans.push(player_id);
+            }
+            if device == ANY_DEVICE {
+                // Set camera as the current
+                player.bind_mut().set_camera_current(true);
+                self.kill_cam_player = Some(player_id);
+            } else if device >= KEYBOARD_MOUSE {
+                self.split_screen.bind_mut().add_player(player.clone(), &name);
+            }
             // Connect signal to respawn
             player.signals()
                 .out_of_bounds()
@@ -67,41 +163,186 @@ impl NPlayers {
         }
     }
 
-    pub fn respawn_player(&mut self, mut player: Gd<Player>) {
-        let pos = self.sample_spawn_point();
+    pub fn respawn_player(&mut self, player: Gd<Player>, reason: KnockoutReason) {
         // Update knockout count
-        self.ko_label.set_text(
-            &player.bind().get_ko_count().to_string(),
-        );
-        player.rpc("respawn", vslice![pos]);
+        let ko_count = player.bind().get_ko_count();
+        let player_id = player.bind().get_player_id();
+        let split = self.split_screen.bind_mut().set_knockouts(player_id, ko_count);
+        // The HUD and the profile only track the first local player
+        if local_index_of(player_id) == 0 {
+            if !split {
+                self.ko_label.set_text(&ko_count.to_string());
+            }
+            self.game_root.bind_mut().record_knockout();
+        }
+        // Zero when nobody gets the credit
+        let by = player.bind().last_attacker().unwrap_or(0);
+        self.base_mut().rpc("report_knockout", vslice![player_id, ko_count, reason, by]);
+
+        // Coming back waits until they've seen what hit them
+        if self.kill_cam_player == Some(player_id)
+                && self.kill_cam.bind_mut().play(player.clone()) {
+            return;
+        }
+        self.finish_knockout(player);
     }
 
-    fn sample_spawn_point(&self) -> Vector3 {
-        // Pick a random spawn point
-        let spawn_points = self.spawn_points_container.get_children();
-        let point = spawn_points.pick_random().unwrap().cast::<Node3D>();
-        point.get_position()
+    fn finish_knockout(&mut self, mut player: Gd<Player>) {
+        let ko_count = player.bind().get_ko_count();
+        let settings = self.game_root.bind().get_match_settings().clone();
+        if settings.is_out(ko_count as i64) {
+            // Out of lives, no more respawning
+            let player_id = player.bind().get_player_id();
+            player.rpc("eliminate", &[]);
+            // Nobody left here to play, so watch the rest
+            if self.local_humans.contains(&player_id) {
+                self.local_humans.retain(|id| *id != player_id);
+                if self.local_humans.is_empty() {
+                    self.split_screen.bind_mut().clear();
+                    self.game_root.bind_mut().start_spectating();
+                }
+            }
+        } else if settings.respawn_delay > 0.0 {
+            self.pending_respawns.push((player, settings.respawn_delay));
+        } else {
+            self.respawn_now(player);
+        }
+    }
+
+    fn respawn_now(&mut self, mut player: Gd<Player>) {
+        let player_id = player.bind().get_player_id();
+        let pos = self.sample_spawn_point(player_id);
+        let protection = self.game_root.bind().get_match_settings().spawn_protection;
+        player.rpc("respawn", vslice![pos, protection]);
+        self.show_status(player_id, "");
+    }
+
+    // Shown to whoever sits at that player, if anyone
+    fn show_status(&mut self, player_id: i64, text: &str) {
+        if !self.split_screen.bind_mut().set_status(player_id, text)
+                && self.kill_cam_player == Some(player_id) {
+            self.respawn_label.set_text(text);
+        }
+    }
+
+    // Every player reports their knockouts to everyone for the
+    // scoreboard and kill feed. The host also decides when the match is over.
+    #[rpc(any_peer, call_local, reliable)]
+    fn report_knockout(&mut self, player_id: i64, ko_count: i32, reason: KnockoutReason, by: i64) {
+        let mut multiplayer = self.base().get_multiplayer().unwrap();
+        // Peers can only speak for their own players
+        if peer_of(player_id) != multiplayer.get_remote_sender_id() as i64 {
+            return;
+        }
+        let settings = self.game_root.bind().get_match_settings().clone();
+        let by_name = self.scores
+            .iter()
+            .find(|score| score.player_id == by)
+            .map(|score| score.name.clone());
+        if let Some(score) = self.scores.iter_mut().find(|score| score.player_id == player_id) {
+            score.knockouts = ko_count;
+            score.out = settings.is_out(ko_count as i64);
+            let (name, color) = (score.name.clone(), score.color);
+            self.kill_feed.bind_mut().add_knockout(&name, color, reason, by_name.as_ref());
+        }
+        if !multiplayer.is_server() {
+            return;
+        }
+        if settings.ko_limit <= 0 || (ko_count as i64) < settings.ko_limit {
+            return;
+        }
+        let over = match settings.mode {
+            GameMode::FreeForAll => true,
+            GameMode::Stock => {
+                if !self.eliminated.contains(&player_id) {
+                    self.eliminated.push(player_id);
+                }
+                let remaining = (count_players(&self.roster) as usize)
+                    .saturating_sub(self.eliminated.len());
+                remaining <= 1
+            }
+        };
+        if over {
+            self.game_root.signals().match_over().emit();
+        }
+    }
+
+    pub fn scores(&self) -> Vec<Score> {
+        let mut scores = self.scores.clone();
+        // Anyone whose Player is gone has left one way or another
+        for score in &mut scores {
+            if !self.base().has_node(&score.player_id.to_string()) {
+                score.out = true;
+            }
+        }
+        scores
+    }
+
+    // A spawn point away from everyone else and anything flying about,
+    // picked at random from the safest few
+    fn sample_spawn_point(&self, player_id: i64) -> Vector3 {
+        let points = self.spawn_point_positions();
+        let hazards = self.game_root
+            .bind()
+            .get_arena()
+            .map(|arena| arena.hazards())
+            .unwrap_or_default();
+        let Some(mut tree) = self.base().get_tree() else {
+            return points.first().copied().unwrap_or_default();
+        };
+        // Nobody waiting to respawn or out of the match is a threat
+        let mut ignored: Vec<String> = self.pending_respawns
+            .iter()
+            .map(|(player, _)| player.get_name().to_string())
+            .chain(self.eliminated.iter().map(i64::to_string))
+            .collect();
+        ignored.push(player_id.to_string());
+        let players: Vec<Vector3> = tree
+            .get_nodes_in_group(PLAYERS_GROUP)
+            .iter_shared()
+            .filter(|player| !ignored.contains(&player.get_name().to_string()))
+            .filter_map(|player| player.try_cast::<Node3D>().ok())
+            .map(|player| player.get_global_position())
+            .collect();
+        let projectiles: Vec<Vector3> = tree
+            .get_nodes_in_group(PROJECTILES_GROUP)
+            .iter_shared()
+            .filter_map(|projectile| projectile.try_cast::<Node3D>().ok())
+            .map(|projectile| projectile.get_global_position())
+            .collect();
+        let closeness = |point: Vector3, positions: &[Vector3]| -> f32 {
+            positions
+                .iter()
+                .map(|position| (SPAWN_DANGER_RADIUS - position.distance_to(point)).max(0.0))
+                .sum()
+        };
+        let dangers: Vec<f32> = points
+            .iter()
+            .map(|point| {
+                closeness(*point, &players)
+                    + closeness(*point, &projectiles) * PROJECTILE_DANGER_WEIGHT
+                    + closeness(*point, &hazards) * HAZARD_DANGER_WEIGHT
+            })
+            .collect();
+        let least = dangers.iter().copied().fold(f32::INFINITY, f32::min);
+        let safest: Vec<Vector3> = points
+            .iter()
+            .zip(&dangers)
+            .filter(|(_, danger)| **danger <= least + SPAWN_DANGER_SLACK)
+            .map(|(point, _)| *point)
+            .collect();
+        if safest.is_empty() {
+            return Vector3::ZERO;
+        }
+        let index = randf_range(0.0, safest.len() as f64) as usize;
+        safest[index.min(safest.len() - 1)]
     }
 
-    // fn sample_spawn_point_filtered(&mut self) -> Vector3 {
-        // Pick a random spawn point, filtering out ones with players nearby
-        // let spawn_points = self.spawn_points_container.get_children();
-        // PackedVector3Array::
-        // let filtered = spawn_points
-        //     .functional_ops()
-        //     .filter(&Callable::from_fn("is_even", |args| {
-        //         let arg = args[0];
-        //         *arg.try_to::<Node3D>();
-        //         true
-// }));
-        // let point = filtered.pick_random().unwrap().cast::<Node3D>();
-        // point.get_position()
-    // }
-
-    #[func]
-    fn filter_point(&mut self, args: Array<Variant>) -> bool {
-        godot_print!("Filtering: {:?}", args);
-        // point: Gd<Node3D> = args;
-        true
+    fn spawn_point_positions(&self) -> Vec<Vector3> {
+        self.game_root
+            .bind()
+            .get_arena()
+            .map(|arena| arena.spawn_points())
+            .unwrap_or_default()
     }
 }
@@ -0,0 +1,214 @@
+use godot::prelude::*;
+use godot::classes::ConfigFile;
+use godot::global::Error;
+
+use crate::roster::DEFAULT_PLAYER_COLOR;
+use crate::settings::{SETTINGS_PATH, read_value, save_atomic};
+
+pub const PROFILE_PATH: &str = "user://profile.cfg";
+
+// Bump this and add a step to `migrate` whenever the layout changes.
+//   0: no profile, only `[Lobby] prev_ip_address` in settings.cfg
+//   1: `[meta] version`, `[player]`, `[controls]`, `[servers]`, `[stats]`
+pub const PROFILE_VERSION: i64 = 1;
+
+const MAX_RECENT_SERVERS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ControlScheme {
+    #[default]
+    KeyboardMouse,
+    Gamepad,
+}
+
+impl ControlScheme {
+    pub const ALL: [ControlScheme; 2] = [ControlScheme::KeyboardMouse, ControlScheme::Gamepad];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ControlScheme::KeyboardMouse => "Keyboard & Mouse",
+            ControlScheme::Gamepad => "Gamepad",
+        }
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            ControlScheme::KeyboardMouse => "keyboard_mouse",
+            ControlScheme::Gamepad => "gamepad",
+        }
+    }
+
+    fn from_key(key: &str) -> Self {
+        Self::ALL.into_iter().find(|c| c.key() == key).unwrap_or_default()
+    }
+
+    pub fn to_index(self) -> i64 {
+        Self::ALL.iter().position(|c| *c == self).unwrap_or(0) as i64
+    }
+
+    pub fn from_index(index: i64) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LifetimeStats {
+    pub matches_played: i64,
+    // Times this player got knocked out
+    pub knockouts: i64,
+    pub rockets_fired: i64,
+}
+
+// Who the local player is, kept between launches
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerProfile {
+    pub display_name: GString,
+    pub color: Color,
+    pub preferred_controls: ControlScheme,
+    // Most recent first
+    pub recent_servers: Vec<GString>,
+    pub favorite_servers: Vec<GString>,
+    pub stats: LifetimeStats,
+}
+
+impl Default for PlayerProfile {
+    fn default() -> Self {
+        Self {
+            display_name: GString::from("Bob"),
+            color: DEFAULT_PLAYER_COLOR,
+            preferred_controls: ControlScheme::default(),
+            recent_servers: Vec::new(),
+            favorite_servers: Vec::new(),
+            stats: LifetimeStats::default(),
+        }
+    }
+}
+
+impl PlayerProfile {
+    // Never fails, a missing or broken profile just gives the defaults
+    pub fn load() -> Self {
+        let mut config = ConfigFile::new_gd();
+        let version = if config.load(PROFILE_PATH) == Error::OK {
+            read_value(&config, "meta", "version", 0_i64)
+        } else {
+            0
+        };
+        if version > PROFILE_VERSION {
+            godot_warn!(
+                "Profile version {version} is newer than {PROFILE_VERSION}, \
+                 unknown fields will be dropped on save"
+            );
+        } else if version < 0 {
+            godot_warn!("Profile version {version} makes no sense, migrating from scratch");
+        }
+        let version = version.clamp(0, PROFILE_VERSION);
+        for from in version..PROFILE_VERSION {
+            migrate(&mut config, from);
+        }
+        Self::from_config(&config)
+    }
+
+    pub fn save(&self) {
+        let mut config = ConfigFile::new_gd();
+        config.set_value("meta", "version", &PROFILE_VERSION.to_variant());
+        config.set_value("player", "name", &self.display_name.to_variant());
+        config.set_value("player", "color", &self.color.to_variant());
+        config.set_value(
+            "controls",
+            "preferred",
+            &self.preferred_controls.key().to_variant(),
+        );
+        config.set_value(
+            "servers",
+            "recent",
+            &PackedStringArray::from(self.recent_servers.as_slice()).to_variant(),
+        );
+        config.set_value(
+            "servers",
+            "favorites",
+            &PackedStringArray::from(self.favorite_servers.as_slice()).to_variant(),
+        );
+        config.set_value("stats", "matches_played", &self.stats.matches_played.to_variant());
+        config.set_value("stats", "knockouts", &self.stats.knockouts.to_variant());
+        config.set_value("stats", "rockets_fired", &self.stats.rockets_fired.to_variant());
+        save_atomic(&mut config, &GString::from(PROFILE_PATH));
+    }
+
+    fn from_config(config: &Gd<ConfigFile>) -> Self {
+        let defaults = Self::default();
+        let preferred: GString = read_value(config, "controls", "preferred", GString::new());
+        Self {
+            display_name: read_value(config, "player", "name", defaults.display_name),
+            color: read_value(config, "player", "color", defaults.color),
+            preferred_controls: ControlScheme::from_key(&preferred.to_string()),
+            recent_servers: read_strings(config, "servers", "recent"),
+            favorite_servers: read_strings(config, "servers", "favorites"),
+            stats: LifetimeStats {
+                matches_played: read_value(config, "stats", "matches_played", 0_i64),
+                knockouts: read_value(config, "stats", "knockouts", 0_i64),
+                rockets_fired: read_value(config, "stats", "rockets_fired", 0_i64),
+            },
+        }
+    }
+
+    pub fn add_recent_server(&mut self, address: &GString) {
+        self.recent_servers.retain(|a| a != address);
+        self.recent_servers.insert(0, address.clone());
+        self.recent_servers.truncate(MAX_RECENT_SERVERS);
+    }
+
+    pub fn is_favorite(&self, address: &GString) -> bool {
+        self.favorite_servers.contains(address)
+    }
+
+    pub fn toggle_favorite(&mut self, address: &GString) {
+        if self.is_favorite(address) {
+            self.favorite_servers.retain(|a| a != address);
+        } else {
+            self.favorite_servers.push(address.clone());
+        }
+    }
+
+    pub fn add_match_stats(&mut self, stats: &LifetimeStats) {
+        self.stats.matches_played += stats.matches_played;
+        self.stats.knockouts += stats.knockouts;
+        self.stats.rockets_fired += stats.rockets_fired;
+    }
+
+    // Favorites first, then anything else we joined lately
+    pub fn known_servers(&self) -> Vec<GString> {
+        let mut servers = self.favorite_servers.clone();
+        for address in &self.recent_servers {
+            if !servers.contains(address) {
+                servers.push(address.clone());
+            }
+        }
+        servers
+    }
+}
+
+// Upgrade the loaded file by exactly one version
+fn migrate(config: &mut Gd<ConfigFile>, from: i64) {
+    match from {
+        0 => {
+            let mut legacy = ConfigFile::new_gd();
+            // Before profiles existed, the only thing we kept was the last address
+            if legacy.load(SETTINGS_PATH) == Error::OK {
+                let address: GString = read_value(&legacy, "Lobby", "prev_ip_address", GString::new());
+                if !address.is_empty() {
+                    let recent = PackedStringArray::from(&[address]);
+                    config.set_value("servers", "recent", &recent.to_variant());
+                }
+            }
+        }
+        // load() never asks for these, but a bad version shouldn't crash anything
+        other => godot_warn!("No migration from profile version {other}"),
+    }
+    config.set_value("meta", "version", &(from + 1).to_variant());
+}
+
+fn read_strings(config: &Gd<ConfigFile>, section: &str, key: &str) -> Vec<GString> {
+    read_value(config, section, key, PackedStringArray::new())
+        .as_slice()
+        .to_vec()
+}
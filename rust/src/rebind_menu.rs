@@ -0,0 +1,238 @@
+use godot::prelude::*;
+use godot::global::Key;
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Control, IControl, Button, Label, GridContainer,
+    InputEvent, InputEventKey,
+};
+
+use crate::input_bindings::{Binding, REBINDABLE_ACTIONS, BindingsSettings, action_bindings};
+use crate::settings::Settings;
+
+// How many bindings each action can hold
+const SLOTS_PER_ACTION: usize = 3;
+
+// Lists every rebindable action with a button per binding slot.
+// Press a slot, then the new key, mouse button or pad input.
+// Escape cancels, Delete clears the slot.
+#[derive(GodotClass)]
+#[class(init, base=Control)]
+pub struct RebindMenu {
+    #[export]
+    rows_grid: OnEditor<Gd<GridContainer>>,
+    #[export]
+    message_label: OnEditor<Gd<Label>>,
+    #[export]
+    restore_button: OnEditor<Gd<Button>>,
+    #[export]
+    close_button: OnEditor<Gd<Button>>,
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
+    // One row of slot buttons per action, same order as REBINDABLE_ACTIONS
+    slot_buttons: Vec<Vec<Gd<Button>>>,
+    // (action index, slot) waiting for an input
+    listening: Option<(usize, usize)>,
+    base: Base<Control>,
+}
+
+#[godot_api]
+impl IControl for RebindMenu {
+    fn ready(&mut self) {
+        let gd_ref = self.to_gd();
+        self.rows_grid.set_columns(1 + SLOTS_PER_ACTION as i32);
+        for (action_index, (_, label_text)) in REBINDABLE_ACTIONS.iter().enumerate() {
+            let mut label = Label::new_alloc();
+            label.set_text(*label_text);
+            self.rows_grid.add_child(&label);
+
+            let mut row = Vec::new();
+            for slot in 0..SLOTS_PER_ACTION {
+                let mut button = Button::new_alloc();
+                button.set_custom_minimum_size(Vector2::new(150.0, 0.0));
+                button.set_clip_text(true);
+                button
+                    .signals()
+                    .pressed()
+                    .connect_other(&gd_ref, move |this| {
+                        this.start_listening(action_index, slot);
+                    });
+                self.rows_grid.add_child(&button);
+                row.push(button);
+            }
+            self.slot_buttons.push(row);
+        }
+
+        self.restore_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::restore_defaults);
+        self.close_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::close);
+
+        self.refresh();
+    }
+
+    fn input(&mut self, event: Gd<InputEvent>) {
+        if !self.base().is_visible() {
+            return;
+        }
+        let Some((action_index, slot)) = self.listening else {
+            if event.is_action_pressed("ui_cancel") {
+                self.close();
+                if let Some(mut viewport) = self.base().get_viewport() {
+                    viewport.set_input_as_handled();
+                }
+            }
+            return;
+        };
+        let key = event
+            .clone()
+            .try_cast::<InputEventKey>()
+            .ok()
+            .filter(|key| key.is_pressed() && !key.is_echo())
+            .map(|key| key.get_keycode());
+        match key {
+            Some(Key::ESCAPE) => {
+                self.listening = None;
+                self.message_label.set_text("");
+            }
+            Some(Key::DELETE) => {
+                self.listening = None;
+                self.clear_slot(action_index, slot);
+            }
+            _ => {
+                let Some(binding) = Binding::from_pressed_event(&event) else {
+                    return;
+                };
+                self.listening = None;
+                self.bind_slot(action_index, slot, binding);
+            }
+        }
+        // Don't let the press reach the game or the buttons underneath
+        if let Some(mut viewport) = self.base().get_viewport() {
+            viewport.set_input_as_handled();
+        }
+        self.refresh();
+    }
+}
+
+#[godot_api]
+impl RebindMenu {
+    #[func]
+    pub fn open(&mut self) {
+        self.listening = None;
+        self.message_label.set_text("");
+        self.refresh();
+        self.base_mut().set_visible(true);
+        self.slot_buttons[0][0].grab_focus();
+    }
+
+    #[func]
+    fn close(&mut self) {
+        self.listening = None;
+        self.base_mut().set_visible(false);
+        self.signals().closed().emit();
+    }
+
+    #[signal]
+    pub fn closed();
+
+    fn start_listening(&mut self, action_index: usize, slot: usize) {
+        self.listening = Some((action_index, slot));
+        let label_text = REBINDABLE_ACTIONS[action_index].1;
+        self.message_label.set_text(&format!(
+            "Press a key, button or stick for {label_text} (Escape cancels, Delete clears)"
+        ));
+        self.refresh();
+    }
+
+    fn bind_slot(&mut self, action_index: usize, slot: usize, binding: Binding) {
+        let (action, label_text) = REBINDABLE_ACTIONS[action_index];
+        let mut settings = self.settings.bind().bindings().clone();
+
+        // An input can only do one thing, so take it off anything else first
+        let mut conflicts = Vec::new();
+        for (other, other_label) in REBINDABLE_ACTIONS {
+            if other == action {
+                continue;
+            }
+            let mut others = action_bindings(other);
+            if others.contains(&binding) {
+                others.retain(|b| *b != binding);
+                settings.actions.insert(other.to_string(), others);
+                conflicts.push(other_label);
+            }
+        }
+
+        let mut bindings = action_bindings(action);
+        bindings.retain(|b| *b != binding);
+        if slot < bindings.len() {
+            bindings[slot] = binding;
+        } else {
+            bindings.push(binding);
+        }
+        bindings.truncate(SLOTS_PER_ACTION);
+        settings.actions.insert(action.to_string(), bindings);
+        self.save_bindings(settings);
+
+        let message = if conflicts.is_empty() {
+            format!("{} bound to {label_text}", binding.label())
+        } else {
+            format!(
+                "{} bound to {label_text}, removed from {}",
+                binding.label(),
+                conflicts.join(", ")
+            )
+        };
+        self.message_label.set_text(&message);
+    }
+
+    fn clear_slot(&mut self, action_index: usize, slot: usize) {
+        let (action, label_text) = REBINDABLE_ACTIONS[action_index];
+        let mut bindings = action_bindings(action);
+        if slot >= bindings.len() {
+            return;
+        }
+        bindings.remove(slot);
+        if bindings.is_empty() {
+            self.message_label
+                .set_text(&format!("{label_text} has nothing bound to it"));
+        } else {
+            self.message_label.set_text("");
+        }
+        let mut settings = self.settings.bind().bindings().clone();
+        settings.actions.insert(action.to_string(), bindings);
+        self.save_bindings(settings);
+    }
+
+    #[func]
+    fn restore_defaults(&mut self) {
+        self.listening = None;
+        self.save_bindings(BindingsSettings::default());
+        self.message_label.set_text("Controls restored to defaults");
+        self.refresh();
+    }
+
+    fn save_bindings(&mut self, bindings: BindingsSettings) {
+        let mut settings = self.settings.bind_mut();
+        settings.set_bindings(bindings);
+        settings.save();
+    }
+
+    fn refresh(&mut self) {
+        for (action_index, (action, _)) in REBINDABLE_ACTIONS.iter().enumerate() {
+            let bindings = action_bindings(action);
+            for (slot, button) in self.slot_buttons[action_index].iter_mut().enumerate() {
+                let text = if self.listening == Some((action_index, slot)) {
+                    "...".to_string()
+                } else {
+                    bindings.get(slot).map(|b| b.label()).unwrap_or_default()
+                };
+                button.set_text(&text);
+            }
+        }
+    }
+}
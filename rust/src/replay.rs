@@ -0,0 +1,527 @@
+// Match replays, saved to user://replays.
+//
+// A replay file holds one Variant, written with FileAccess.store_var
+// into a file opened with FileAccess.open_compressed (FastLZ). It's a
+// Dictionary laid out like this:
+//
+//   "format":      "bazooka-wars-replay"
+//   "version":     1
+//   "recorded_at": String, local time the recording started
+//   "tick_rate":   float, frames per second of game time
+//   "arena":       int, index into arena::ARENAS
+//   "settings":    Dictionary, the MatchSettings the match was played with
+//   "roster":      Dictionary, player id to { "name": String, "color": Color }
+//   "frames":      Array with one Dictionary per physics tick:
+//       "players":    player id to PackedFloat32Array
+//                     [x, y, z, rotation x, rotation y, rotation z, camera pitch, ragdoll]
+//                     where the rotation is of whichever body is showing
+//                     and ragdoll is 1 or 0
+//       "rockets":    rocket id to PackedFloat32Array
+//                     [x, y, z, rotation x, rotation y, rotation z]
+//       "grenades":   grenade id to PackedFloat32Array, laid out like rockets
+//       "explosions": PackedVector3Array, blasts that started that tick
+//
+// Rocket and grenade ids only mean something within one file. Anything a reader
+// doesn't know about should be ignored, so new keys don't need a new
+// version. Changing or removing a key does.
+
+use std::collections::{HashMap, HashSet};
+
+use godot::prelude::*;
+use godot::classes::{Node, INode, FileAccess, DirAccess, Engine, Time, SceneTree};
+use godot::classes::file_access::ModeFlags;
+
+use crate::match_settings::MatchSettings;
+use crate::player::{Player, PLAYERS_GROUP};
+use crate::rocket::{Rocket, PROJECTILES_GROUP};
+use crate::grenade::Grenade;
+use crate::explosion::{Explosion, EXPLOSIONS_GROUP};
+use crate::settings::Settings;
+
+pub const REPLAY_FORMAT: &str = "bazooka-wars-replay";
+pub const REPLAY_VERSION: i64 = 1;
+pub const REPLAYS_DIR: &str = "user://replays";
+pub const REPLAY_EXTENSION: &str = "replay";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PlayerState {
+    pub position: Vector3,
+    // Of whichever body is showing
+    pub rotation: Vector3,
+    pub pitch: f32,
+    pub ragdoll: bool,
+}
+
+impl PlayerState {
+    fn encode(&self) -> PackedFloat32Array {
+        PackedFloat32Array::from(&[
+            self.position.x, self.position.y, self.position.z,
+            self.rotation.x, self.rotation.y, self.rotation.z,
+            self.pitch,
+            if self.ragdoll { 1.0 } else { 0.0 },
+        ])
+    }
+
+    fn decode(values: &PackedFloat32Array) -> Option<Self> {
+        let v = values.as_slice();
+        if v.len() < 8 {
+            return None;
+        }
+        Some(Self {
+            position: Vector3::new(v[0], v[1], v[2]),
+            rotation: Vector3::new(v[3], v[4], v[5]),
+            pitch: v[6],
+            ragdoll: v[7] != 0.0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProjectileState {
+    pub position: Vector3,
+    pub rotation: Vector3,
+}
+
+impl ProjectileState {
+    fn encode(&self) -> PackedFloat32Array {
+        PackedFloat32Array::from(&[
+            self.position.x, self.position.y, self.position.z,
+            self.rotation.x, self.rotation.y, self.rotation.z,
+        ])
+    }
+
+    fn decode(values: &PackedFloat32Array) -> Option<Self> {
+        let v = values.as_slice();
+        if v.len() < 6 {
+            return None;
+        }
+        Some(Self {
+            position: Vector3::new(v[0], v[1], v[2]),
+            rotation: Vector3::new(v[3], v[4], v[5]),
+        })
+    }
+}
+
+// Everything worth seeing on one physics tick
+#[derive(Clone, Debug, Default)]
+pub struct ReplayFrame {
+    pub players: Vec<(i64, PlayerState)>,
+    pub rockets: Vec<(i64, ProjectileState)>,
+    pub grenades: Vec<(i64, ProjectileState)>,
+    pub explosions: Vec<Vector3>,
+}
+
+impl ReplayFrame {
+    // Reads the state of the world as it is now. `seen_explosions` keeps
+    // track of blasts already written down, so each only shows up once.
+    pub fn capture(tree: &mut Gd<SceneTree>, seen_explosions: &mut HashSet<InstanceId>) -> Self {
+        let players = tree
+            .get_nodes_in_group(PLAYERS_GROUP)
+            .iter_shared()
+            .filter_map(|node| node.try_cast::<Player>().ok())
+            .map(|player| {
+                let player = player.bind();
+                (player.get_player_id(), player.replay_state())
+            })
+            .collect();
+        let mut rockets = Vec::new();
+        let mut grenades = Vec::new();
+        for node in tree.get_nodes_in_group(PROJECTILES_GROUP).iter_shared() {
+            let Ok(projectile) = node.try_cast::<Node3D>() else {
+                continue;
+            };
+            let entry = (projectile.instance_id().to_i64(), ProjectileState {
+                position: projectile.get_position(),
+                rotation: projectile.get_rotation(),
+            });
+            if projectile.clone().try_cast::<Rocket>().is_ok() {
+                rockets.push(entry);
+            } else if projectile.try_cast::<Grenade>().is_ok() {
+                grenades.push(entry);
+            }
+        }
+        let explosions: Vec<Gd<Node3D>> = tree
+            .get_nodes_in_group(EXPLOSIONS_GROUP)
+            .iter_shared()
+            .filter_map(|node| node.try_cast::<Node3D>().ok())
+            .collect();
+        // Forget the ones that are gone, so the set doesn't grow forever
+        let live: HashSet<InstanceId> = explosions.iter().map(|e| e.instance_id()).collect();
+        seen_explosions.retain(|id| live.contains(id));
+        let explosions = explosions
+            .into_iter()
+            .filter(|explosion| seen_explosions.insert(explosion.instance_id()))
+            .map(|explosion| explosion.get_position())
+            .collect();
+        Self { players, rockets, grenades, explosions }
+    }
+
+    pub fn projectiles(&self) -> impl Iterator<Item = &(i64, ProjectileState)> {
+        self.rockets.iter().chain(&self.grenades)
+    }
+
+    pub fn projectile(&self, id: i64) -> Option<&ProjectileState> {
+        self.projectiles().find(|(other, _)| *other == id).map(|(_, state)| state)
+    }
+
+    fn to_dict(&self) -> VarDictionary {
+        let mut players = VarDictionary::new();
+        for (id, state) in &self.players {
+            players.set(*id, state.encode());
+        }
+        let projectiles = |states: &[(i64, ProjectileState)]| {
+            let mut dict = VarDictionary::new();
+            for (id, state) in states {
+                dict.set(*id, state.encode());
+            }
+            dict
+        };
+        vdict! {
+            "players": players,
+            "rockets": projectiles(&self.rockets),
+            "grenades": projectiles(&self.grenades),
+            "explosions": PackedVector3Array::from(self.explosions.as_slice()),
+        }
+    }
+
+    fn from_dict(dict: &VarDictionary) -> Self {
+        let states = |key: &str| -> Vec<(i64, PackedFloat32Array)> {
+            dict.get(key)
+                .and_then(|v| v.try_to::<VarDictionary>().ok())
+                .map(|states| {
+                    states
+                        .iter_shared()
+                        .filter_map(|(id, values)| {
+                            Some((id.try_to::<i64>().ok()?, values.try_to().ok()?))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Self {
+            players: states("players")
+                .into_iter()
+                .filter_map(|(id, values)| Some((id, PlayerState::decode(&values)?)))
+                .collect(),
+            rockets: states("rockets")
+                .into_iter()
+                .filter_map(|(id, values)| Some((id, ProjectileState::decode(&values)?)))
+                .collect(),
+            grenades: states("grenades")
+                .into_iter()
+                .filter_map(|(id, values)| Some((id, ProjectileState::decode(&values)?)))
+                .collect(),
+            explosions: dict
+                .get("explosions")
+                .and_then(|v| v.try_to::<PackedVector3Array>().ok())
+                .map(|positions| positions.to_vec())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Replay {
+    pub recorded_at: GString,
+    pub tick_rate: f64,
+    pub settings: MatchSettings,
+    // Player id to { "name", "color" }
+    pub roster: VarDictionary,
+    pub frames: Vec<ReplayFrame>,
+}
+
+impl Replay {
+    pub fn new(settings: MatchSettings) -> Self {
+        Self {
+            recorded_at: Time::singleton().get_datetime_string_from_system(),
+            tick_rate: Engine::singleton().get_physics_ticks_per_second() as f64,
+            settings,
+            roster: VarDictionary::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    // In seconds of game time
+    pub fn duration(&self) -> f64 {
+        self.frames.len().saturating_sub(1) as f64 / self.tick_rate
+    }
+
+    pub fn to_dict(&self) -> VarDictionary {
+        let frames: VarArray = self.frames
+            .iter()
+            .map(|frame| frame.to_dict().to_variant())
+            .collect();
+        vdict! {
+            "format": REPLAY_FORMAT,
+            "version": REPLAY_VERSION,
+            "recorded_at": self.recorded_at.clone(),
+            "tick_rate": self.tick_rate,
+            "arena": self.settings.arena,
+            "settings": self.settings.to_dict(),
+            "roster": self.roster.clone(),
+            "frames": frames,
+        }
+    }
+
+    // None for anything that isn't a replay we know how to read
+    pub fn from_dict(dict: &VarDictionary) -> Option<Self> {
+        let format = dict.get("format")?.try_to::<GString>().ok()?;
+        let version = dict.get("version")?.try_to::<i64>().ok()?;
+        if format.to_string() != REPLAY_FORMAT || version > REPLAY_VERSION {
+            return None;
+        }
+        let mut settings = dict.get("settings")
+            .and_then(|v| v.try_to::<VarDictionary>().ok())
+            .map(|settings| MatchSettings::from_dict(&settings))
+            .unwrap_or_default();
+        if let Some(arena) = dict.get("arena").and_then(|v| v.try_to::<i64>().ok()) {
+            settings.arena = arena;
+        }
+        let tick_rate = dict.get("tick_rate")?.try_to::<f64>().ok()?;
+        if tick_rate <= 0.0 {
+            return None;
+        }
+        Some(Self {
+            recorded_at: dict.get("recorded_at")
+                .and_then(|v| v.try_to::<GString>().ok())
+                .unwrap_or_default(),
+            tick_rate,
+            settings,
+            roster: dict.get("roster")
+                .and_then(|v| v.try_to::<VarDictionary>().ok())
+                .unwrap_or_default(),
+            frames: dict.get("frames")?
+                .try_to::<VarArray>()
+                .ok()?
+                .iter_shared()
+                .filter_map(|frame| frame.try_to::<VarDictionary>().ok())
+                .map(|frame| ReplayFrame::from_dict(&frame))
+                .collect(),
+        })
+    }
+
+    pub fn load(path: &GString) -> Option<Self> {
+        let file = FileAccess::open_compressed(path, ModeFlags::READ)?;
+        let dict = file.get_var().try_to::<VarDictionary>().ok()?;
+        Self::from_dict(&dict)
+    }
+
+    pub fn save(&self, path: &GString) -> bool {
+        let Some(mut file) = FileAccess::open_compressed(path, ModeFlags::WRITE) else {
+            godot_error!("Couldn't write {path}");
+            return false;
+        };
+        file.store_var(&self.to_dict().to_variant())
+    }
+}
+
+// Adds anyone playing now who isn't in the roster yet
+pub fn fill_roster(roster: &mut VarDictionary, tree: &mut Gd<SceneTree>) {
+    let players = tree.get_nodes_in_group(PLAYERS_GROUP);
+    for player in players.iter_shared().filter_map(|node| node.try_cast::<Player>().ok()) {
+        let player = player.bind();
+        let id = player.get_player_id();
+        if !roster.contains_key(id) {
+            roster.set(id, vdict! {
+                "name": player.get_name_label().get_text(),
+                "color": player.get_color(),
+            });
+        }
+    }
+}
+
+// Puppets acting out replay frames: players, projectiles and blasts.
+// None of them are in the usual groups, so nothing mistakes them for
+// the real thing.
+pub struct ReplayStage {
+    parent: Gd<Node>,
+    roster: VarDictionary,
+    player_scene: Gd<PackedScene>,
+    rocket_scene: Gd<PackedScene>,
+    grenade_scene: Gd<PackedScene>,
+    explosion_scene: Gd<PackedScene>,
+    players: HashMap<i64, Gd<Player>>,
+    rockets: HashMap<i64, Gd<Rocket>>,
+    grenades: HashMap<i64, Gd<Grenade>>,
+}
+
+impl ReplayStage {
+    pub fn new(parent: Gd<Node>, roster: VarDictionary) -> Self {
+        Self {
+            parent,
+            roster,
+            player_scene: load("res://player/player.tscn"),
+            rocket_scene: load("res://rocket/rocket.tscn"),
+            grenade_scene: load("res://weapons/grenade.tscn"),
+            explosion_scene: load("res://explosion/rocket_explosion.tscn"),
+            players: HashMap::new(),
+            rockets: HashMap::new(),
+            grenades: HashMap::new(),
+        }
+    }
+
+    pub fn show_frame(&mut self, frame: &ReplayFrame) {
+        for player in self.players.values_mut() {
+            player.set_visible(false);
+        }
+        for (id, state) in &frame.players {
+            let mut player = self.player_puppet(*id);
+            player.set_visible(true);
+            player.bind_mut().show_replay_state(state);
+        }
+
+        let (parent, rocket_scene) = (&mut self.parent, &self.rocket_scene);
+        place_puppets(&mut self.rockets, &frame.rockets, || {
+            let mut rocket = rocket_scene.instantiate_as::<Rocket>();
+            parent.add_child(&rocket);
+            rocket.bind_mut().make_puppet();
+            rocket
+        });
+        let (parent, grenade_scene) = (&mut self.parent, &self.grenade_scene);
+        place_puppets(&mut self.grenades, &frame.grenades, || {
+            let mut grenade = grenade_scene.instantiate_as::<Grenade>();
+            parent.add_child(&grenade);
+            grenade.bind_mut().make_puppet();
+            grenade
+        });
+    }
+
+    // Blasts that only look the part, they don't push anyone
+    pub fn explode(&mut self, positions: &[Vector3]) {
+        for position in positions {
+            let mut explosion = self.explosion_scene.instantiate_as::<Explosion>();
+            explosion.set_position(*position);
+            explosion.bind_mut().make_puppet();
+            self.parent.add_child(&explosion);
+        }
+    }
+
+    // Takes every puppet off the stage. Blasts finish on their own.
+    pub fn clear(&mut self) {
+        for (_, mut player) in self.players.drain() {
+            player.queue_free();
+        }
+        for (_, mut rocket) in self.rockets.drain() {
+            rocket.queue_free();
+        }
+        for (_, mut grenade) in self.grenades.drain() {
+            grenade.queue_free();
+        }
+    }
+
+    // Made the first time the player shows up
+    fn player_puppet(&mut self, id: i64) -> Gd<Player> {
+        if let Some(player) = self.players.get(&id) {
+            return player.clone();
+        }
+        let mut player = self.player_scene.instantiate_as::<Player>();
+        player.set_name(&format!("Replay{id}"));
+        player.bind_mut().set_player_id(id);
+        self.parent.add_child(&player);
+        player.bind_mut().make_puppet();
+        let entry = self.roster
+            .get(id)
+            .and_then(|entry| entry.try_to::<VarDictionary>().ok())
+            .unwrap_or_default();
+        if let Some(name) = entry.get("name").and_then(|name| name.try_to::<GString>().ok()) {
+            player.bind_mut().get_name_label().set_text(&name);
+        }
+        if let Some(color) = entry.get("color").and_then(|color| color.try_to::<Color>().ok()) {
+            player.bind_mut().set_color(color);
+        }
+        self.players.insert(id, player.clone());
+        player
+    }
+}
+
+// Deletes the oldest replays until only `keep` are left.
+// File names start with the date, so they sort oldest first.
+fn prune_replays(keep: i64) {
+    let mut files: Vec<String> = DirAccess::get_files_at(REPLAYS_DIR)
+        .as_slice()
+        .iter()
+        .map(|file| file.to_string())
+        .filter(|file| file.ends_with(&format!(".{REPLAY_EXTENSION}")))
+        .collect();
+    files.sort();
+    let excess = files.len().saturating_sub(keep.max(0) as usize);
+    for file in &files[..excess] {
+        DirAccess::remove_absolute(&format!("{REPLAYS_DIR}/{file}"));
+    }
+}
+
+// Writes down every tick of the match it's part of, and saves it when
+// the match ends. Works the same on the host and on clients.
+#[derive(GodotClass)]
+#[class(init, base=Node)]
+pub struct ReplayRecorder {
+    #[init(node="/root/Settings")]
+    settings: OnReady<Gd<Settings>>,
+    replay: Option<Replay>,
+    seen_explosions: HashSet<InstanceId>,
+    base: Base<Node>,
+}
+
+#[godot_api]
+impl ReplayRecorder {
+    // Must be called before the recorder enters the tree
+    pub fn set_match_settings(&mut self, settings: MatchSettings) {
+        self.replay = Some(Replay::new(settings));
+    }
+}
+
+#[godot_api]
+impl INode for ReplayRecorder {
+    fn physics_process(&mut self, _delta: f64) {
+        let Some(mut tree) = self.base().get_tree() else {
+            return;
+        };
+        let frame = ReplayFrame::capture(&mut tree, &mut self.seen_explosions);
+        let Some(replay) = self.replay.as_mut() else {
+            return;
+        };
+        // Players can show up late, so the roster fills in as we go
+        fill_roster(&mut replay.roster, &mut tree);
+        replay.frames.push(frame);
+    }
+
+    fn exit_tree(&mut self) {
+        let Some(replay) = self.replay.take() else {
+            return;
+        };
+        if replay.frames.is_empty() {
+            return;
+        }
+        DirAccess::make_dir_recursive_absolute(REPLAYS_DIR);
+        // Colons aren't allowed in file names everywhere
+        let stamp = replay.recorded_at.to_string().replace(':', "-");
+        let path = GString::from(&format!("{REPLAYS_DIR}/{stamp}.{REPLAY_EXTENSION}"));
+        if replay.save(&path) {
+            let keep = self.settings.bind().replays().keep;
+            prune_replays(keep);
+        }
+    }
+}
+
+// Moves projectile puppets to match `states`, making the ones that
+// just showed up with `make` and dropping the ones that are gone
+fn place_puppets<T>(
+    puppets: &mut HashMap<i64, Gd<T>>,
+    states: &[(i64, ProjectileState)],
+    mut make: impl FnMut() -> Gd<T>,
+) where
+    T: GodotClass + Inherits<Node3D>,
+{
+    let mut gone: Vec<i64> = puppets.keys().copied().collect();
+    for (id, state) in states {
+        gone.retain(|puppet| puppet != id);
+        let mut puppet = puppets.entry(*id).or_insert_with(&mut make).clone().upcast::<Node3D>();
+        puppet.set_position(state.position);
+        puppet.set_rotation(state.rotation);
+    }
+    for id in gone {
+        if let Some(puppet) = puppets.remove(&id) {
+            puppet.upcast::<Node3D>().queue_free();
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Control, IControl, Button, Label, HSlider, OptionButton,
+};
+
+use crate::free_camera::FreeCamera;
+use crate::game::Game;
+use crate::replay::{Replay, ReplayStage};
+
+// Playback speeds on offer, as multiples of real time
+const REPLAY_SPEEDS: [f64; 5] = [0.25, 0.5, 1.0, 2.0, 4.0];
+const NORMAL_SPEED_INDEX: usize = 2;
+
+// Skipping ahead further than this many frames doesn't set off
+// every blast along the way
+const MAX_EXPLOSION_CATCHUP: usize = 30;
+
+// Plays a Replay back inside the game scene, with controls for
+// pausing and scrubbing
+#[derive(GodotClass)]
+#[class(init, base=Control)]
+pub struct ReplayViewer {
+    #[export]
+    free_camera: OnEditor<Gd<FreeCamera>>,
+    #[export]
+    play_button: OnEditor<Gd<Button>>,
+    #[export]
+    timeline: OnEditor<Gd<HSlider>>,
+    #[export]
+    speed_option: OnEditor<Gd<OptionButton>>,
+    #[export]
+    time_label: OnEditor<Gd<Label>>,
+    #[init(node="/root/Game")]
+    game_root: OnReady<Gd<Game>>,
+    replay: Option<Replay>,
+    // Seconds into the replay
+    time: f64,
+    playing: bool,
+    #[init(val=1.0)]
+    speed: f64,
+    shown_frame: Option<usize>,
+    stage: Option<ReplayStage>,
+    base: Base<Control>,
+}
+
+#[godot_api]
+impl IControl for ReplayViewer {
+    fn ready(&mut self) {
+        let gd_ref = self.to_gd();
+        self.free_camera.make_current();
+        let roster = self.replay.as_ref().map(|replay| replay.roster.clone()).unwrap_or_default();
+        self.stage = Some(ReplayStage::new(self.game_root.clone().upcast(), roster));
+
+        let duration = self.replay.as_ref().map(Replay::duration).unwrap_or_default();
+        self.timeline.set_max(duration);
+        self.timeline
+            .signals()
+            .value_changed()
+            .connect_other(&gd_ref, |this, value: f64| {
+                this.time = value;
+            });
+
+        for speed in REPLAY_SPEEDS {
+            self.speed_option.add_item(&format!("{speed}x"));
+        }
+        self.speed_option.select(NORMAL_SPEED_INDEX as i32);
+        self.speed_option
+            .signals()
+            .item_selected()
+            .connect_other(&gd_ref, |this, index: i64| {
+                this.speed = REPLAY_SPEEDS[index as usize];
+            });
+
+        self.play_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| {
+                let playing = !this.playing;
+                this.set_playing(playing);
+            });
+        self.set_playing(true);
+    }
+
+    fn process(&mut self, delta: f64) {
+        let Some(duration) = self.replay.as_ref().map(Replay::duration) else {
+            return;
+        };
+        if self.playing {
+            self.time = (self.time + delta * self.speed).min(duration);
+            if self.time >= duration {
+                self.set_playing(false);
+            }
+        }
+        self.show_time(self.time);
+        self.timeline.set_value_no_signal(self.time);
+        self.time_label.set_text(&format!(
+            "{} / {}", format_time(self.time), format_time(duration)));
+    }
+}
+
+#[godot_api]
+impl ReplayViewer {
+    // Must be called before the viewer enters the tree
+    pub fn set_replay(&mut self, replay: Replay) {
+        self.replay = Some(replay);
+    }
+
+    fn set_playing(&mut self, playing: bool) {
+        let duration = self.replay.as_ref().map(Replay::duration).unwrap_or_default();
+        // Playing from the end starts over
+        if playing && self.time >= duration {
+            self.time = 0.0;
+        }
+        self.playing = playing;
+        self.play_button.set_text(if playing { "Pause" } else { "Play" });
+    }
+
+    fn show_time(&mut self, time: f64) {
+        let Some(replay) = self.replay.as_ref() else {
+            return;
+        };
+        if replay.frames.is_empty() {
+            return;
+        }
+        let index = ((time * replay.tick_rate).round() as usize).min(replay.frames.len() - 1);
+        if self.shown_frame == Some(index) {
+            return;
+        }
+        let frame = replay.frames[index].clone();
+        // Blasts only go off playing forwards through them
+        let explosions: Vec<Vector3> = match self.shown_frame {
+            Some(shown) if index > shown && index - shown <= MAX_EXPLOSION_CATCHUP => {
+                replay.frames[shown + 1..=index]
+                    .iter()
+                    .flat_map(|frame| frame.explosions.iter().copied())
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        self.shown_frame = Some(index);
+        if let Some(stage) = self.stage.as_mut() {
+            stage.show_frame(&frame);
+            stage.explode(&explosions);
+        }
+    }
+}
+
+// As minutes and seconds
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds as i64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
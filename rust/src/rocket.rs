@@ -1,18 +1,35 @@
+use std::f32::consts::TAU;
+
 use godot::prelude::*;
+use godot::classes::node::ProcessMode;
 
 #[allow(unused_imports)]
 use godot::classes::{Area3D, IArea3D, Node3D, INode3D,
-    GpuParticles3D, Timer, ITimer, RigidBody3D, IRigidBody3D
+    GpuParticles3D, Timer, ITimer, RigidBody3D, IRigidBody3D,
+    StandardMaterial3D, ProjectSettings,
 };
 
 #[allow(unused_imports)]
 use crate::explosion::{Explosion};
+use crate::grenade::Grenade;
+
+// Group every Rocket and Grenade joins while it's flying
+pub const PROJECTILES_GROUP: &str = "projectiles";
 
 #[derive(GodotClass)]
 #[class(base=RigidBody3D, init)]
 pub struct Rocket {
     #[init(val=OnReady::from_loaded("res://explosion/rocket_explosion.tscn"))]
     explosion_scene: OnReady<Gd<PackedScene>>,
+    #[init(node="Trail")]
+    trail: OnReady<Gd<GpuParticles3D>>,
+    // Color of whoever fired this rocket
+    #[var]
+    #[init(val=Color::WHITE)]
+    trail_color: Color,
+    // The player who fired it
+    #[var]
+    owner_id: i64,
     #[export]
     #[init(val=15.0)]
     thrust: f32,
@@ -33,6 +50,17 @@ pub struct Rocket {
     #[export]
     #[init(val=1.0)]
     lifetime: f32,
+    #[export]
+    #[init(val=1.0)]
+    explosion_scale: f32,
+    // Thrown out in a ring when it goes off, for cluster rockets
+    #[export]
+    cluster_scene: Option<Gd<PackedScene>>,
+    #[export]
+    cluster_count: i32,
+    #[export]
+    #[init(val=6.0)]
+    cluster_speed: f32,
     base: Base<RigidBody3D>
 }
 
@@ -42,6 +70,18 @@ impl IRigidBody3D for Rocket {
         self.signals()
             .body_entered()
             .connect_self(Self::on_body_entered);
+
+        // Each rocket needs its own copy to tint
+        let material = self.trail
+            .get_material_override()
+            .and_then(|material| material.duplicate())
+            .and_then(|material| material.try_cast::<StandardMaterial3D>().ok());
+        if let Some(mut material) = material {
+            let alpha = material.get_albedo().a;
+            material.set_albedo(self.trail_color.with_alpha(alpha));
+            self.trail.set_material_override(&material);
+        }
+        self.base_mut().add_to_group(PROJECTILES_GROUP);
     }
 
     fn physics_process(&mut self, delta: f32) {
@@ -81,6 +121,24 @@ impl IRigidBody3D for Rocket {
 
 #[godot_api]
 impl Rocket {
+    pub fn flight(&self) -> RocketFlight {
+        RocketFlight {
+            thrust: self.thrust,
+            cd: self.cd,
+            mass: self.base().get_mass(),
+            gravity: body_gravity(&self.base()),
+            lifetime: self.lifetime,
+        }
+    }
+
+    // Stop flying, so a replay can move this one around.
+    // The trail keeps going so it still streams out behind.
+    pub fn make_puppet(&mut self) {
+        self.base_mut().set_process_mode(ProcessMode::DISABLED);
+        self.trail.set_process_mode(ProcessMode::ALWAYS);
+        self.base_mut().remove_from_group(PROJECTILES_GROUP);
+    }
+
     #[func]
     fn on_body_entered(&mut self, body: Gd<Node>) {
         let pos = self.base().get_position();
@@ -95,12 +153,88 @@ impl Rocket {
 
     #[rpc(any_peer, call_local, unreliable)]
     pub fn explode(&mut self, position: Vector3) {
+        // Already went off, most likely hitting something here
+        // as well as on another peer
+        if !self.base().is_physics_processing() {
+            return;
+        }
         // Explode with an explosion
         let mut explosion = self.explosion_scene
             .instantiate_as::<Explosion>();
         explosion.set_position(position);
+        explosion.set_scale(Vector3::ONE * self.explosion_scale);
+        explosion.bind_mut().set_owner_id(self.owner_id);
         self.base_mut().add_sibling(&explosion);
+        self.scatter_cluster(position);
+        self.base_mut().set_physics_process(false);
         self.base_mut().queue_free();
     }
+
+    // Evenly spaced rather than random, so every peer throws them the same way
+    fn scatter_cluster(&mut self, position: Vector3) {
+        let Some(scene) = self.cluster_scene.clone() else {
+            return;
+        };
+        for index in 0..self.cluster_count {
+            let angle = TAU * index as f32 / self.cluster_count as f32;
+            let direction = Vector3::new(angle.cos(), 1.0, angle.sin()).normalized();
+            let mut bomblet = scene.instantiate_as::<Grenade>();
+            bomblet.set_position(position + direction * 0.3);
+            bomblet.set_linear_velocity(direction * self.cluster_speed);
+            bomblet.bind_mut().set_color(self.trail_color);
+            bomblet.bind_mut().set_owner_id(self.owner_id);
+            self.base_mut().add_sibling(&bomblet);
+        }
+    }
+}
+
+// What gravity does to a body, for predicting its flight
+pub fn body_gravity(body: &Gd<RigidBody3D>) -> Vector3 {
+    let project_settings = ProjectSettings::singleton();
+    project_settings.get_setting("physics/3d/default_gravity").to::<f32>()
+        * project_settings.get_setting("physics/3d/default_gravity_vector").to::<Vector3>()
+        * body.get_gravity_scale()
+}
+
+
+// Point mass version of the flight model, for predicting where a
+// rocket will be. Leaves out lift and assumes the nose follows the
+// velocity, which the aero moment takes care of soon after launch.
+#[derive(Clone, Copy, Debug)]
+pub struct RocketFlight {
+    thrust: f32,
+    cd: f32,
+    mass: f32,
+    gravity: Vector3,
+    lifetime: f32,
 }
 
+impl RocketFlight {
+    const STEP: f32 = 1.0 / 60.0;
+
+    // Something thrown rather than flown, like a grenade
+    pub fn ballistic(gravity: Vector3, lifetime: f32) -> Self {
+        Self { thrust: 0.0, cd: 0.0, mass: 1.0, gravity, lifetime }
+    }
+
+    // Flies a rocket until it's `distance` away from where it started.
+    // Returns the time that took and where it ended up relative to the
+    // start, or None if it burns out first.
+    pub fn fly(&self, launch_velocity: Vector3, distance: f32) -> Option<(f32, Vector3)> {
+        let mut velocity = launch_velocity;
+        let mut offset = Vector3::ZERO;
+        let mut time = 0.0;
+        while time < self.lifetime {
+            if offset.length() >= distance {
+                return Some((time, offset));
+            }
+            let direction = velocity.normalized_or_zero();
+            let force = direction * self.thrust
+                - direction * velocity.length_squared() * self.cd;
+            velocity += (force / self.mass + self.gravity) * Self::STEP;
+            offset += velocity * Self::STEP;
+            time += Self::STEP;
+        }
+        None
+    }
+}
@@ -0,0 +1,108 @@
+use godot::prelude::*;
+
+use crate::input_bindings::{KEYBOARD_MOUSE, NO_DEVICE, BOT_DEVICE};
+
+// Matches the default of the lobby color picker
+pub const DEFAULT_PLAYER_COLOR: Color = Color::from_rgb(0.86, 0.86, 0.0);
+const DUMMY_COLOR: Color = Color::from_rgb(0.6, 0.6, 0.6);
+const BOT_COLOR: Color = Color::from_rgb(0.85, 0.3, 0.3);
+
+// Most players sharing one machine
+pub const MAX_LOCAL_PLAYERS: i64 = 4;
+
+// Peer ids fit in 32 bits, so the local index goes above them.
+// A peer's first player keeps the plain peer id.
+const LOCAL_INDEX_SHIFT: i64 = 32;
+
+pub fn player_id(peer_id: i64, local_index: i64) -> i64 {
+    peer_id | (local_index << LOCAL_INDEX_SHIFT)
+}
+
+pub fn peer_of(player_id: i64) -> i64 {
+    player_id & ((1 << LOCAL_INDEX_SHIFT) - 1)
+}
+
+pub fn local_index_of(player_id: i64) -> i64 {
+    player_id >> LOCAL_INDEX_SHIFT
+}
+
+// Computer players belong to the host, numbered well past its local players
+const FIRST_COMPUTER_INDEX: i64 = 16;
+
+pub fn computer_player_id(n: i64) -> i64 {
+    player_id(1, FIRST_COMPUTER_INDEX + n)
+}
+
+// Everything the lobby knows about one player. The roster itself is a
+// dictionary of player id to the dictionary form of this, so it can go
+// straight through RPCs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RosterEntry {
+    pub name: GString,
+    pub color: Color,
+    // Only meaningful on the machine the player sits at
+    pub device: i64,
+    // Watches instead of playing, and never gets a Player
+    pub spectator: bool,
+}
+
+impl RosterEntry {
+    pub fn new(name: GString, color: Color) -> Self {
+        Self { name, color, device: KEYBOARD_MOUSE, spectator: false }
+    }
+
+    // Stands there and takes it
+    pub fn dummy(n: i64) -> Self {
+        Self {
+            name: GString::from(&format!("Dummy {}", n + 1)),
+            color: DUMMY_COLOR,
+            device: NO_DEVICE,
+            spectator: false,
+        }
+    }
+
+    // Played by the host's computer
+    pub fn bot(n: i64) -> Self {
+        Self {
+            name: GString::from(&format!("Bot {}", n + 1)),
+            color: BOT_COLOR,
+            device: BOT_DEVICE,
+            spectator: false,
+        }
+    }
+
+    pub fn to_dict(&self) -> VarDictionary {
+        vdict! {
+            "name": self.name.clone(),
+            "color": self.color,
+            "device": self.device,
+            "spectator": self.spectator,
+        }
+    }
+
+    pub fn from_dict(dict: &VarDictionary) -> Self {
+        Self {
+            name: dict.get("name")
+                .and_then(|v| v.try_to::<GString>().ok())
+                .unwrap_or_default(),
+            color: dict.get("color")
+                .and_then(|v| v.try_to::<Color>().ok())
+                .unwrap_or(DEFAULT_PLAYER_COLOR),
+            device: dict.get("device")
+                .and_then(|v| v.try_to::<i64>().ok())
+                .unwrap_or(KEYBOARD_MOUSE),
+            spectator: dict.get("spectator")
+                .and_then(|v| v.try_to::<bool>().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// How many in a roster dictionary actually play
+pub fn count_players(roster: &VarDictionary) -> i64 {
+    roster
+        .iter_shared()
+        .typed::<i64, VarDictionary>()
+        .filter(|(_, entry)| !RosterEntry::from_dict(entry).spectator)
+        .count() as i64
+}
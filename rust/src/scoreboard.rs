@@ -0,0 +1,68 @@
+use godot::prelude::*;
+use godot::classes::control::SizeFlags;
+
+#[allow(unused_imports)]
+use godot::classes::{Panel, IPanel, GridContainer, Label};
+
+// What the scoreboard knows about one player
+#[derive(Clone, Debug, PartialEq)]
+pub struct Score {
+    pub player_id: i64,
+    pub name: GString,
+    pub color: Color,
+    pub knockouts: i32,
+    // Out of lives, or gone from the match
+    pub out: bool,
+}
+
+// Everyone in the match and how often they've been knocked out,
+// fewest first
+#[derive(GodotClass)]
+#[class(init, base=Panel)]
+pub struct Scoreboard {
+    #[export]
+    rows: OnEditor<Gd<GridContainer>>,
+    base: Base<Panel>,
+}
+
+#[godot_api]
+impl Scoreboard {
+    pub fn show_scores(&mut self, scores: &[Score]) {
+        let mut scores = scores.to_vec();
+        scores.sort_by_key(|score| (score.out, score.knockouts, score.player_id));
+
+        // Two labels a row, only remade when the count changes
+        let labels: Vec<Gd<Label>> = self.rows
+            .get_children()
+            .iter_shared()
+            .filter_map(|child| child.try_cast::<Label>().ok())
+            .collect();
+        let labels = if labels.len() == scores.len() * 2 {
+            labels
+        } else {
+            for mut label in labels {
+                label.queue_free();
+            }
+            (0..scores.len() * 2)
+                .map(|index| {
+                    let mut label = Label::new_alloc();
+                    if index % 2 == 0 {
+                        label.set_h_size_flags(SizeFlags::EXPAND_FILL);
+                    }
+                    self.rows.add_child(&label);
+                    label
+                })
+                .collect()
+        };
+        for (score, pair) in scores.iter().zip(labels.chunks(2)) {
+            let (mut name, mut knockouts) = (pair[0].clone(), pair[1].clone());
+            name.set_text(&score.name);
+            name.set_modulate(if score.out { score.color.with_alpha(0.5) } else { score.color });
+            knockouts.set_text(&if score.out {
+                format!("{} (out)", score.knockouts)
+            } else {
+                score.knockouts.to_string()
+            });
+        }
+    }
+}
@@ -0,0 +1,539 @@
+use godot::prelude::*;
+use godot::classes::{
+    Node, INode,
+    ConfigFile, DirAccess, DisplayServer, Engine, AudioServer,
+};
+use godot::classes::display_server::{WindowMode, VSyncMode};
+use godot::global::{Error, linear_to_db};
+
+use crate::match_settings::MatchSettings;
+use crate::input_bindings::BindingsSettings;
+
+pub const SETTINGS_PATH: &str = "user://settings.cfg";
+
+// One `[Section]` of settings.cfg with its own keys and defaults
+pub trait SettingsSection: Clone + Default + PartialEq {
+    const NAME: &'static str;
+
+    // Missing keys fall back to the defaults
+    fn read(config: &Gd<ConfigFile>) -> Self;
+    fn write(&self, config: &mut Gd<ConfigFile>);
+    // Pull anything out of range back to something usable
+    fn validated(self) -> Self;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlsSettings {
+    // Multipliers on the player's base mouse sensitivity
+    pub horizontal_sensitivity: f64,
+    pub vertical_sensitivity: f64,
+    pub invert_y: bool,
+    // Zero is raw input, higher values speed up fast flicks
+    pub mouse_acceleration: f64,
+    // How far up or down the camera can look, in degrees
+    pub pitch_limit: f64,
+    // Vertical field of view in degrees
+    pub fov: f64,
+    // Full stick deflection turns this many radians per second
+    pub stick_sensitivity: f64,
+    pub stick_deadzone: f64,
+    // Exponent on the stick deflection, 1 is linear
+    pub stick_curve: f64,
+    pub aim_assist: bool,
+}
+
+impl Default for ControlsSettings {
+    fn default() -> Self {
+        Self {
+            horizontal_sensitivity: 0.7,
+            vertical_sensitivity: 0.7,
+            invert_y: false,
+            mouse_acceleration: 0.0,
+            pitch_limit: 90.0,
+            fov: 75.0,
+            stick_sensitivity: 3.0,
+            stick_deadzone: 0.15,
+            stick_curve: 2.0,
+            aim_assist: true,
+        }
+    }
+}
+
+impl SettingsSection for ControlsSettings {
+    const NAME: &'static str = "Controls";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let d = Self::default();
+        // Older files only had the one sensitivity for both axes
+        let legacy_sensitivity =
+            read_value(config, Self::NAME, "mouse_sensitivity", d.horizontal_sensitivity);
+        Self {
+            horizontal_sensitivity:
+                read_value(config, Self::NAME, "horizontal_sensitivity", legacy_sensitivity),
+            vertical_sensitivity:
+                read_value(config, Self::NAME, "vertical_sensitivity", legacy_sensitivity),
+            invert_y: read_value(config, Self::NAME, "invert_y", d.invert_y),
+            mouse_acceleration: read_value(config, Self::NAME, "mouse_acceleration", d.mouse_acceleration),
+            pitch_limit: read_value(config, Self::NAME, "pitch_limit", d.pitch_limit),
+            fov: read_value(config, Self::NAME, "fov", d.fov),
+            stick_sensitivity: read_value(config, Self::NAME, "stick_sensitivity", d.stick_sensitivity),
+            stick_deadzone: read_value(config, Self::NAME, "stick_deadzone", d.stick_deadzone),
+            stick_curve: read_value(config, Self::NAME, "stick_curve", d.stick_curve),
+            aim_assist: read_value(config, Self::NAME, "aim_assist", d.aim_assist),
+        }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        if config.has_section_key(Self::NAME, "mouse_sensitivity") {
+            config.erase_section_key(Self::NAME, "mouse_sensitivity");
+        }
+        config.set_value(Self::NAME, "horizontal_sensitivity", &self.horizontal_sensitivity.to_variant());
+        config.set_value(Self::NAME, "vertical_sensitivity", &self.vertical_sensitivity.to_variant());
+        config.set_value(Self::NAME, "invert_y", &self.invert_y.to_variant());
+        config.set_value(Self::NAME, "mouse_acceleration", &self.mouse_acceleration.to_variant());
+        config.set_value(Self::NAME, "pitch_limit", &self.pitch_limit.to_variant());
+        config.set_value(Self::NAME, "fov", &self.fov.to_variant());
+        config.set_value(Self::NAME, "stick_sensitivity", &self.stick_sensitivity.to_variant());
+        config.set_value(Self::NAME, "stick_deadzone", &self.stick_deadzone.to_variant());
+        config.set_value(Self::NAME, "stick_curve", &self.stick_curve.to_variant());
+        config.set_value(Self::NAME, "aim_assist", &self.aim_assist.to_variant());
+    }
+
+    fn validated(self) -> Self {
+        Self {
+            horizontal_sensitivity: self.horizontal_sensitivity.clamp(0.1, 1.0),
+            vertical_sensitivity: self.vertical_sensitivity.clamp(0.1, 1.0),
+            mouse_acceleration: self.mouse_acceleration.clamp(0.0, 1.0),
+            pitch_limit: self.pitch_limit.clamp(30.0, 90.0),
+            fov: self.fov.clamp(50.0, 110.0),
+            stick_sensitivity: self.stick_sensitivity.clamp(0.5, 8.0),
+            stick_deadzone: self.stick_deadzone.clamp(0.0, 0.9),
+            stick_curve: self.stick_curve.clamp(1.0, 4.0),
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoSettings {
+    pub fullscreen: bool,
+    pub vsync: bool,
+    // Zero means uncapped
+    pub max_fps: i64,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        Self { fullscreen: false, vsync: true, max_fps: 0 }
+    }
+}
+
+impl SettingsSection for VideoSettings {
+    const NAME: &'static str = "Video";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let d = Self::default();
+        Self {
+            fullscreen: read_value(config, Self::NAME, "fullscreen", d.fullscreen),
+            vsync: read_value(config, Self::NAME, "vsync", d.vsync),
+            max_fps: read_value(config, Self::NAME, "max_fps", d.max_fps),
+        }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        config.set_value(Self::NAME, "fullscreen", &self.fullscreen.to_variant());
+        config.set_value(Self::NAME, "vsync", &self.vsync.to_variant());
+        config.set_value(Self::NAME, "max_fps", &self.max_fps.to_variant());
+    }
+
+    fn validated(self) -> Self {
+        Self {
+            max_fps: self.max_fps.clamp(0, 500),
+            ..self
+        }
+    }
+}
+
+// Volumes are linear, from silent to full
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub master_volume: f64,
+    pub music_volume: f64,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self { master_volume: 1.0, music_volume: 1.0 }
+    }
+}
+
+impl SettingsSection for AudioSettings {
+    const NAME: &'static str = "Audio";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let d = Self::default();
+        Self {
+            master_volume: read_value(config, Self::NAME, "master_volume", d.master_volume),
+            music_volume: read_value(config, Self::NAME, "music_volume", d.music_volume),
+        }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        config.set_value(Self::NAME, "master_volume", &self.master_volume.to_variant());
+        config.set_value(Self::NAME, "music_volume", &self.music_volume.to_variant());
+    }
+
+    fn validated(self) -> Self {
+        Self {
+            master_volume: self.master_volume.clamp(0.0, 1.0),
+            music_volume: self.music_volume.clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkSettings {
+    pub port: i64,
+    pub max_clients: i64,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self { port: 8910, max_clients: 9 }
+    }
+}
+
+impl SettingsSection for NetworkSettings {
+    const NAME: &'static str = "Network";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let d = Self::default();
+        Self {
+            port: read_value(config, Self::NAME, "port", d.port),
+            max_clients: read_value(config, Self::NAME, "max_clients", d.max_clients),
+        }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        config.set_value(Self::NAME, "port", &self.port.to_variant());
+        config.set_value(Self::NAME, "max_clients", &self.max_clients.to_variant());
+    }
+
+    fn validated(self) -> Self {
+        Self {
+            port: self.port.clamp(1024, 65535),
+            max_clients: self.max_clients.clamp(1, 31),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LobbySettings {
+    pub prev_ip_address: GString,
+    // The rules the host used last time
+    pub match_settings: MatchSettings,
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            prev_ip_address: GString::from("127.0.0.1"),
+            match_settings: MatchSettings::default(),
+        }
+    }
+}
+
+impl SettingsSection for LobbySettings {
+    const NAME: &'static str = "Lobby";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let d = Self::default();
+        let match_settings: VarDictionary =
+            read_value(config, Self::NAME, "match_settings", d.match_settings.to_dict());
+        Self {
+            prev_ip_address: read_value(config, Self::NAME, "prev_ip_address", d.prev_ip_address),
+            match_settings: MatchSettings::from_dict(&match_settings),
+        }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        config.set_value(Self::NAME, "prev_ip_address", &self.prev_ip_address.to_variant());
+        config.set_value(Self::NAME, "match_settings", &self.match_settings.to_dict().to_variant());
+    }
+
+    fn validated(self) -> Self {
+        let prev_ip_address = if self.prev_ip_address.is_valid_ip_address() {
+            self.prev_ip_address
+        } else {
+            Self::default().prev_ip_address
+        };
+        Self {
+            prev_ip_address,
+            // Already sanitized by the dictionary round trip
+            match_settings: MatchSettings::from_dict(&self.match_settings.to_dict()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplaySettings {
+    // Save every match to user://replays
+    pub record: bool,
+    // Older recordings get deleted past this many
+    pub keep: i64,
+    // Show what hit us after a knockout, when the match allows it
+    pub kill_cam: bool,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self { record: true, keep: 20, kill_cam: true }
+    }
+}
+
+impl SettingsSection for ReplaySettings {
+    const NAME: &'static str = "Replays";
+
+    fn read(config: &Gd<ConfigFile>) -> Self {
+        let d = Self::default();
+        Self {
+            record: read_value(config, Self::NAME, "record", d.record),
+            keep: read_value(config, Self::NAME, "keep", d.keep),
+            kill_cam: read_value(config, Self::NAME, "kill_cam", d.kill_cam),
+        }
+    }
+
+    fn write(&self, config: &mut Gd<ConfigFile>) {
+        config.set_value(Self::NAME, "record", &self.record.to_variant());
+        config.set_value(Self::NAME, "keep", &self.keep.to_variant());
+        config.set_value(Self::NAME, "kill_cam", &self.kill_cam.to_variant());
+    }
+
+    fn validated(self) -> Self {
+        Self {
+            keep: self.keep.clamp(1, 500),
+            ..self
+        }
+    }
+}
+
+
+// Every user setting lives here, autoloaded as /root/Settings.
+// Read with the section getters, change with the setters, and call
+// `save` once the change is final.
+#[derive(GodotClass)]
+#[class(init, base=Node)]
+pub struct Settings {
+    #[export]
+    #[init(val=GString::from(SETTINGS_PATH))]
+    config_path: GString,
+    controls: ControlsSettings,
+    video: VideoSettings,
+    audio: AudioSettings,
+    network: NetworkSettings,
+    lobby: LobbySettings,
+    bindings: BindingsSettings,
+    replays: ReplaySettings,
+    dirty: bool,
+    base: Base<Node>,
+}
+
+#[godot_api]
+impl INode for Settings {
+    fn ready(&mut self) {
+        self.load();
+    }
+
+    fn exit_tree(&mut self) {
+        if self.dirty {
+            self.save();
+        }
+    }
+}
+
+#[godot_api]
+impl Settings {
+    // Emitted with the section's name whenever any of its values change
+    #[signal]
+    pub fn section_changed(section: GString);
+
+    pub fn controls(&self) -> &ControlsSettings {
+        &self.controls
+    }
+
+    pub fn set_controls(&mut self, value: ControlsSettings) {
+        let value = value.validated();
+        if value != self.controls {
+            self.controls = value;
+            self.on_changed(ControlsSettings::NAME);
+        }
+    }
+
+    pub fn video(&self) -> &VideoSettings {
+        &self.video
+    }
+
+    pub fn set_video(&mut self, value: VideoSettings) {
+        let value = value.validated();
+        if value != self.video {
+            self.video = value;
+            self.apply_video();
+            self.on_changed(VideoSettings::NAME);
+        }
+    }
+
+    pub fn audio(&self) -> &AudioSettings {
+        &self.audio
+    }
+
+    pub fn set_audio(&mut self, value: AudioSettings) {
+        let value = value.validated();
+        if value != self.audio {
+            self.audio = value;
+            self.apply_audio();
+            self.on_changed(AudioSettings::NAME);
+        }
+    }
+
+    // Host-side values, only changed by editing the file
+    pub fn network(&self) -> &NetworkSettings {
+        &self.network
+    }
+
+    pub fn lobby(&self) -> &LobbySettings {
+        &self.lobby
+    }
+
+    pub fn set_lobby(&mut self, value: LobbySettings) {
+        let value = value.validated();
+        if value != self.lobby {
+            self.lobby = value;
+            self.on_changed(LobbySettings::NAME);
+        }
+    }
+
+    pub fn bindings(&self) -> &BindingsSettings {
+        &self.bindings
+    }
+
+    pub fn set_bindings(&mut self, value: BindingsSettings) {
+        let value = value.validated();
+        if value != self.bindings {
+            self.bindings = value;
+            self.bindings.apply();
+            self.on_changed(BindingsSettings::NAME);
+        }
+    }
+
+    pub fn replays(&self) -> &ReplaySettings {
+        &self.replays
+    }
+
+    pub fn set_replays(&mut self, value: ReplaySettings) {
+        let value = value.validated();
+        if value != self.replays {
+            self.replays = value;
+            self.on_changed(ReplaySettings::NAME);
+        }
+    }
+
+    fn on_changed(&mut self, section: &str) {
+        self.dirty = true;
+        self.signals().section_changed().emit(section);
+    }
+
+    #[func]
+    pub fn load(&mut self) {
+        let mut config = ConfigFile::new_gd();
+        // A missing file is fine, everything just takes its default
+        config.load(&self.config_path);
+        self.controls = ControlsSettings::read(&config).validated();
+        self.video = VideoSettings::read(&config).validated();
+        self.audio = AudioSettings::read(&config).validated();
+        self.network = NetworkSettings::read(&config).validated();
+        self.lobby = LobbySettings::read(&config).validated();
+        self.bindings = BindingsSettings::read(&config).validated();
+        self.replays = ReplaySettings::read(&config).validated();
+        self.dirty = false;
+        self.apply_video();
+        self.apply_audio();
+        self.bindings.apply();
+    }
+
+    #[func]
+    pub fn save(&mut self) {
+        // Start from what's on disk so keys we don't know about survive
+        let mut config = ConfigFile::new_gd();
+        config.load(&self.config_path);
+        self.controls.write(&mut config);
+        self.video.write(&mut config);
+        self.audio.write(&mut config);
+        self.network.write(&mut config);
+        self.lobby.write(&mut config);
+        self.bindings.write(&mut config);
+        self.replays.write(&mut config);
+        if save_atomic(&mut config, &self.config_path) == Error::OK {
+            self.dirty = false;
+        }
+    }
+
+    fn apply_video(&self) {
+        let mut display_server = DisplayServer::singleton();
+        let window_mode = if self.video.fullscreen {
+            WindowMode::FULLSCREEN
+        } else {
+            WindowMode::WINDOWED
+        };
+        if display_server.window_get_mode() != window_mode {
+            display_server.window_set_mode(window_mode);
+        }
+        display_server.window_set_vsync_mode(if self.video.vsync {
+            VSyncMode::ENABLED
+        } else {
+            VSyncMode::DISABLED
+        });
+        Engine::singleton().set_max_fps(self.video.max_fps as i32);
+    }
+
+    fn apply_audio(&self) {
+        AudioServer::singleton().set_bus_volume_db(
+            0,
+            linear_to_db(self.audio.master_volume) as f32,
+        );
+    }
+}
+
+pub fn read_value<T: FromGodot + ToGodot>(
+    config: &Gd<ConfigFile>,
+    section: &str,
+    key: &str,
+    default: T,
+) -> T {
+    config
+        .get_value_ex(section, key)
+        .default(&default.to_variant())
+        .done()
+        .try_to::<T>()
+        .unwrap_or(default)
+}
+
+// Write next to the target first, so a crash mid-save
+// can never leave a half-written file behind
+pub fn save_atomic(config: &mut Gd<ConfigFile>, path: &GString) -> Error {
+    write_atomic(path, |temp_path| config.save(temp_path))
+}
+
+// Same, for files that aren't a ConfigFile. `write` gets the temp path.
+pub fn write_atomic(path: &GString, write: impl FnOnce(&GString) -> Error) -> Error {
+    let temp_path = GString::from(&format!("{path}.tmp"));
+    let err = write(&temp_path);
+    if err != Error::OK {
+        godot_error!("Couldn't write {temp_path}: {err:?}");
+        return err;
+    }
+    let err = DirAccess::rename_absolute(&temp_path, path);
+    if err != Error::OK {
+        godot_error!("Couldn't replace {path}: {err:?}");
+    }
+    err
+}
@@ -0,0 +1,258 @@
+use godot::prelude::*;
+use godot::global::Key;
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Control, IControl, Camera3D, Label, Button, InputEvent, InputEventKey,
+};
+
+use crate::free_camera::FreeCamera;
+use crate::player::{Player, PLAYERS_GROUP};
+use crate::player_spawner::NPlayers;
+use crate::scoreboard::Scoreboard;
+
+// Where the follow camera sits, behind and above whoever it follows
+const FOLLOW_DISTANCE: f32 = 4.0;
+const FOLLOW_HEIGHT: f32 = 1.5;
+// How quickly the follow and overhead cameras catch up, per second
+const CAMERA_SMOOTHING: f32 = 6.0;
+// The overhead camera looks down at everyone from up here
+const OVERHEAD_HEIGHT: f32 = 25.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpectatorView {
+    #[default]
+    Follow,
+    Free,
+    Overhead,
+}
+
+impl SpectatorView {
+    pub fn label(self) -> &'static str {
+        match self {
+            SpectatorView::Follow => "Follow",
+            SpectatorView::Free => "Free Camera",
+            SpectatorView::Overhead => "Overhead",
+        }
+    }
+}
+
+// Cameras for whoever's watching instead of playing: people who joined
+// to spectate, and players knocked out of a stock match. The scoreboard
+// stays up the whole time.
+#[derive(GodotClass)]
+#[class(init, base=Control)]
+pub struct Spectator {
+    #[export]
+    player_spawner: OnEditor<Gd<NPlayers>>,
+    #[export]
+    free_camera: OnEditor<Gd<FreeCamera>>,
+    #[export]
+    follow_camera: OnEditor<Gd<Camera3D>>,
+    #[export]
+    overhead_camera: OnEditor<Gd<Camera3D>>,
+    #[export]
+    scoreboard: OnEditor<Gd<Scoreboard>>,
+    #[export]
+    view_label: OnEditor<Gd<Label>>,
+    #[export]
+    follow_button: OnEditor<Gd<Button>>,
+    #[export]
+    free_button: OnEditor<Gd<Button>>,
+    #[export]
+    overhead_button: OnEditor<Gd<Button>>,
+    #[export]
+    next_button: OnEditor<Gd<Button>>,
+    active: bool,
+    view: SpectatorView,
+    // Who the follow camera is on
+    target: Option<Gd<Player>>,
+    base: Base<Control>,
+}
+
+#[godot_api]
+impl IControl for Spectator {
+    fn ready(&mut self) {
+        let gd_ref = self.to_gd();
+        self.base_mut().set_visible(false);
+        self.follow_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| this.set_view(SpectatorView::Follow));
+        self.free_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| this.set_view(SpectatorView::Free));
+        self.overhead_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, |this| this.set_view(SpectatorView::Overhead));
+        self.next_button
+            .signals()
+            .pressed()
+            .connect_other(&gd_ref, Self::next_target);
+    }
+
+    fn process(&mut self, delta: f64) {
+        if !self.active {
+            return;
+        }
+        let scores = self.player_spawner.bind().scores();
+        self.scoreboard.bind_mut().show_scores(&scores);
+
+        let blend = 1.0 - (-CAMERA_SMOOTHING * delta as f32).exp();
+        match self.view {
+            SpectatorView::Follow => self.update_follow_camera(blend),
+            SpectatorView::Overhead => self.update_overhead_camera(blend),
+            SpectatorView::Free => {}
+        }
+    }
+
+    fn unhandled_input(&mut self, event: Gd<InputEvent>) {
+        if !self.active {
+            return;
+        }
+        if self.view == SpectatorView::Follow && event.is_action_pressed("shoot") {
+            self.next_target();
+            return;
+        }
+        let Ok(key) = event.try_cast::<InputEventKey>() else {
+            return;
+        };
+        if !key.is_pressed() || key.is_echo() {
+            return;
+        }
+        match key.get_keycode() {
+            Key::KEY_1 => self.set_view(SpectatorView::Follow),
+            Key::KEY_2 => self.set_view(SpectatorView::Free),
+            Key::KEY_3 => self.set_view(SpectatorView::Overhead),
+            _ => {}
+        }
+    }
+}
+
+#[godot_api]
+impl Spectator {
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn start(&mut self) {
+        if self.active {
+            return;
+        }
+        self.active = true;
+        self.base_mut().set_visible(true);
+        self.set_view(SpectatorView::Follow);
+    }
+
+    pub fn set_view(&mut self, view: SpectatorView) {
+        // Pick up from wherever we were looking
+        let from = self.current_camera().get_global_transform();
+        self.view = view;
+        let mut camera = self.current_camera();
+        if view == SpectatorView::Free {
+            camera.set_global_transform(from);
+        }
+        camera.make_current();
+        self.next_button.set_visible(view == SpectatorView::Follow);
+        if view == SpectatorView::Follow && !self.target_valid() {
+            self.next_target();
+        }
+        self.update_view_label();
+    }
+
+    // Cycles through whoever is still playing, in the order they joined
+    fn next_target(&mut self) {
+        let players = self.players();
+        let current = self.target
+            .as_ref()
+            .filter(|target| target.is_instance_valid())
+            .and_then(|target| players.iter().position(|player| player == target));
+        self.target = match current {
+            Some(index) => players.get((index + 1) % players.len()).cloned(),
+            None => players.first().cloned(),
+        };
+        self.update_view_label();
+    }
+
+    fn players(&self) -> Vec<Gd<Player>> {
+        let Some(mut tree) = self.base().get_tree() else {
+            return Vec::new();
+        };
+        let mut players: Vec<Gd<Player>> = tree
+            .get_nodes_in_group(PLAYERS_GROUP)
+            .iter_shared()
+            .filter_map(|node| node.try_cast::<Player>().ok())
+            .filter(|player| !player.is_queued_for_deletion())
+            .collect();
+        players.sort_by_key(|player| player.bind().get_player_id());
+        players
+    }
+
+    fn target_valid(&self) -> bool {
+        self.target
+            .as_ref()
+            .is_some_and(|target| target.is_instance_valid() && !target.is_queued_for_deletion())
+    }
+
+    fn current_camera(&self) -> Gd<Camera3D> {
+        match self.view {
+            SpectatorView::Follow => (*self.follow_camera).clone(),
+            SpectatorView::Free => self.free_camera.clone().upcast(),
+            SpectatorView::Overhead => (*self.overhead_camera).clone(),
+        }
+    }
+
+    fn update_view_label(&mut self) {
+        let text = match (self.view, self.target.as_ref()) {
+            (SpectatorView::Follow, Some(target)) if target.is_instance_valid() => {
+                format!("Following {}", target.bind().get_name_label().get_text())
+            }
+            (view, _) => view.label().to_string(),
+        };
+        self.view_label.set_text(&text);
+    }
+
+    fn update_follow_camera(&mut self, blend: f32) {
+        if !self.target_valid() {
+            self.next_target();
+        }
+        let Some(target) = self.target.clone() else {
+            return;
+        };
+        let target_position = target.get_global_position();
+        // Behind where they're looking, flattened so it doesn't swing under the floor
+        let look = target.bind().get_camera().get_global_basis().col_c();
+        let behind = Vector3::new(look.x, 0.0, look.z).normalized_or_zero();
+        let wanted = target_position + behind * FOLLOW_DISTANCE + Vector3::UP * FOLLOW_HEIGHT;
+        let position = self.follow_camera.get_global_position().lerp(wanted, blend);
+        self.follow_camera.set_global_position(position);
+        look_at_safely(&mut self.follow_camera, target_position + Vector3::UP * 0.5);
+    }
+
+    fn update_overhead_camera(&mut self, blend: f32) {
+        let players = self.players();
+        if players.is_empty() {
+            return;
+        }
+        let center = players
+            .iter()
+            .map(|player| player.get_global_position())
+            .fold(Vector3::ZERO, |sum, position| sum + position)
+            / players.len() as f32;
+        // Tipped back a little so it never looks straight down
+        let wanted = center + Vector3::UP * OVERHEAD_HEIGHT + Vector3::BACK * OVERHEAD_HEIGHT * 0.5;
+        let position = self.overhead_camera.get_global_position().lerp(wanted, blend);
+        self.overhead_camera.set_global_position(position);
+        look_at_safely(&mut self.overhead_camera, center);
+    }
+}
+
+// look_at can't cope with a target straight above or below
+fn look_at_safely(camera: &mut Gd<Camera3D>, target: Vector3) {
+    let direction = (target - camera.get_global_position()).normalized_or_zero();
+    if direction.cross(Vector3::UP).length() > 0.01 {
+        camera.look_at(target);
+    }
+}
@@ -0,0 +1,163 @@
+use godot::prelude::*;
+use godot::classes::control::{LayoutPreset, SizeFlags};
+use godot::global::{HorizontalAlignment, VerticalAlignment};
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Control, IControl, GridContainer, Label,
+    SubViewport, SubViewportContainer, Camera3D,
+};
+
+use crate::player::Player;
+
+// Render layers 11 to 14 carry the name labels of local players,
+// so each split screen camera can leave out its own
+const FIRST_NAME_LAYER: u32 = 11;
+
+struct Pane {
+    player_id: i64,
+    name: GString,
+    player: Gd<Player>,
+    camera: Gd<Camera3D>,
+    knockouts_label: Gd<Label>,
+    status_label: Gd<Label>,
+}
+
+// One viewport per local player, laid out in a grid. Stays empty
+// and out of the way when there's only one player on this machine.
+#[derive(GodotClass)]
+#[class(init, base=Control)]
+pub struct SplitScreen {
+    #[init(node="Grid")]
+    grid: OnReady<Gd<GridContainer>>,
+    // The full screen HUD, replaced by one per pane once split
+    #[export]
+    hud: OnEditor<Gd<Control>>,
+    #[export]
+    crosshair: OnEditor<Gd<Control>>,
+    panes: Vec<Pane>,
+    base: Base<Control>,
+}
+
+#[godot_api]
+impl IControl for SplitScreen {
+    fn process(&mut self, _delta: f64) {
+        // The players' own cameras live in the main viewport,
+        // so ours just follow them around
+        for pane in &mut self.panes {
+            if !pane.player.is_instance_valid() {
+                continue;
+            }
+            let player_camera = pane.player.bind().get_camera();
+            pane.camera.set_global_transform(player_camera.get_global_transform());
+            pane.camera.set_fov(player_camera.get_fov());
+        }
+    }
+
+    fn exit_tree(&mut self) {
+        if !self.panes.is_empty() {
+            self.set_main_viewport_3d(true);
+        }
+    }
+}
+
+#[godot_api]
+impl SplitScreen {
+    pub fn add_player(&mut self, mut player: Gd<Player>, name: &GString) {
+        let index = self.panes.len() as u32;
+        if index == 0 {
+            self.hud.set_visible(false);
+            // Every pane covers it, no need to draw it too
+            self.set_main_viewport_3d(false);
+        }
+
+        let mut pane = Control::new_alloc();
+        pane.set_h_size_flags(SizeFlags::EXPAND_FILL);
+        pane.set_v_size_flags(SizeFlags::EXPAND_FILL);
+        pane.set_clip_contents(true);
+
+        let mut container = SubViewportContainer::new_alloc();
+        container.set_stretch(true);
+        container.set_anchors_and_offsets_preset(LayoutPreset::FULL_RECT);
+        let mut viewport = SubViewport::new_alloc();
+        // Only one pane can be heard from
+        viewport.set_as_audio_listener_3d(index == 0);
+        let mut camera = Camera3D::new_alloc();
+        let own_layer = 1 << (FIRST_NAME_LAYER - 1 + index);
+        let cull_mask = camera.get_cull_mask() & !own_layer;
+        camera.set_cull_mask(cull_mask);
+        viewport.add_child(&camera);
+        container.add_child(&viewport);
+        pane.add_child(&container);
+
+        if let Some(crosshair) = self.crosshair.duplicate() {
+            pane.add_child(&crosshair);
+        }
+        let mut knockouts_label = Label::new_alloc();
+        knockouts_label.add_theme_font_size_override("font_size", 24);
+        knockouts_label.set_anchors_and_offsets_preset(LayoutPreset::TOP_WIDE);
+        knockouts_label.set_horizontal_alignment(HorizontalAlignment::CENTER);
+        pane.add_child(&knockouts_label);
+        let mut status_label = Label::new_alloc();
+        status_label.add_theme_font_size_override("font_size", 32);
+        status_label.set_anchors_and_offsets_preset(LayoutPreset::FULL_RECT);
+        status_label.set_horizontal_alignment(HorizontalAlignment::CENTER);
+        status_label.set_vertical_alignment(VerticalAlignment::CENTER);
+        pane.add_child(&status_label);
+
+        self.grid.add_child(&pane);
+        camera.make_current();
+
+        player.bind_mut().show_name_on_layer(own_layer);
+        let player_id = player.bind().get_player_id();
+        self.panes.push(Pane {
+            player_id,
+            name: name.clone(),
+            player,
+            camera,
+            knockouts_label,
+            status_label,
+        });
+        self.set_knockouts(player_id, 0);
+        // Stacked for two, two by two for more
+        let columns = if self.panes.len() > 2 { 2 } else { 1 };
+        self.grid.set_columns(columns);
+    }
+
+    // False when that player has no pane here
+    pub fn set_knockouts(&mut self, player_id: i64, count: i32) -> bool {
+        let Some(pane) = self.panes.iter_mut().find(|p| p.player_id == player_id) else {
+            return false;
+        };
+        pane.knockouts_label.set_text(&format!("{}  Knockouts: {count}", pane.name));
+        true
+    }
+
+    // Big text in the middle of their pane, empty to clear.
+    // False when that player has no pane here.
+    pub fn set_status(&mut self, player_id: i64, text: &str) -> bool {
+        let Some(pane) = self.panes.iter_mut().find(|p| p.player_id == player_id) else {
+            return false;
+        };
+        pane.status_label.set_text(text);
+        true
+    }
+
+    // Back to the one full screen view, once nobody here is playing
+    pub fn clear(&mut self) {
+        if self.panes.is_empty() {
+            return;
+        }
+        for mut pane in self.grid.get_children().iter_shared() {
+            pane.queue_free();
+        }
+        self.panes.clear();
+        self.set_main_viewport_3d(true);
+    }
+
+    fn set_main_viewport_3d(&mut self, enabled: bool) {
+        if let Some(mut viewport) = self.base().get_tree().and_then(|tree| tree.get_root()) {
+            viewport.set_disable_3d(!enabled);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{
+    Node, INode, GridMap, MeshLibrary,
+    MeshInstance3D, IMeshInstance3D,
+};
+
+// Arenas' mesh libraries set this metadata to a dictionary of item name
+// to how much blasting the item takes before it breaks. A blast does 1.0
+// to a cell at its middle, fading out to nothing at its edge. Items left
+// out never break.
+pub const DURABILITY_META: &str = "durability";
+// The match's DestructibleTerrain, when it has one
+pub const TERRAIN_GROUP: &str = "terrain";
+
+// Seconds a broken cell takes to fall away
+const DEBRIS_LIFETIME: f32 = 1.5;
+const DEBRIS_GRAVITY: f32 = 9.8;
+
+// Wears an arena's GridMap down as blasts land on it. The host keeps
+// track of the damage and decides what breaks, everyone else just
+// removes the cells they're told about.
+#[derive(GodotClass)]
+#[class(init, base=Node)]
+pub struct DestructibleTerrain {
+    grid: Option<Gd<GridMap>>,
+    // Host only: how much blasting each item takes
+    durability: HashMap<i32, f32>,
+    // Host only: blasting taken so far by cells still standing
+    damage: HashMap<Vector3i, f32>,
+    // Every cell broken so far, for anyone catching up
+    broken: Vec<Vector3i>,
+    base: Base<Node>,
+}
+
+#[godot_api]
+impl INode for DestructibleTerrain {
+    fn ready(&mut self) {
+        self.base_mut().add_to_group(TERRAIN_GROUP);
+        if let Some(library) = self.grid.as_ref().and_then(|grid| grid.get_mesh_library()) {
+            self.durability = item_durability(&library);
+        }
+        // The match might have been going a while already
+        if !self.base().is_multiplayer_authority() {
+            let host = self.base().get_multiplayer_authority() as i64;
+            self.base_mut().rpc_id(host, "request_terrain", &[]);
+        }
+    }
+}
+
+#[godot_api]
+impl DestructibleTerrain {
+    // Must be called before entering the tree
+    pub fn set_grid(&mut self, grid: Gd<GridMap>) {
+        self.grid = Some(grid);
+    }
+
+    // Does nothing off the host, it sends out whatever breaks
+    pub fn blast(&mut self, center: Vector3, radius: f32) {
+        if !self.base().is_multiplayer_authority() || radius <= 0.0 {
+            return;
+        }
+        let Some(grid) = self.grid.clone() else {
+            return;
+        };
+        let local = grid.to_local(center);
+        let reach = Vector3::ONE * radius;
+        let low = grid.local_to_map(local - reach);
+        let high = grid.local_to_map(local + reach);
+
+        let mut broken = Array::<Vector3i>::new();
+        for x in low.x..=high.x {
+            for y in low.y..=high.y {
+                for z in low.z..=high.z {
+                    let cell = Vector3i::new(x, y, z);
+                    let Some(durability) = self.durability.get(&grid.get_cell_item(cell)) else {
+                        continue;
+                    };
+                    let distance = grid.map_to_local(cell).distance_to(local);
+                    if distance >= radius {
+                        continue;
+                    }
+                    let damage = self.damage.entry(cell).or_default();
+                    *damage += 1.0 - distance / radius;
+                    if *damage >= *durability {
+                        self.damage.remove(&cell);
+                        broken.push(cell);
+                    }
+                }
+            }
+        }
+        if !broken.is_empty() {
+            self.base_mut().rpc("break_cells", vslice![broken]);
+        }
+    }
+
+    #[rpc(authority, call_local, reliable)]
+    fn break_cells(&mut self, cells: Array<Vector3i>) {
+        let Some(mut grid) = self.grid.clone() else {
+            return;
+        };
+        let library = grid.get_mesh_library();
+        for cell in cells.iter_shared() {
+            let item = grid.get_cell_item(cell);
+            if item == GridMap::INVALID_CELL_ITEM {
+                continue;
+            }
+            if let Some(library) = &library
+                    && let Some(mesh) = library.get_item_mesh(item) {
+                let mut debris = Debris::new_alloc();
+                debris.set_mesh(&mesh);
+                debris.set_transform(
+                    grid.get_global_transform()
+                        * Transform3D::new(grid.get_cell_item_basis(cell), grid.map_to_local(cell))
+                        * library.get_item_mesh_transform(item),
+                );
+                self.base_mut().add_child(&debris);
+            }
+            grid.set_cell_item(cell, GridMap::INVALID_CELL_ITEM);
+            self.broken.push(cell);
+        }
+    }
+
+    #[rpc(any_peer, call_remote, reliable)]
+    fn request_terrain(&mut self) {
+        if !self.base().is_multiplayer_authority() {
+            return;
+        }
+        let peer = self.base().get_multiplayer().unwrap().get_remote_sender_id() as i64;
+        let broken: Array<Vector3i> = self.broken.iter().copied().collect();
+        self.base_mut().rpc_id(peer, "sync_terrain", vslice![broken]);
+    }
+
+    // Everything broken before we got here, gone without any fuss
+    #[rpc(authority, call_remote, reliable)]
+    fn sync_terrain(&mut self, cells: Array<Vector3i>) {
+        let Some(mut grid) = self.grid.clone() else {
+            return;
+        };
+        for cell in cells.iter_shared() {
+            if grid.get_cell_item(cell) != GridMap::INVALID_CELL_ITEM {
+                grid.set_cell_item(cell, GridMap::INVALID_CELL_ITEM);
+                self.broken.push(cell);
+            }
+        }
+    }
+}
+
+// Item id to durability, for the items the library gives one
+fn item_durability(library: &Gd<MeshLibrary>) -> HashMap<i32, f32> {
+    let durability = library
+        .get_meta_ex(DURABILITY_META)
+        .default(&VarDictionary::new().to_variant())
+        .done()
+        .try_to::<VarDictionary>()
+        .unwrap_or_default();
+    library
+        .get_item_list()
+        .as_slice()
+        .iter()
+        .filter_map(|item| {
+            let name = library.get_item_name(*item);
+            durability
+                .get(name)
+                .and_then(|value| value.try_to::<f32>().ok())
+                .map(|value| (*item, value))
+        })
+        .collect()
+}
+
+// A broken cell tumbling away. Only for show, nothing can touch it.
+#[derive(GodotClass)]
+#[class(init, base=MeshInstance3D)]
+pub struct Debris {
+    velocity: Vector3,
+    age: f32,
+    base: Base<MeshInstance3D>,
+}
+
+#[godot_api]
+impl IMeshInstance3D for Debris {
+    fn process(&mut self, delta: f64) {
+        let delta = delta as f32;
+        self.age += delta;
+        if self.age >= DEBRIS_LIFETIME {
+            self.base_mut().queue_free();
+            return;
+        }
+        self.velocity.y -= DEBRIS_GRAVITY * delta;
+        let position = self.base().get_position() + self.velocity * delta;
+        self.base_mut().set_position(position);
+        self.base_mut().rotate_object_local(Vector3::RIGHT, delta * 2.0);
+        // Crumbles as it falls
+        let size = 1.0 - self.age / DEBRIS_LIFETIME;
+        self.base_mut().set_scale(Vector3::ONE * size);
+    }
+}
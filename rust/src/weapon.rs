@@ -0,0 +1,212 @@
+use godot::prelude::*;
+
+#[allow(unused_imports)]
+use godot::classes::{Resource, IResource, RigidBody3D};
+
+use crate::grenade::Grenade;
+use crate::input_source::InputCommand;
+use crate::rocket::{Rocket, RocketFlight};
+
+// When holding the trigger down fires again
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum FireMode {
+    // Once per press
+    #[default]
+    Single = 0,
+    // Every `fire_interval` for as long as it's held
+    Automatic = 1,
+}
+
+// Everything about a weapon but who's holding it.
+// Each kind has one saved under res://weapons.
+#[derive(GodotClass)]
+#[class(base=Resource, init)]
+pub struct Weapon {
+    // A Rocket or a Grenade
+    #[export]
+    projectile_scene: Option<Gd<PackedScene>>,
+    #[export]
+    #[init(val=20.0)]
+    launch_speed: f32,
+    // Roll, in radians per second
+    #[export]
+    #[init(val=5.0)]
+    spin: f32,
+    // Seconds to fill an empty magazine
+    #[export]
+    #[init(val=1.0)]
+    reload_time: f64,
+    #[export]
+    #[init(val=1)]
+    magazine_size: i32,
+    #[export]
+    fire_mode: FireMode,
+    // Seconds between shots from the same magazine
+    #[export]
+    #[init(val=0.1)]
+    fire_interval: f64,
+    base: Base<Resource>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeaponKind {
+    #[default]
+    Bazooka,
+    GrenadeLauncher,
+    ClusterRocket,
+    RocketPod,
+}
+
+impl WeaponKind {
+    pub const ALL: [WeaponKind; 4] = [
+        WeaponKind::Bazooka,
+        WeaponKind::GrenadeLauncher,
+        WeaponKind::ClusterRocket,
+        WeaponKind::RocketPod,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            WeaponKind::Bazooka => "Bazooka",
+            WeaponKind::GrenadeLauncher => "Grenade Launcher",
+            WeaponKind::ClusterRocket => "Cluster Rocket",
+            WeaponKind::RocketPod => "Rocket Pod",
+        }
+    }
+
+    pub fn to_index(self) -> i64 {
+        Self::ALL.iter().position(|k| *k == self).unwrap_or(0) as i64
+    }
+
+    pub fn from_index(index: i64) -> Option<Self> {
+        Self::ALL.get(usize::try_from(index).ok()?).copied()
+    }
+
+    fn path(self) -> &'static str {
+        match self {
+            WeaponKind::Bazooka => "res://weapons/bazooka.tres",
+            WeaponKind::GrenadeLauncher => "res://weapons/grenade_launcher.tres",
+            WeaponKind::ClusterRocket => "res://weapons/cluster_rocket.tres",
+            WeaponKind::RocketPod => "res://weapons/rocket_pod.tres",
+        }
+    }
+}
+
+// Whatever a weapon's projectile scene turns out to be
+pub enum Projectile {
+    Rocket(Gd<Rocket>),
+    Grenade(Gd<Grenade>),
+}
+
+impl Projectile {
+    pub fn instantiate(scene: &Gd<PackedScene>) -> Option<Self> {
+        match scene.instantiate()?.try_cast::<Rocket>() {
+            Ok(rocket) => Some(Self::Rocket(rocket)),
+            Err(node) => match node.try_cast::<Grenade>() {
+                Ok(grenade) => Some(Self::Grenade(grenade)),
+                Err(node) => {
+                    godot_warn!("Weapons can only fire rockets and grenades");
+                    node.free();
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn body(&self) -> Gd<RigidBody3D> {
+        match self {
+            Self::Rocket(rocket) => rocket.clone().upcast(),
+            Self::Grenade(grenade) => grenade.clone().upcast(),
+        }
+    }
+
+    pub fn set_shooter(&mut self, owner_id: i64, color: Color) {
+        match self {
+            Self::Rocket(rocket) => {
+                rocket.bind_mut().set_owner_id(owner_id);
+                rocket.bind_mut().set_trail_color(color);
+            }
+            Self::Grenade(grenade) => {
+                grenade.bind_mut().set_owner_id(owner_id);
+                grenade.bind_mut().set_color(color);
+            }
+        }
+    }
+
+    pub fn flight(&self) -> RocketFlight {
+        match self {
+            Self::Rocket(rocket) => rocket.bind().flight(),
+            Self::Grenade(grenade) => grenade.bind().flight(),
+        }
+    }
+
+    // For one that's never going to be fired
+    pub fn free(self) {
+        self.body().free();
+    }
+}
+
+// One weapon in a player's hands, and how soon it can fire
+pub struct WeaponSlot {
+    weapon: Gd<Weapon>,
+    rounds: i32,
+    // Seconds until an empty magazine is full again
+    reloading: f64,
+    // Seconds until the next shot
+    cooldown: f64,
+}
+
+impl WeaponSlot {
+    pub fn new(kind: WeaponKind) -> Self {
+        let weapon = load::<Weapon>(kind.path());
+        let rounds = weapon.bind().magazine_size.max(1);
+        Self { weapon, rounds, reloading: 0.0, cooldown: 0.0 }
+    }
+
+    pub fn weapon(&self) -> Gd<Weapon> {
+        self.weapon.clone()
+    }
+
+    pub fn can_fire(&self) -> bool {
+        self.rounds > 0 && self.cooldown <= 0.0
+    }
+
+    // Whether the trigger's being pulled the way this weapon needs
+    pub fn is_triggered(&self, command: &InputCommand) -> bool {
+        match self.weapon.bind().fire_mode {
+            FireMode::Single => command.shoot,
+            FireMode::Automatic => command.shoot || command.firing,
+        }
+    }
+
+    // True when that was the last round, and it's started reloading.
+    // `reload_speed` is how many times faster than normal that goes.
+    pub fn use_round(&mut self, reload_speed: f64) -> bool {
+        let weapon = self.weapon.bind();
+        self.rounds = (self.rounds - 1).max(0);
+        self.cooldown = weapon.fire_interval;
+        if self.rounds > 0 {
+            return false;
+        }
+        self.reloading = weapon.reload_time / reload_speed;
+        true
+    }
+
+    pub fn tick(&mut self, delta: f64) {
+        self.cooldown -= delta;
+        if self.rounds == 0 {
+            self.reloading -= delta;
+            if self.reloading <= 0.0 {
+                self.rounds = self.weapon.bind().magazine_size.max(1);
+            }
+        }
+    }
+}
+
+#[godot_api]
+impl Weapon {
+    pub fn projectile(&self) -> Option<Projectile> {
+        Projectile::instantiate(self.projectile_scene.as_ref()?)
+    }
+}